@@ -0,0 +1,7 @@
+pub mod trend;
+pub mod levels;
+pub mod volume;
+pub mod volatility;
+pub mod correlation;
+pub mod oscillators;
+pub mod moving_average;
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use tinkoff_invest_api::tcs::Candle;
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
+use crate::trading_cfg::CorrelationCfg;
+use crate::utils::quotation::QuotationExtension;
+
+// критическое значение теста Энгла-Грейнджера для двух рядов на уровне 5%
+const ENGLE_GRANGER_CRITICAL_5: f64 = -3.34;
+
+#[derive(Debug, Clone)]
+pub struct CorrelationStats {
+    // корреляция лог-доходностей за весь range
+    pub correlation: f64,
+    // корреляция в скользящем окне, от старых к новым
+    pub rolling: Vec<f64>,
+    // бета первого инструмента относительно второго (бенчмарка)
+    pub beta: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CointegrationStats {
+    // spread = price - hedge_ratio * other_price - intercept
+    pub hedge_ratio: f64,
+    pub intercept: f64,
+    // t-статистика Дики-Фуллера для остатков
+    pub adf_stat: f64,
+    pub is_cointegrated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpreadStats {
+    // ln(price) = intercept + hedge_ratio * ln(other_price) + spread
+    pub hedge_ratio: f64,
+    pub intercept: f64,
+    // последнее значение спреда и его среднее/отклонение в окне
+    pub spread: f64,
+    pub mean: f64,
+    pub std: f64,
+    pub zscore: f64,
+}
+
+// цены закрытия двух инструментов, совпадающие по времени свечи. candles in any order, result from old to new
+pub fn aligned_closes(candles: &[Candle], other: &[Candle]) -> (Vec<f64>, Vec<f64>) {
+    let other_by_time: HashMap<i64, f64> = other.iter()
+        .map(|candle| (candle.time.clone().unwrap().seconds, candle.close.clone().unwrap().to_f()))
+        .collect();
+    let mut pairs: Vec<(i64, f64, f64)> = candles.iter()
+        .filter_map(|candle| {
+            let time = candle.time.clone().unwrap().seconds;
+            other_by_time.get(&time).map(|other_close| (time, candle.close.clone().unwrap().to_f(), *other_close))
+        })
+        .collect();
+    pairs.sort_by_key(|(time, _, _)| *time);
+    pairs.into_iter().map(|(_, a, b)| (a, b)).unzip()
+}
+
+pub fn log_returns(prices: &[f64]) -> Vec<f64> {
+    prices.windows(2).map(|window| (window[1] / window[0]).ln()).collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn covariance(x: &[f64], y: &[f64]) -> f64 {
+    let (mean_x, mean_y) = (mean(x), mean(y));
+    x.iter().zip(y).map(|(a, b)| (a - mean_x) * (b - mean_y)).sum::<f64>() / x.len() as f64
+}
+
+pub fn correlation(x: &[f64], y: &[f64]) -> f64 {
+    if x.len() < 2 || x.len() != y.len() {
+        return 0.0;
+    }
+    let denominator = (covariance(x, x) * covariance(y, y)).sqrt();
+    if denominator == 0.0 { 0.0 } else { covariance(x, y) / denominator }
+}
+
+pub fn rolling_correlation(x: &[f64], y: &[f64], window: usize) -> Vec<f64> {
+    if window < 2 || x.len() < window {
+        return Vec::new();
+    }
+    (0..=x.len() - window).map(|i| correlation(&x[i..i + window], &y[i..i + window])).collect()
+}
+
+pub fn beta(returns: &[f64], benchmark_returns: &[f64]) -> f64 {
+    let var = covariance(benchmark_returns, benchmark_returns);
+    if var == 0.0 { 0.0 } else { covariance(returns, benchmark_returns) / var }
+}
+
+// (intercept, slope) для y = intercept + slope * x
+pub fn ols(x: &[f64], y: &[f64]) -> (f64, f64) {
+    let slope = beta(y, x);
+    (mean(y) - slope * mean(x), slope)
+}
+
+// тест Дики-Фуллера без константы: Δe_t = γ e_{t-1} + ε, возвращает t-статистику γ
+pub fn dickey_fuller(series: &[f64]) -> f64 {
+    if series.len() < 3 {
+        return 0.0;
+    }
+    let lagged = &series[..series.len() - 1];
+    let diffs: Vec<f64> = series.windows(2).map(|window| window[1] - window[0]).collect();
+    let sum_xx: f64 = lagged.iter().map(|x| x * x).sum();
+    if sum_xx == 0.0 {
+        return 0.0;
+    }
+    let gamma = lagged.iter().zip(&diffs).map(|(x, y)| x * y).sum::<f64>() / sum_xx;
+    let ssr: f64 = lagged.iter().zip(&diffs).map(|(x, y)| (y - gamma * x).powi(2)).sum();
+    let se = (ssr / (diffs.len() - 1) as f64 / sum_xx).sqrt();
+    if se == 0.0 { f64::NEG_INFINITY } else { gamma / se }
+}
+
+// двухшаговый тест Энгла-Грейнджера
+pub fn cointegration(prices: &[f64], other_prices: &[f64]) -> Option<CointegrationStats> {
+    if prices.len() < 10 || prices.len() != other_prices.len() {
+        return None;
+    }
+    let (intercept, hedge_ratio) = ols(other_prices, prices);
+    let residuals: Vec<f64> = prices.iter().zip(other_prices)
+        .map(|(price, other)| price - hedge_ratio * other - intercept)
+        .collect();
+    let adf_stat = dickey_fuller(&residuals);
+    Some(CointegrationStats { hedge_ratio, intercept, adf_stat, is_cointegrated: adf_stat < ENGLE_GRANGER_CRITICAL_5 })
+}
+
+// спред логарифмов цен, хедж-коэффициент по всему ряду, z-score последнего значения по последним window значениям
+pub fn log_spread(prices: &[f64], other_prices: &[f64], window: usize) -> Option<SpreadStats> {
+    if window < 2 || prices.len() < window || prices.len() != other_prices.len() {
+        return None;
+    }
+    let log_prices: Vec<f64> = prices.iter().map(|price| price.ln()).collect();
+    let log_other: Vec<f64> = other_prices.iter().map(|price| price.ln()).collect();
+    let (intercept, hedge_ratio) = ols(&log_other, &log_prices);
+    let spreads: Vec<f64> = log_prices.iter().zip(&log_other)
+        .map(|(price, other)| price - hedge_ratio * other - intercept)
+        .collect();
+    let window_spreads = &spreads[spreads.len() - window..];
+    let mean = mean(window_spreads);
+    let std = covariance(window_spreads, window_spreads).sqrt();
+    let spread = *spreads.last()?;
+    let zscore = if std == 0.0 { 0.0 } else { (spread - mean) / std };
+    Some(SpreadStats { hedge_ratio, intercept, spread, mean, std, zscore })
+}
+
+impl CorrelationStats {
+    pub fn new(prices: &[f64], benchmark_prices: &[f64], rolling_window: usize) -> Option<Self> {
+        let returns = log_returns(prices);
+        let benchmark_returns = log_returns(benchmark_prices);
+        if returns.len() < 2 {
+            return None;
+        }
+        Some(CorrelationStats {
+            correlation: correlation(&returns, &benchmark_returns),
+            rolling: rolling_correlation(&returns, &benchmark_returns, rolling_window),
+            beta: beta(&returns, &benchmark_returns),
+        })
+    }
+
+    // корреляция последнего скользящего окна, если ряд короче окна -- за весь range
+    pub fn current(&self) -> f64 {
+        self.rolling.last().copied().unwrap_or(self.correlation)
+    }
+
+    // не стоит открывать обе позиции в одну сторону
+    pub fn is_correlated(&self, correlation_cfg: &CorrelationCfg) -> bool {
+        self.current().abs() >= correlation_cfg.max_correlation
+    }
+}
+
+// пары инструментов, спред между которыми стационарен -- кандидаты для парной торговли
+pub async fn find_spread_candidates(stat: &CandleState, instrument_uids: &[String], range: SizedRange) -> Vec<(String, String, CointegrationStats)> {
+    let mut candidates = Vec::new();
+    for (i, first) in instrument_uids.iter().enumerate() {
+        for second in &instrument_uids[i + 1..] {
+            if let Some(stats) = stat.get_cointegration(first, second, range.clone()).await {
+                if stats.is_cointegrated {
+                    candidates.push((first.clone(), second.clone(), stats));
+                }
+            }
+        }
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::correlation::{cointegration, correlation, log_returns, log_spread, CorrelationStats};
+
+    #[test]
+    fn test_correlation_and_beta() {
+        let benchmark = [100.0, 101.0, 100.5, 102.0, 101.0, 103.0];
+        // доходности первого ровно в два раза больше доходностей бенчмарка
+        let returns = log_returns(&benchmark);
+        let mut prices = vec![50.0];
+        for r in &returns {
+            prices.push(prices.last().unwrap() * (2.0 * r).exp());
+        }
+        let stats = CorrelationStats::new(&prices, &benchmark, 3).unwrap();
+        assert!((stats.correlation - 1.0).abs() < 1e-9);
+        assert!((stats.beta - 2.0).abs() < 1e-9);
+        assert_eq!(stats.rolling.len(), 3);
+        assert!((correlation(&[1.0, 2.0, 3.0], &[3.0, 2.0, 1.0]) + 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cointegration() {
+        let mut walk = vec![100.0];
+        for i in 1..200 {
+            walk.push(walk.last().unwrap() + ((i * 7919) % 13) as f64 / 10.0 - 0.6);
+        }
+        let noise = |i: usize| ((i * 31) % 7) as f64 / 100.0 - 0.03;
+        let prices: Vec<f64> = walk.iter().enumerate().map(|(i, w)| 2.0 * w + 5.0 + noise(i)).collect();
+
+        let stats = cointegration(&prices, &walk).unwrap();
+        assert!((stats.hedge_ratio - 2.0).abs() < 0.01);
+        assert!(stats.is_cointegrated);
+    }
+
+    #[test]
+    fn test_log_spread() {
+        let other: Vec<f64> = (0..50).map(|i| 100.0 + i as f64).collect();
+        // ln(price) = 0.5 + 2 * ln(other), последняя цена выше равновесной
+        let mut prices: Vec<f64> = other.iter().map(|o| (0.5 + 2.0 * o.ln()).exp()).collect();
+        for (i, price) in prices.iter_mut().enumerate() {
+            *price *= 1.0 + if i % 2 == 0 { 0.001 } else { -0.001 };
+        }
+        *prices.last_mut().unwrap() *= 1.01;
+
+        let stats = log_spread(&prices, &other, 20).unwrap();
+        assert!((stats.hedge_ratio - 2.0).abs() < 0.05);
+        assert!(stats.zscore > 2.0);
+        assert!(log_spread(&prices[..10], &other[..10], 20).is_none());
+    }
+}
@@ -0,0 +1,155 @@
+use prost_types::Timestamp;
+use tinkoff_invest_api::tcs::Candle;
+use crate::analytics::trend::{swing_points, SwingPoint};
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
+use crate::state::last_price_state::{LastPriceState, LastPriceStateStatistic};
+use crate::trading_cfg::LevelsCfg;
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelKind {
+    Support,
+    Resistance,
+}
+
+#[derive(Debug, Clone)]
+pub struct Level {
+    pub price: f64,
+    // сколько свеч дотянулись до уровня high или low
+    pub touches: usize,
+    // суммарный объем свеч, коснувшихся уровня
+    pub volume: i64,
+    pub last_touch: Timestamp,
+    // touches с затуханием по давности последнего касания, по нему сортируем
+    pub score: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct NearestLevels {
+    pub support: Option<Level>,
+    pub resistance: Option<Level>,
+}
+
+impl Level {
+    pub fn kind(&self, price: f64) -> LevelKind {
+        if self.price <= price { LevelKind::Support } else { LevelKind::Resistance }
+    }
+
+    // цена находится в пределах допуска уровня
+    pub fn is_near(&self, price: f64, cluster_prc: f64) -> bool {
+        (price - self.price).abs() <= self.price * cluster_prc / 100.0
+    }
+}
+
+impl NearestLevels {
+    pub fn new(levels: &[Level], price: f64) -> Self {
+        let support = levels.iter()
+            .filter(|level| level.kind(price) == LevelKind::Support)
+            .max_by(|a, b| a.price.total_cmp(&b.price))
+            .cloned();
+        let resistance = levels.iter()
+            .filter(|level| level.kind(price) == LevelKind::Resistance)
+            .min_by(|a, b| a.price.total_cmp(&b.price))
+            .cloned();
+        NearestLevels { support, resistance }
+    }
+}
+
+// candles must be sorted by time from old to new. Result sorted by score, strongest first
+pub fn find_levels(levels_cfg: &LevelsCfg, candles: &[Candle]) -> Vec<Level> {
+    let (highs, lows) = swing_points(candles, levels_cfg.swing_depth);
+    let mut points: Vec<SwingPoint> = highs.into_iter().chain(lows).collect();
+    if points.is_empty() {
+        return Vec::new();
+    }
+    points.sort_by(|a, b| a.price.total_cmp(&b.price));
+
+    // жадная кластеризация: точка попадает в кластер если она в пределах допуска от его средней цены
+    let mut clusters: Vec<Vec<SwingPoint>> = Vec::new();
+    for point in points {
+        match clusters.last_mut() {
+            Some(cluster) if (point.price - volume_weighted_price(cluster)).abs() <= point.price * levels_cfg.cluster_prc / 100.0 => {
+                cluster.push(point)
+            }
+            _ => clusters.push(vec![point]),
+        }
+    }
+
+    let last_time = candles.last().unwrap().time.clone().unwrap();
+    let mut levels: Vec<Level> = clusters.iter()
+        .filter_map(|cluster| {
+            let price = volume_weighted_price(cluster);
+            let tolerance = price * levels_cfg.cluster_prc / 100.0;
+            let touched: Vec<&Candle> = candles.iter()
+                .filter(|candle| {
+                    let high = candle.high.clone().unwrap().to_f();
+                    let low = candle.low.clone().unwrap().to_f();
+                    (high - price).abs() <= tolerance || (low - price).abs() <= tolerance
+                })
+                .collect();
+            if touched.len() < levels_cfg.min_touches {
+                return None;
+            }
+            let last_touch = touched.last().unwrap().time.clone().unwrap();
+            let age_min = (last_time.seconds - last_touch.seconds) as f64 / 60.0;
+            let score = touched.len() as f64 * 0.5_f64.powf(age_min / levels_cfg.recency_half_life_min);
+            Some(Level {
+                price,
+                touches: touched.len(),
+                volume: touched.iter().map(|candle| candle.volume).sum(),
+                last_touch,
+                score,
+            })
+        })
+        .collect();
+    levels.sort_by(|a, b| b.score.total_cmp(&a.score));
+    levels
+}
+
+fn volume_weighted_price(points: &[SwingPoint]) -> f64 {
+    let volume: i64 = points.iter().map(|point| point.volume).sum();
+    if volume == 0 {
+        points.iter().map(|point| point.price).sum::<f64>() / points.len() as f64
+    } else {
+        points.iter().map(|point| point.price * point.volume as f64).sum::<f64>() / volume as f64
+    }
+}
+
+// ближайшие уровни снизу и сверху от текущей цены инструмента
+pub async fn get_nearest_levels(
+    candle_state: &CandleState,
+    last_price_state: &LastPriceState,
+    levels_cfg: &LevelsCfg,
+    instrument_uid: &String,
+    range: SizedRange,
+) -> Option<NearestLevels> {
+    let price = last_price_state.get_last_price(instrument_uid).await?.to_f();
+    let levels = candle_state.get_levels(levels_cfg, instrument_uid, range).await?;
+    Some(NearestLevels::new(&levels, price))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::levels::{find_levels, NearestLevels};
+    use crate::trading_cfg::LevelsCfg;
+    use crate::utils::candle::new_test_candle;
+
+    #[test]
+    fn test_find_levels() {
+        // цена дважды отбивается от 100 и дважды от 110
+        let lows_highs = [(104.0, 106.0), (100.0, 103.0), (104.0, 107.0), (107.0, 110.0), (104.0, 107.0),
+            (100.1, 103.0), (104.0, 106.0), (107.0, 109.9), (104.0, 106.0)];
+        let candles: Vec<_> = lows_highs.iter().enumerate()
+            .map(|(i, (low, high))| new_test_candle(i as i64, *low, *high, *low, *high, 100))
+            .collect();
+        let levels_cfg = LevelsCfg { window_size_min: 60, swing_depth: 1, cluster_prc: 0.5, min_touches: 2, recency_half_life_min: 60.0 };
+
+        let levels = find_levels(&levels_cfg, &candles);
+        assert_eq!(levels.len(), 2);
+        assert!(levels.iter().all(|level| level.touches == 2));
+
+        let nearest = NearestLevels::new(&levels, 105.0);
+        assert!((nearest.support.unwrap().price - 100.05).abs() < 1e-9);
+        assert!((nearest.resistance.unwrap().price - 109.95).abs() < 1e-9);
+    }
+}
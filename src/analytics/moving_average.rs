@@ -0,0 +1,73 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovingAverageKind {
+    Sma,
+    Ema,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaCrossover {
+    pub fast: f64,
+    pub slow: f64,
+    // 1 -- быстрая пересекла медленную снизу вверх на последней свече, -1 -- сверху вниз, 0 -- пересечения нет
+    pub cross: i8,
+}
+
+pub fn sma(values: &[f64], period: usize) -> Option<f64> {
+    if period == 0 || values.len() < period {
+        return None;
+    }
+    Some(values[values.len() - period..].iter().sum::<f64>() / period as f64)
+}
+
+// начальное значение -- SMA первых period значений
+pub fn ema(values: &[f64], period: usize) -> Option<f64> {
+    let mut value = sma(&values[..period.min(values.len())], period)?;
+    let alpha = 2.0 / (period as f64 + 1.0);
+    for v in &values[period..] {
+        value = alpha * v + (1.0 - alpha) * value;
+    }
+    Some(value)
+}
+
+pub fn moving_average(kind: MovingAverageKind, values: &[f64], period: usize) -> Option<f64> {
+    match kind {
+        MovingAverageKind::Sma => sma(values, period),
+        MovingAverageKind::Ema => ema(values, period),
+    }
+}
+
+// values must be sorted by time from old to new
+pub fn ma_crossover(kind: MovingAverageKind, values: &[f64], fast_period: usize, slow_period: usize) -> Option<MaCrossover> {
+    let (_, previous) = values.split_last()?;
+    let fast = moving_average(kind, values, fast_period)?;
+    let slow = moving_average(kind, values, slow_period)?;
+    let cross = match (moving_average(kind, previous, fast_period), moving_average(kind, previous, slow_period)) {
+        (Some(prev_fast), Some(prev_slow)) if prev_fast <= prev_slow && fast > slow => 1,
+        (Some(prev_fast), Some(prev_slow)) if prev_fast >= prev_slow && fast < slow => -1,
+        _ => 0,
+    };
+    Some(MaCrossover { fast, slow, cross })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::moving_average::{ema, ma_crossover, sma, MovingAverageKind};
+
+    #[test]
+    fn test_sma_ema() {
+        assert_eq!(sma(&[1.0, 2.0, 3.0, 4.0], 2), Some(3.5));
+        assert_eq!(sma(&[1.0], 2), None);
+        // alpha = 0.5, старт с sma(1, 2, 3) = 2
+        assert_eq!(ema(&[1.0, 2.0, 3.0, 6.0], 3), Some(4.0));
+    }
+
+    #[test]
+    fn test_crossover() {
+        let up = [5.0, 4.0, 3.0, 2.0, 6.0];
+        assert_eq!(ma_crossover(MovingAverageKind::Sma, &up, 1, 3).unwrap().cross, 1);
+        let down = [1.0, 2.0, 3.0, 4.0, 0.0];
+        assert_eq!(ma_crossover(MovingAverageKind::Sma, &down, 1, 3).unwrap().cross, -1);
+        let none = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(ma_crossover(MovingAverageKind::Ema, &none, 2, 3).unwrap().cross, 0);
+    }
+}
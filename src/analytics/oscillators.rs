@@ -0,0 +1,113 @@
+use tinkoff_invest_api::tcs::Candle;
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone)]
+pub struct BollingerBands {
+    pub lower: f64,
+    // скользящая средняя
+    pub middle: f64,
+    pub upper: f64,
+}
+
+impl BollingerBands {
+    // ширина канала в % от средней
+    pub fn width_prc(&self) -> f64 {
+        if self.middle == 0.0 { 0.0 } else { (self.upper - self.lower) / self.middle * 100.0 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DonchianChannel {
+    // минимум low и максимум high за период
+    pub lower: f64,
+    pub upper: f64,
+}
+
+// candles must be sorted by time from old to new
+pub fn closes(candles: &[Candle]) -> Vec<f64> {
+    candles.iter().map(|candle| candle.close.clone().unwrap().to_f()).collect()
+}
+
+// по последним period закрытиям, отклонение -- популяционное, как в большинстве терминалов
+pub fn bollinger(closes: &[f64], period: usize, std_multiplier: f64) -> Option<BollingerBands> {
+    if period == 0 || closes.len() < period {
+        return None;
+    }
+    let window = &closes[closes.len() - period..];
+    let middle = window.iter().sum::<f64>() / period as f64;
+    let std = (window.iter().map(|close| (close - middle).powi(2)).sum::<f64>() / period as f64).sqrt();
+    Some(BollingerBands { lower: middle - std * std_multiplier, middle, upper: middle + std * std_multiplier })
+}
+
+// RSI по Уайлдеру, 0-100. Нужно хотя бы period + 1 закрытий
+pub fn rsi(closes: &[f64], period: usize) -> Option<f64> {
+    if period == 0 || closes.len() < period + 1 {
+        return None;
+    }
+    let changes: Vec<f64> = closes.windows(2).map(|window| window[1] - window[0]).collect();
+    let mut avg_gain = changes[..period].iter().filter(|c| **c > 0.0).sum::<f64>() / period as f64;
+    let mut avg_loss = -changes[..period].iter().filter(|c| **c < 0.0).sum::<f64>() / period as f64;
+    for change in &changes[period..] {
+        avg_gain = (avg_gain * (period - 1) as f64 + change.max(0.0)) / period as f64;
+        avg_loss = (avg_loss * (period - 1) as f64 + (-change).max(0.0)) / period as f64;
+    }
+    if avg_loss == 0.0 {
+        return Some(if avg_gain == 0.0 { 50.0 } else { 100.0 });
+    }
+    Some(100.0 - 100.0 / (1.0 + avg_gain / avg_loss))
+}
+
+// канал по period свечам перед последней, чтобы последнюю свечу можно было сравнивать с ним. candles must be sorted by time from old to new
+pub fn donchian(candles: &[Candle], period: usize) -> Option<DonchianChannel> {
+    if period == 0 || candles.len() < period + 1 {
+        return None;
+    }
+    let window = &candles[candles.len() - 1 - period..candles.len() - 1];
+    let lower = window.iter().map(|candle| candle.low.clone().unwrap().to_f()).fold(f64::INFINITY, f64::min);
+    let upper = window.iter().map(|candle| candle.high.clone().unwrap().to_f()).fold(f64::NEG_INFINITY, f64::max);
+    Some(DonchianChannel { lower, upper })
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{Candle, Quotation};
+    use crate::analytics::oscillators::{bollinger, donchian, rsi};
+    use crate::utils::quotation::QuotationExtension;
+
+    #[test]
+    fn test_bollinger() {
+        let bands = bollinger(&[1.0, 2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0], 8, 2.0).unwrap();
+        // среднее 5, отклонение 2
+        assert_eq!(bands.middle, 5.0);
+        assert_eq!(bands.lower, 1.0);
+        assert_eq!(bands.upper, 9.0);
+        assert_eq!(bands.width_prc(), 160.0);
+        assert!(bollinger(&[1.0, 2.0], 3, 2.0).is_none());
+    }
+
+    #[test]
+    fn test_rsi() {
+        assert_eq!(rsi(&[1.0, 2.0, 3.0, 4.0], 3), Some(100.0));
+        assert_eq!(rsi(&[4.0, 3.0, 2.0, 1.0], 3), Some(0.0));
+        // прирост 2, падение 1 -> rs = 2
+        let value = rsi(&[10.0, 12.0, 11.0], 2).unwrap();
+        assert!((value - 100.0 * 2.0 / 3.0).abs() < 1e-9);
+        assert!(rsi(&[1.0, 2.0], 2).is_none());
+    }
+
+    #[test]
+    fn test_donchian() {
+        let candle = |low: f64, high: f64| Candle {
+            low: Some(Quotation::from_f(low)),
+            high: Some(Quotation::from_f(high)),
+            ..Default::default()
+        };
+        let candles = [candle(1.0, 9.0), candle(3.0, 5.0), candle(2.0, 6.0), candle(4.0, 10.0)];
+        // последняя свеча в канал не входит
+        let channel = donchian(&candles, 2).unwrap();
+        assert_eq!(channel.lower, 2.0);
+        assert_eq!(channel.upper, 6.0);
+        assert_eq!(donchian(&candles, 3).unwrap().upper, 9.0);
+        assert!(donchian(&candles, 4).is_none());
+    }
+}
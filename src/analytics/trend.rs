@@ -0,0 +1,238 @@
+use tinkoff_invest_api::tcs::Candle;
+use crate::trading_cfg::TrendCfg;
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrendDirection {
+    Bullish,
+    Bearish,
+    Flat,
+}
+
+// структура рынка по экстремумам: HH+HL -- восходящая, LH+LL -- нисходящая
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwingStructure {
+    HigherHighsHigherLows,
+    LowerHighsLowerLows,
+    Mixed,
+    Undefined, // меньше двух экстремумов одного типа
+}
+
+#[derive(Debug, Clone)]
+pub struct SwingPoint {
+    pub price: f64,
+    pub volume: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrendAnalysis {
+    // по наклону линейной регрессии цен закрытия
+    pub direction: TrendDirection,
+    // 0-1, насколько цены закрытия ложатся на прямую
+    pub r2: f64,
+    // 0-100, сила тренда без учета направления
+    pub adx: f64,
+    pub plus_di: f64,
+    pub minus_di: f64,
+    pub structure: SwingStructure,
+}
+
+impl TrendAnalysis {
+    // candles must be sorted by time from old to new
+    pub fn new(trend_cfg: &TrendCfg, candles: &[Candle]) -> Option<Self> {
+        if candles.len() < 2 {
+            return None;
+        }
+        let closes: Vec<f64> = candles.iter().map(|c| c.close.clone().unwrap().to_f()).collect();
+        let (slope, r2) = linear_regression(&closes);
+        let mean_close = closes.iter().sum::<f64>() / closes.len() as f64;
+        let slope_prc = if mean_close != 0.0 { slope / mean_close * 100.0 } else { 0.0 };
+        let (adx, plus_di, minus_di) = adx(candles, trend_cfg.adx_period);
+        let (swing_highs, swing_lows) = swing_points(candles, trend_cfg.swing_depth);
+        let structure = swing_structure(&swing_highs, &swing_lows);
+
+        let direction = if slope_prc.abs() < trend_cfg.flat_slope_prc {
+            TrendDirection::Flat
+        } else if slope_prc > 0.0 {
+            TrendDirection::Bullish
+        } else {
+            TrendDirection::Bearish
+        };
+
+        Some(TrendAnalysis { direction, r2, adx, plus_di, minus_di, structure })
+    }
+
+    // тренд в сторону direction не слабее min_strength, DI и структура экстремумов ему не противоречат
+    pub fn confirms(&self, direction: TrendDirection, min_strength: f64) -> bool {
+        let is_di_confirmed = match direction {
+            TrendDirection::Bullish => self.plus_di >= self.minus_di,
+            TrendDirection::Bearish => self.minus_di >= self.plus_di,
+            TrendDirection::Flat => true,
+        };
+        let is_structure_confirmed = !matches!(
+            (direction, self.structure),
+            (TrendDirection::Bullish, SwingStructure::LowerHighsLowerLows) | (TrendDirection::Bearish, SwingStructure::HigherHighsHigherLows)
+        );
+        self.direction == direction && self.strength() >= min_strength && is_di_confirmed && is_structure_confirmed
+    }
+
+    // сила тренда 0-100: ADX, взвешенный на то насколько ровно цена идет по прямой
+    pub fn strength(&self) -> f64 {
+        self.adx * self.r2
+    }
+}
+
+// (slope, r2) for y over x = 0, 1, 2, ...
+pub fn linear_regression(values: &[f64]) -> (f64, f64) {
+    let n = values.len() as f64;
+    if values.len() < 2 {
+        return (0.0, 0.0);
+    }
+    let mean_x = (n - 1.0) / 2.0;
+    let mean_y = values.iter().sum::<f64>() / n;
+    let mut cov = 0.0;
+    let mut var_x = 0.0;
+    let mut var_y = 0.0;
+    for (i, y) in values.iter().enumerate() {
+        let dx = i as f64 - mean_x;
+        let dy = y - mean_y;
+        cov += dx * dy;
+        var_x += dx * dx;
+        var_y += dy * dy;
+    }
+    let slope = cov / var_x;
+    let r2 = if var_y == 0.0 { 0.0 } else { (cov * cov) / (var_x * var_y) };
+    (slope, r2)
+}
+
+// ADX по Уайлдеру, период урезается до доступного количества свеч. Возвращает (adx, +DI, -DI)
+pub fn adx(candles: &[Candle], period: usize) -> (f64, f64, f64) {
+    if candles.len() < 2 || period == 0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let period = period.min(candles.len() - 1);
+    let mut tr = Vec::new();
+    let mut plus_dm = Vec::new();
+    let mut minus_dm = Vec::new();
+    for window in candles.windows(2) {
+        let (prev, curr) = (&window[0], &window[1]);
+        let high = curr.high.clone().unwrap().to_f();
+        let low = curr.low.clone().unwrap().to_f();
+        let prev_high = prev.high.clone().unwrap().to_f();
+        let prev_low = prev.low.clone().unwrap().to_f();
+        let prev_close = prev.close.clone().unwrap().to_f();
+
+        tr.push((high - low).max((high - prev_close).abs()).max((low - prev_close).abs()));
+        let up_move = high - prev_high;
+        let down_move = prev_low - low;
+        plus_dm.push(if up_move > down_move && up_move > 0.0 { up_move } else { 0.0 });
+        minus_dm.push(if down_move > up_move && down_move > 0.0 { down_move } else { 0.0 });
+    }
+
+    let mut sm_tr: f64 = tr[..period].iter().sum();
+    let mut sm_plus: f64 = plus_dm[..period].iter().sum();
+    let mut sm_minus: f64 = minus_dm[..period].iter().sum();
+    let di = |sm_dm: f64, sm_tr: f64| if sm_tr == 0.0 { 0.0 } else { 100.0 * sm_dm / sm_tr };
+    let dx = |plus: f64, minus: f64| if plus + minus == 0.0 { 0.0 } else { 100.0 * (plus - minus).abs() / (plus + minus) };
+
+    let mut dxs = vec![dx(di(sm_plus, sm_tr), di(sm_minus, sm_tr))];
+    for i in period..tr.len() {
+        sm_tr = sm_tr - sm_tr / period as f64 + tr[i];
+        sm_plus = sm_plus - sm_plus / period as f64 + plus_dm[i];
+        sm_minus = sm_minus - sm_minus / period as f64 + minus_dm[i];
+        dxs.push(dx(di(sm_plus, sm_tr), di(sm_minus, sm_tr)));
+    }
+
+    let first = dxs.len().min(period);
+    let mut adx = dxs[..first].iter().sum::<f64>() / first as f64;
+    for value in &dxs[first..] {
+        adx = (adx * (period - 1) as f64 + value) / period as f64;
+    }
+    (adx, di(sm_plus, sm_tr), di(sm_minus, sm_tr))
+}
+
+// экстремум -- свеча, high(low) которой выше(ниже) depth соседей слева и не ниже(не выше) depth соседей справа
+pub fn swing_points(candles: &[Candle], depth: usize) -> (Vec<SwingPoint>, Vec<SwingPoint>) {
+    let mut highs = Vec::new();
+    let mut lows = Vec::new();
+    if depth == 0 || candles.len() < 2 * depth + 1 {
+        return (highs, lows);
+    }
+    let high = |i: usize| candles[i].high.clone().unwrap().to_f();
+    let low = |i: usize| candles[i].low.clone().unwrap().to_f();
+    for (i, candle) in candles.iter().enumerate().take(candles.len() - depth).skip(depth) {
+        let is_high = (i - depth..i).all(|j| high(j) < high(i)) && (i + 1..=i + depth).all(|j| high(j) <= high(i));
+        let is_low = (i - depth..i).all(|j| low(j) > low(i)) && (i + 1..=i + depth).all(|j| low(j) >= low(i));
+        if is_high {
+            highs.push(SwingPoint { price: high(i), volume: candle.volume });
+        }
+        if is_low {
+            lows.push(SwingPoint { price: low(i), volume: candle.volume });
+        }
+    }
+    (highs, lows)
+}
+
+pub fn swing_structure(highs: &[SwingPoint], lows: &[SwingPoint]) -> SwingStructure {
+    if highs.len() < 2 || lows.len() < 2 {
+        return SwingStructure::Undefined;
+    }
+    let rising = |points: &[SwingPoint]| points.windows(2).all(|w| w[1].price > w[0].price);
+    let falling = |points: &[SwingPoint]| points.windows(2).all(|w| w[1].price < w[0].price);
+    if rising(highs) && rising(lows) {
+        SwingStructure::HigherHighsHigherLows
+    } else if falling(highs) && falling(lows) {
+        SwingStructure::LowerHighsLowerLows
+    } else {
+        SwingStructure::Mixed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::trend::{linear_regression, SwingStructure, TrendAnalysis, TrendDirection};
+    use crate::trading_cfg::TrendCfg;
+    use crate::utils::candle::new_test_candle;
+
+    fn trend_cfg() -> TrendCfg {
+        TrendCfg { max_candle_skip: 1, adx_period: 14, swing_depth: 1, flat_slope_prc: 0.05, min_strength: 0.0 }
+    }
+
+    #[test]
+    fn test_linear_regression() {
+        let (slope, r2) = linear_regression(&[1.0, 3.0, 5.0, 7.0]);
+        assert_eq!(slope, 2.0);
+        assert_eq!(r2, 1.0);
+
+        let (slope, r2) = linear_regression(&[5.0, 5.0, 5.0]);
+        assert_eq!(slope, 0.0);
+        assert_eq!(r2, 0.0);
+    }
+
+    #[test]
+    fn test_trend_bullish() {
+        // пила вверх: каждый откат не доходит до предыдущего минимума
+        let closes = [100.0, 102.0, 101.0, 104.0, 103.0, 106.0, 105.0, 108.0, 107.0, 110.0];
+        let candles: Vec<_> = closes.iter().enumerate()
+            .map(|(i, c)| new_test_candle(i as i64, c - 0.5, c + 0.5, c - 1.0, *c, 100))
+            .collect();
+        let trend = TrendAnalysis::new(&trend_cfg(), &candles).unwrap();
+        assert_eq!(trend.direction, TrendDirection::Bullish);
+        assert_eq!(trend.structure, SwingStructure::HigherHighsHigherLows);
+        assert!(trend.plus_di > trend.minus_di);
+        assert!(trend.adx > 50.0);
+        assert!(trend.confirms(TrendDirection::Bullish, 50.0));
+        assert!(!trend.confirms(TrendDirection::Bearish, 0.0));
+    }
+
+    #[test]
+    fn test_trend_flat() {
+        let closes = [100.0, 100.5, 100.0, 100.5, 100.0, 100.5];
+        let candles: Vec<_> = closes.iter().enumerate()
+            .map(|(i, c)| new_test_candle(i as i64, *c, c + 0.5, c - 0.5, *c, 100))
+            .collect();
+        let trend = TrendAnalysis::new(&trend_cfg(), &candles).unwrap();
+        assert_eq!(trend.direction, TrendDirection::Flat);
+        assert!(trend.strength() < 10.0);
+    }
+}
@@ -0,0 +1,126 @@
+use tinkoff_invest_api::tcs::Candle;
+use crate::trading_cfg::VolatilityCfg;
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolatilityRegime {
+    Low,
+    Normal,
+    High,
+}
+
+// волатильность за одну свечу интервала, без приведения к году
+#[derive(Debug, Clone)]
+pub struct VolatilityStats {
+    pub atr: f64,
+    // по тому, где текущий средний диапазон свечи (high-low)/open находится среди исторических значений
+    pub regime: VolatilityRegime,
+}
+
+struct Ohlc {
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+}
+
+fn ohlc(candle: &Candle) -> Ohlc {
+    Ohlc {
+        open: candle.open.clone().unwrap().to_f(),
+        high: candle.high.clone().unwrap().to_f(),
+        low: candle.low.clone().unwrap().to_f(),
+        close: candle.close.clone().unwrap().to_f(),
+    }
+}
+
+impl VolatilityStats {
+    // candles must be sorted by time from old to new
+    pub fn new(volatility_cfg: &VolatilityCfg, candles: &[Candle]) -> Option<Self> {
+        if candles.len() < 2 {
+            return None;
+        }
+        let atr = atr(candles, volatility_cfg.atr_period);
+        let ranges: Vec<f64> = candles.iter().map(|candle| range_prc(&ohlc(candle))).collect();
+
+        let mut rolling: Vec<f64> = ranges.windows(volatility_cfg.atr_period.clamp(1, ranges.len()))
+            .map(|window| window.iter().sum::<f64>() / window.len() as f64)
+            .collect();
+        let current = *rolling.last().unwrap();
+        rolling.sort_by(|a, b| a.total_cmp(b));
+        let range_rank = percentile_rank(&rolling, current);
+        let regime = if range_rank < volatility_cfg.low_percentile {
+            VolatilityRegime::Low
+        } else if range_rank > volatility_cfg.high_percentile {
+            VolatilityRegime::High
+        } else {
+            VolatilityRegime::Normal
+        };
+        Some(VolatilityStats { atr, regime })
+    }
+}
+
+fn range_prc(candle: &Ohlc) -> f64 {
+    if candle.open == 0.0 { 0.0 } else { (candle.high - candle.low) / candle.open * 100.0 }
+}
+
+// average true range по Уайлдеру, период урезается до доступного количества свеч
+pub fn atr(candles: &[Candle], period: usize) -> f64 {
+    if candles.len() < 2 || period == 0 {
+        return 0.0;
+    }
+    let tr: Vec<f64> = candles.windows(2).map(|window| {
+        let prev_close = ohlc(&window[0]).close;
+        let c = ohlc(&window[1]);
+        (c.high - c.low).max((c.high - prev_close).abs()).max((c.low - prev_close).abs())
+    }).collect();
+    let period = period.min(tr.len());
+    let mut atr = tr[..period].iter().sum::<f64>() / period as f64;
+    for value in &tr[period..] {
+        atr = (atr * (period - 1) as f64 + value) / period as f64;
+    }
+    atr
+}
+
+// values must be sorted. Доля значений строго меньше value плюс половина равных, в процентах
+pub fn percentile_rank(values: &[f64], value: f64) -> f64 {
+    if values.is_empty() {
+        return 50.0;
+    }
+    let less = values.iter().filter(|v| **v < value).count() as f64;
+    let equal = values.iter().filter(|v| **v == value).count() as f64;
+    (less + equal / 2.0) / values.len() as f64 * 100.0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::volatility::{atr, percentile_rank, VolatilityRegime, VolatilityStats};
+    use crate::trading_cfg::VolatilityCfg;
+    use crate::utils::candle::new_test_candle;
+
+    #[test]
+    fn test_atr_and_percentile_rank() {
+        let candles = vec![
+            new_test_candle(0, 100.0, 101.0, 99.0, 100.0, 1),
+            new_test_candle(1, 100.0, 102.0, 100.0, 101.0, 1),
+            new_test_candle(2, 101.0, 101.0, 97.0, 98.0, 1),
+        ];
+        // true range: 2, 4
+        assert_eq!(atr(&candles, 2), 3.0);
+        assert_eq!(atr(&candles, 1), 4.0);
+        assert_eq!(percentile_rank(&[1.0, 2.0, 3.0, 4.0, 5.0], 3.0), 50.0);
+        assert_eq!(percentile_rank(&[1.0, 2.0], 5.0), 100.0);
+    }
+
+    #[test]
+    fn test_regime() {
+        let volatility_cfg = VolatilityCfg { window_size_min: 60, atr_period: 2, low_percentile: 20.0, high_percentile: 80.0, target_atr_multiplier: 2.0, allowed_regimes: vec![] };
+        let mut candles: Vec<_> = (0..10).map(|i| new_test_candle(i, 100.0, 100.5, 99.5, 100.0, 1)).collect();
+        candles.push(new_test_candle(10, 100.0, 103.0, 97.0, 102.0, 1));
+        let stats = VolatilityStats::new(&volatility_cfg, &candles).unwrap();
+        assert_eq!(stats.regime, VolatilityRegime::High);
+
+        candles.truncate(10);
+        let stats = VolatilityStats::new(&volatility_cfg, &candles).unwrap();
+        assert_eq!(stats.regime, VolatilityRegime::Normal);
+    }
+}
@@ -0,0 +1,136 @@
+use tinkoff_invest_api::tcs::Candle;
+use crate::utils::candle::CandleExtension;
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone)]
+pub struct VolumeBucket {
+    pub price_low: f64,
+    pub price_high: f64,
+    pub volume: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeProfile {
+    pub buckets: Vec<VolumeBucket>,
+    // point of control -- середина корзины с максимальным объемом
+    pub poc: f64,
+    pub total_volume: i64,
+}
+
+impl VolumeProfile {
+    // доля объема окна в корзине point of control, чем выше -- тем сильнее цена держалась у poc
+    pub fn poc_share(&self) -> f64 {
+        if self.total_volume == 0 {
+            return 0.0;
+        }
+        self.buckets.iter().map(|bucket| bucket.volume).fold(0.0, f64::max) / self.total_volume as f64
+    }
+}
+
+// объем последней свечи относительно среднего объема avg_period свеч перед ней.
+// candles must be sorted by time from old to new
+pub fn relative_volume(candles: &[Candle], avg_period: usize) -> Option<f64> {
+    let (last, previous) = candles.split_last()?;
+    let previous = &previous[previous.len().saturating_sub(avg_period)..];
+    if previous.is_empty() {
+        return None;
+    }
+    let avg = previous.iter().map(|candle| candle.volume as f64).sum::<f64>() / previous.len() as f64;
+    if avg == 0.0 {
+        None
+    } else {
+        Some(last.volume as f64 / avg)
+    }
+}
+
+// последняя свеча закрылась за пределами high/low предыдущих свеч. 1 -- вверх, -1 -- вниз, 0 -- пробоя нет
+pub fn breakout_direction(candles: &[Candle]) -> i8 {
+    match candles.split_last() {
+        Some((last, previous)) if !previous.is_empty() => {
+            let close = last.close.clone().unwrap().to_f();
+            let high = previous.iter().map(|candle| candle.high.clone().unwrap().to_f()).fold(f64::MIN, f64::max);
+            let low = previous.iter().map(|candle| candle.low.clone().unwrap().to_f()).fold(f64::MAX, f64::min);
+            if close > high {
+                1
+            } else if close < low {
+                -1
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    }
+}
+
+// (объем бычьих свеч, объем медвежьих свеч)
+pub fn directional_volume(candles: &[Candle]) -> (i64, i64) {
+    let up = candles.iter().filter(|candle| candle.is_bullish()).map(|candle| candle.volume).sum();
+    let down = candles.iter().filter(|candle| candle.is_bearish()).map(|candle| candle.volume).sum();
+    (up, down)
+}
+
+// объем каждой свечи равномерно размазывается по ее диапазону low-high
+pub fn volume_profile(candles: &[Candle], buckets_count: usize) -> Option<VolumeProfile> {
+    if candles.is_empty() || buckets_count == 0 {
+        return None;
+    }
+    let low = candles.iter().map(|candle| candle.low.clone().unwrap().to_f()).fold(f64::MAX, f64::min);
+    let high = candles.iter().map(|candle| candle.high.clone().unwrap().to_f()).fold(f64::MIN, f64::max);
+    let step = if high > low { (high - low) / buckets_count as f64 } else { 1.0 };
+    let mut buckets: Vec<VolumeBucket> = (0..buckets_count)
+        .map(|i| VolumeBucket { price_low: low + step * i as f64, price_high: low + step * (i + 1) as f64, volume: 0.0 })
+        .collect();
+
+    for candle in candles {
+        let c_low = candle.low.clone().unwrap().to_f();
+        let c_high = candle.high.clone().unwrap().to_f();
+        if c_high <= c_low {
+            let index = (((c_low - low) / step) as usize).min(buckets_count - 1);
+            buckets[index].volume += candle.volume as f64;
+            continue;
+        }
+        for bucket in buckets.iter_mut() {
+            let overlap = c_high.min(bucket.price_high) - c_low.max(bucket.price_low);
+            if overlap > 0.0 {
+                bucket.volume += candle.volume as f64 * overlap / (c_high - c_low);
+            }
+        }
+    }
+
+    let poc_bucket = buckets.iter().max_by(|a, b| a.volume.total_cmp(&b.volume)).unwrap();
+    let poc = (poc_bucket.price_low + poc_bucket.price_high) / 2.0;
+    Some(VolumeProfile { poc, total_volume: candles.iter().map(|candle| candle.volume).sum(), buckets })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analytics::volume::{breakout_direction, relative_volume, volume_profile};
+    use crate::utils::candle::new_test_candle;
+
+    #[test]
+    fn test_relative_volume_and_breakout() {
+        let candles = vec![
+            new_test_candle(0, 100.0, 101.0, 99.0, 100.5, 100),
+            new_test_candle(1, 100.5, 101.0, 99.5, 100.0, 300),
+            new_test_candle(2, 100.0, 103.0, 100.0, 102.5, 600),
+        ];
+        assert_eq!(relative_volume(&candles, 2), Some(3.0));
+        assert_eq!(relative_volume(&candles, 1), Some(2.0));
+        assert_eq!(breakout_direction(&candles), 1);
+        assert_eq!(breakout_direction(&candles[..2]), 0);
+    }
+
+    #[test]
+    fn test_volume_profile() {
+        let candles = vec![
+            new_test_candle(0, 100.0, 102.0, 100.0, 101.0, 200),
+            new_test_candle(1, 101.0, 101.0, 100.0, 100.5, 1000),
+        ];
+        let profile = volume_profile(&candles, 2).unwrap();
+        assert_eq!(profile.total_volume, 1200);
+        assert_eq!(profile.buckets[0].volume, 1100.0);
+        assert_eq!(profile.buckets[1].volume, 100.0);
+        assert_eq!(profile.poc, 100.5);
+        assert_eq!(profile.poc_share(), 1100.0 / 1200.0);
+    }
+}
@@ -1,27 +1,40 @@
+mod analytics;
 mod state;
 mod strategy;
 mod trading_cfg;
 mod utils;
 mod service;
 
+use std::collections::HashMap;
 use std::fs;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use flume::Sender;
 use prost_types::Timestamp;
 use tonic::{Streaming, transport::{Channel, ClientTlsConfig}};
 use tinkoff_invest_api::{TIResult, TinkoffInvestService};
 use tinkoff_invest_api::tcs::{Account, GetAccountsRequest, InstrumentsRequest, InstrumentStatus, MarketDataRequest, MarketDataResponse, Quotation, Share};
+use tokio::sync::Mutex;
 use tokio::time;
+use crate::analytics::correlation::find_spread_candidates;
 use crate::service::operations_service::{OperationsService, OperationsServiceSandBoxImpl};
-use crate::service::order_service::OrderServiceSandboxImpl;
+use crate::service::order_service::{OrderServiceSandboxImpl, SharedOrderService};
+use crate::service::trading_calendar::{load_trading_calendar, TradingCalendar};
 use crate::service::user_service::BrokerAccountSandboxImpl;
-use crate::state::{run_updater_last_price, run_updater_candles};
-use crate::state::candle_state::{CandleState, CandleStateStatistic};
+use crate::state::{run_updater_last_price, run_updater_candles, run_updater_portfolio};
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
 use crate::state::last_price_state::{LastPriceState, LastPriceStateStatistic};
+use crate::state::portfolio_state::PortfolioState;
 use crate::state::state::State;
+use crate::strategy::registry::{run_strategies, StrategyContext, StrategyRegistry};
+use crate::trading_cfg::{BollingerCfg, BollingerStrategySettings, CorrelationCfg, ExitCfg, FirstStrategySettings, OrderRetryCfg, RsiCfg, SessionRulesCfg, SizingCfg, StrategyCfg, StrategySettings};
+use crate::utils::clock::{Clock, SystemClock};
 use crate::utils::local_tokens;
 
+// окно истории для поиска коинтегрированных пар
+const SPREAD_CANDIDATES_WINDOW_SEC: i64 = 24 * 60 * 60;
+// с запасом, календарь перезагружается раз в сутки
+const CALENDAR_DAYS: i64 = 7;
 
 async fn prepare_channel() -> TIResult<Channel> {
     let is_prod = false;
@@ -48,6 +61,28 @@ async fn prepare_instruments(service: &TinkoffInvestService, tickers: Vec<&str>)
     all_instruments.into_iter().filter(|share| tickers.contains(&&**&share.ticker)).collect()
 }
 
+async fn prepare_trading_calendar(service: &TinkoffInvestService) -> TradingCalendar {
+    let now = Timestamp::from(std::time::SystemTime::now());
+    let channel = match prepare_channel().await {
+        Ok(channel) => channel,
+        Err(e) => {
+            eprintln!("Error while connecting for trading schedules, use static MOEX calendar: {:?}", e);
+            return TradingCalendar::moex_fallback(&now, CALENDAR_DAYS);
+        }
+    };
+    let mut instrument_service_client = service.instruments(channel).await.unwrap();
+    load_trading_calendar(&mut instrument_service_client, "MOEX", now, CALENDAR_DAYS, "./calendar_cache").await
+}
+
+// календарь грузится на CALENDAR_DAYS вперед, обновляется раз в сутки, чтобы не кончиться
+async fn run_updater_calendar(service: &TinkoffInvestService, calendar: Arc<RwLock<TradingCalendar>>, period: Duration) {
+    loop {
+        time::sleep(period).await;
+        let reloaded = prepare_trading_calendar(service).await;
+        *calendar.write().unwrap() = reloaded;
+    }
+}
+
 async fn prepare_md_stream(service: &TinkoffInvestService, request: MarketDataRequest) -> (Sender<MarketDataRequest>, Streaming<MarketDataResponse>) {
     let channel = prepare_channel().await.unwrap();
     let mut marketdata_stream = service.marketdata_stream(channel).await.unwrap();
@@ -76,7 +111,11 @@ async fn prepare_broker_account_service(service: &TinkoffInvestService, account:
 async fn prepare_order_service(service: &TinkoffInvestService, account: Account) -> OrderServiceSandboxImpl {
     let channel = prepare_channel().await.unwrap();
     let order_service = service.sandbox(channel).await.unwrap();
-    OrderServiceSandboxImpl::new(account, order_service)
+    OrderServiceSandboxImpl::new(account, order_service, OrderRetryCfg {
+        max_attempts: 4,
+        initial_backoff_ms: 200,
+        max_backoff_ms: 2000,
+    })
 }
 
 async fn prepare_operations_service(service: &TinkoffInvestService, account: Account) -> OperationsServiceSandBoxImpl {
@@ -85,28 +124,120 @@ async fn prepare_operations_service(service: &TinkoffInvestService, account: Acc
     OperationsServiceSandBoxImpl::new(account, operation_client)
 }
 
+fn strategies_cfg() -> Vec<StrategyCfg> {
+    vec![
+        StrategyCfg {
+            name: "first".to_string(),
+            kind: "first".to_string(),
+            tickers: vec!["SBER".to_string(), "TCSG".to_string()],
+            capital_share: 50,
+            sizing: Some(SizingCfg {
+                risk_prc: 1.0,
+                default_stop_prc: 2.0,
+                min_signal_factor: 0.5,
+                max_position_prc: 50.0,
+                max_lots: HashMap::from([("TCSG".to_string(), 10)]),
+                // SBER и TCSG часто ходят вместе, вторая позиция в ту же сторону удвоила бы риск
+                correlation: Some(CorrelationCfg { window_size_min: 240, rolling_window: 30, max_correlation: 0.8 }),
+            }),
+            session_rules: Some(SessionRulesCfg {
+                no_entry_first_min: 15,
+                no_entry_last_min: 15,
+                flatten_before_close_min: None,
+            }),
+            execution: None,
+            settings: StrategySettings::First(FirstStrategySettings {
+                exit_cfg: Some(ExitCfg {
+                    stop_loss_prc: Some(2.0),
+                    stop_loss_atr: None,
+                    take_profit_prc: None,
+                    trailing: None,
+                    break_even_prc: None,
+                    use_broker_stops: false,
+                }),
+            }),
+        },
+        // SBER торгует и first: сохраненные позиции каждая стратегия забирает себе, несохраненную позицию брокера -- стратегия, идущая в конфиге раньше
+        StrategyCfg {
+            name: "bollinger".to_string(),
+            kind: "bollinger".to_string(),
+            tickers: vec!["SBER".to_string()],
+            capital_share: 50,
+            sizing: None,
+            session_rules: Some(SessionRulesCfg {
+                no_entry_first_min: 15,
+                no_entry_last_min: 30,
+                flatten_before_close_min: Some(10),
+            }),
+            execution: None,
+            settings: StrategySettings::Bollinger(BollingerStrategySettings {
+                bollinger_cfg: BollingerCfg { period: 20, std_multiplier: 2.0, min_width_prc: 0.3 },
+                rsi_cfg: RsiCfg { period: 14, oversold: 30.0 },
+                window_size_min: 60,
+                volatility_cfg: None,
+                exit_cfg: Some(ExitCfg {
+                    stop_loss_prc: Some(1.5),
+                    stop_loss_atr: None,
+                    take_profit_prc: None,
+                    trailing: None,
+                    break_even_prc: None,
+                    use_broker_stops: false,
+                }),
+            }),
+        },
+    ]
+}
+
 #[tokio::main]
 async fn main() -> TIResult<()> {
     let (prod_token, sandbox_token) = local_tokens::get_local_tokens();
-    let tickers = vec!["SBER", "TCSG"];
+    let strategies_cfg = strategies_cfg();
+    let mut tickers: Vec<&str> = strategies_cfg.iter().flat_map(|cfg| cfg.tickers.iter().map(|ticker| ticker.as_str())).collect();
+    tickers.sort();
+    tickers.dedup();
 
     let service = TinkoffInvestService::new(sandbox_token.parse().unwrap());
     let instruments = prepare_instruments(&service, tickers).await;
+    let calendar = Arc::new(RwLock::new(prepare_trading_calendar(&service).await));
 
     let account = chose_account(&service).await;
     let mut broker_account_service = prepare_broker_account_service(&service, account.clone()).await;
-    let mut order_service_sandbox = prepare_order_service(&service, account.clone()).await;
+    let order_service = Arc::new(Mutex::new(prepare_order_service(&service, account.clone()).await));
     let mut operations_service_sandbox = prepare_operations_service(&service, account.clone()).await;
 
     let positions = operations_service_sandbox.get_portfolio().await;
 
     let last_price_state = Arc::new(LastPriceState::new());
     let candle_state = Arc::new(CandleState::new());
+    let portfolio_state = Arc::new(PortfolioState::new());
+    portfolio_state.update(&positions)
+        .unwrap_or_else(|err| eprintln!("Error updating portfolio_state: {}", err));
 
-    let (tx, _) = run_updater_last_price(&service, instruments.clone(), Arc::clone(&last_price_state), order_service_sandbox, positions).await;
+    let (ticks_tx, ticks_rx) = flume::unbounded();
+    let (tx, _) = run_updater_last_price(&service, instruments.clone(), Arc::clone(&last_price_state), ticks_tx).await;
     let (tx, _) = run_updater_candles(&service, instruments.clone(), Arc::clone(&candle_state)).await;
 
-    print_states(last_price_state, candle_state, instruments.clone()).await;
+    let context = StrategyContext {
+        candle_state: Arc::clone(&candle_state),
+        last_price_state: Arc::clone(&last_price_state),
+        portfolio_state: Arc::clone(&portfolio_state),
+        order_service: Arc::clone(&order_service),
+        clock: SystemClock,
+        instruments: instruments.clone(),
+        state_dir: Some("./strategy_state".to_string()),
+        calendar: Some(Arc::clone(&calendar)),
+    };
+    let strategies = StrategyRegistry::<SharedOrderService<OrderServiceSandboxImpl>, SystemClock>::with_defaults()
+        .create_all(&context, &strategies_cfg)
+        .expect("Error while creating strategies");
+
+    tokio::join!(
+        run_strategies(strategies, positions, ticks_rx),
+        run_updater_portfolio(operations_service_sandbox, Arc::clone(&portfolio_state), Duration::from_secs(10)),
+        run_sandbox_stop_orders(order_service, Arc::clone(&last_price_state), Duration::from_secs(1)),
+        run_updater_calendar(&service, calendar, Duration::from_secs(24 * 3600)),
+        print_states(last_price_state, candle_state, instruments.clone()),
+    );
 
     // order_service_sandbox.get_orders().await;
     // operations_service_sandbox.get_positions().await;
@@ -115,10 +246,30 @@ async fn main() -> TIResult<()> {
     Ok(())
 }
 
+// в песочнице нет StopOrders API, стоп-заявки исполняются по последней цене
+async fn run_sandbox_stop_orders(order_service: SharedOrderService<OrderServiceSandboxImpl>, last_price_state: Arc<LastPriceState>, period: Duration) {
+    loop {
+        order_service.lock().await.trigger_stop_orders(&last_price_state).await;
+        time::sleep(period).await;
+    }
+}
+
 async fn print_states(last_price_state: Arc<LastPriceState>, candle_state: Arc<CandleState>, instruments: Vec<Share>) {
+    let instrument_uids: Vec<String> = instruments.iter().map(|instrument| instrument.uid.clone()).collect();
+    let mut iteration: u64 = 0;
     loop {
         println!("Now price: {:?}", last_price_state.get_last_price(&instruments.get(0).unwrap().uid).await);
 
+        // раз в час -- пары торгуемых инструментов, подходящие для парной торговли
+        if iteration.is_multiple_of(1800) {
+            let now = SystemClock.now();
+            let range = SizedRange::new_1m(Timestamp { seconds: now.seconds - SPREAD_CANDIDATES_WINDOW_SEC, nanos: 0 }, now);
+            for (first, second, stats) in find_spread_candidates(&candle_state, &instrument_uids, range).await {
+                println!("Spread candidate {} / {}: spread = price - {} * other - {}, adf_stat={}", first, second, stats.hedge_ratio, stats.intercept, stats.adf_stat);
+            }
+        }
+        iteration += 1;
+
         // let range = SizedRange::new_1m(Timestamp::from(SystemTime::now()), Timestamp::from(SystemTime::now()));
         // println!("Now candles(1): {:?}", candle_state.get_candles(&instruments.get(0).unwrap().uid, range).await);
         //
@@ -137,19 +288,27 @@ mod test {
     use std::error::Error;
     use std::io::{self, Read, Write};
     use std::fs::File;
-    use std::sync::RwLock;
     use chrono::{DateTime, TimeZone, Utc};
     use csv::{ReaderBuilder, StringRecord};
     use mock_instant::{MockClock, SystemTime};
     use prost_types::Timestamp;
     use rand::Rng;
-    use tinkoff_invest_api::tcs::{Candle, Quotation, SubscriptionInterval};
+    use tinkoff_invest_api::tcs::{Candle, LastPrice, Quotation, SubscriptionInterval};
     use reqwest::header::{AUTHORIZATION, HeaderValue};
     use zip::ZipArchive;
     use crate::service::order_service::OrderServiceHistBoxImpl;
-    use crate::strategy::hammer_strategy::HammerStrategy;
-    use crate::strategy::strategy::Strategy;
-    use crate::trading_cfg::{HammerCfg, HammerStrategySettings, TrendCfg};
+    use crate::strategy::bollinger_strategy::BollingerSignals;
+    use crate::strategy::donchian_strategy::DonchianSignals;
+    use crate::strategy::grid_strategy::GridStrategy;
+    use crate::strategy::hammer_strategy::HammerSignals;
+    use crate::strategy::ma_crossover_strategy::MaCrossoverSignals;
+    use crate::strategy::script_strategy::ScriptSignals;
+    use crate::analytics::moving_average::MovingAverageKind;
+    use crate::analytics::trend::TrendDirection;
+    use crate::strategy::strategy::{PatternStrategy, Strategy, StrategyComponents};
+    use crate::analytics::volatility::VolatilityRegime;
+    use crate::trading_cfg::{DonchianStrategySettings, EnsembleCfg, GridStrategySettings, HammerCfg, HammerStrategySettings, LevelsCfg, MaCrossoverStrategySettings, MovingAverageCfg, MultiTimeframeCfg, ScriptLimitsCfg, ScriptStrategySettings, TimeframeCombine, TimeframeCondition, TimeframeRule, TrailingCfg, TrendCfg, VolatilityCfg, VolumeCfg};
+    use crate::utils::clock::HistClock;
     use crate::utils::quotation::QuotationExtension;
 
     fn read_candle(row: StringRecord, interval: SubscriptionInterval) -> Candle {
@@ -286,8 +445,13 @@ mod test {
         Quotation { units, nano }
     }
 
-    #[tokio::test]
-    async fn test_hammer_strategy() {
+    struct HistData {
+        instruments: Vec<Share>,
+        year: u32,
+        candles: Vec<Candle>,
+    }
+
+    async fn load_hist_data() -> HistData {
         let (_, sandbox_token) = local_tokens::get_local_tokens();
         let tickers = vec!["SBER"];
 
@@ -298,14 +462,68 @@ mod test {
         fs::create_dir_all(dir_path).expect("Error creating dir_path for data hist");
 
         let year = 2023;
-        let sorted_stream = prepare_hist_data(&sandbox_token, dir_path, instruments.get(0).unwrap(), year, SubscriptionInterval::OneMinute).await.unwrap();
-        println!("stream_len={:#?}", sorted_stream.len());
-        // todo cross validation like a lot of slices from sorted_stream: sorted_stream[x..y]
+        let candles = prepare_hist_data(&sandbox_token, dir_path, instruments.get(0).unwrap(), year, SubscriptionInterval::OneMinute).await.unwrap();
+        println!("stream_len={:#?}", candles.len());
+        HistData { instruments, year, candles }
+    }
+
+    const TRASH_HOLD: u64 = 100;
 
+    fn new_order_service_mock() -> SharedOrderService<OrderServiceHistBoxImpl<HistClock>> {
         let start_balance = Quotation { units: 10000, nano: 0 };
         let commission = 30_u8; // percentage
-        let trash_hold = 100_u64;
-        let order_service_mock = Arc::new(RwLock::new(OrderServiceHistBoxImpl::new(start_balance.clone(), commission, trash_hold)));
+        let borrow_fee_prc = 0.05; // в день
+        Arc::new(Mutex::new(OrderServiceHistBoxImpl::new(start_balance, commission, TRASH_HOLD, borrow_fee_prc, HistClock)))
+    }
+
+    // прогоняет свечи через состояния и стратегию, печатает итог. Одинаковый для всех стратегий, чтобы их можно было сравнивать
+    async fn run_hist<S: Strategy>(
+        name: &str,
+        strategy: &mut S,
+        hist_data: &HistData,
+        candle_state: &CandleState,
+        last_price_state: &LastPriceState,
+        order_service_mock: &SharedOrderService<OrderServiceHistBoxImpl<HistClock>>,
+    ) {
+        // todo cross validation like a lot of slices from sorted_stream: sorted_stream[x..y]
+        let start_balance = order_service_mock.lock().await.get_balance();
+        let now = std::time::Instant::now();
+        for candle in &hist_data.candles {
+            MockClock::set_system_time(Duration::from_secs(candle.time.clone().unwrap().seconds as u64));
+            MockClock::advance_system_time(Duration::from_secs(60));
+
+            let price = generate_random_price(candle);
+            order_service_mock.lock().await.set_current_price(price.clone());
+            last_price_state.update(&LastPrice {
+                figi: candle.figi.clone(),
+                price: Some(price),
+                time: candle.time.clone(),
+                instrument_uid: candle.instrument_uid.clone(),
+            }).unwrap_or_else(|err| eprintln!("Error updating last_price_state: {}", err));
+
+            candle_state.update(candle)
+                .unwrap_or_else(|err| eprintln!("Error updating candle_state: {}", err));
+
+            strategy.update().await.expect("Error updating strategy");
+        }
+
+        println!("Elapsed test time: {:.10?}", now.elapsed());
+        let order_service = order_service_mock.lock().await;
+        let new_balance = &order_service.balance;
+        println!("{} for
+          instruments={:?},
+          year={:?}
+          start_balance={:?}
+          trash_hold={:?},
+          balance={:?}
+          profit={:?}
+          finished.", name, hist_data.instruments.iter().map(|s| s.ticker.clone()).collect::<Vec<_>>(), hist_data.year, start_balance, TRASH_HOLD, new_balance, new_balance.wr() - start_balance.wr());
+    }
+
+    #[tokio::test]
+    async fn test_hammer_strategy() {
+        let hist_data = load_hist_data().await;
+        let order_service_mock = new_order_service_mock();
 
         let hammer_settings = HammerStrategySettings {
             hammer_cfg: HammerCfg {
@@ -316,42 +534,229 @@ mod test {
             },
             trend_cfg: TrendCfg {
                 max_candle_skip: 1,
+                adx_period: 14,
+                swing_depth: 2,
+                flat_slope_prc: 0.01,
+                min_strength: 0.0,
             },
             window_size_min: 5,
+            levels_cfg: Some(LevelsCfg {
+                window_size_min: 240,
+                swing_depth: 3,
+                cluster_prc: 0.1,
+                min_touches: 2,
+                recency_half_life_min: 120.0,
+            }),
+            volume_cfg: Some(VolumeCfg {
+                avg_period: 20,
+                spike_ratio: 3.0,
+                confirm_ratio: 1.5,
+                trend_volume_ratio: 1.0,
+                profile_buckets: 20,
+            }),
+            confirmation: None, // в исторических данных только минутные свечи
+            volatility_cfg: Some(VolatilityCfg {
+                window_size_min: 120,
+                atr_period: 14,
+                low_percentile: 10.0,
+                high_percentile: 95.0,
+                target_atr_multiplier: 2.0,
+                allowed_regimes: vec![VolatilityRegime::Normal, VolatilityRegime::High],
+            }),
+            exit_cfg: Some(ExitCfg {
+                stop_loss_prc: Some(1.0),
+                stop_loss_atr: Some(2.0),
+                take_profit_prc: None,
+                trailing: Some(TrailingCfg::Atr(3.0)),
+                break_even_prc: Some(0.5),
+                use_broker_stops: false, // у OrderServiceHistBoxImpl нет стоп-заявок
+            }),
+            allow_short: true,
+            short_confirmation: None,
+            ensemble_cfg: Some(EnsembleCfg {
+                weights: HashMap::from([("hammer".to_string(), 3.0), ("trend".to_string(), 2.0), ("volume".to_string(), 1.0), ("timeframes".to_string(), 0.0)]),
+                default_weight: 1.0,
+                buy_threshold: 0.6,
+                sell_threshold: 0.6,
+                vetoes: Vec::new(),
+            }),
         };
         let state = Arc::new(CandleState::new());
-        let mut hammer_strategy = HammerStrategy::new(Arc::clone(&state), Arc::clone(&order_service_mock), instruments.get(0).unwrap().clone(), hammer_settings);
+        let last_price_state = Arc::new(LastPriceState::new());
+        let mut hammer_strategy = PatternStrategy::new(Arc::clone(&state), Arc::clone(&last_price_state), Arc::clone(&order_service_mock), HistClock, StrategyComponents::default(), hist_data.instruments.first().unwrap().clone(), HammerSignals::new(hammer_settings));
 
-        let mut i = 0;
-        let now = std::time::Instant::now();
-        for candle in sorted_stream {
-            MockClock::set_system_time(Duration::from_secs(candle.time.clone().unwrap().seconds as u64));
-            MockClock::advance_system_time(Duration::from_secs(60));
+        run_hist("HammerStrategy", &mut hammer_strategy, &hist_data, &state, &last_price_state, &order_service_mock).await;
 
-            order_service_mock.write().unwrap().current_price = generate_random_price(&candle);
+        assert_eq!(true, false);
+    }
 
-            state.update(&candle)
-                .unwrap_or_else(|err| eprintln!("Error updating last_price_state: {}", err));
+    #[tokio::test]
+    async fn test_bollinger_strategy() {
+        let hist_data = load_hist_data().await;
+        let order_service_mock = new_order_service_mock();
+
+        let bollinger_settings = BollingerStrategySettings {
+            bollinger_cfg: BollingerCfg { period: 20, std_multiplier: 2.0, min_width_prc: 0.3 },
+            rsi_cfg: RsiCfg { period: 14, oversold: 30.0 },
+            window_size_min: 60,
+            volatility_cfg: Some(VolatilityCfg {
+                window_size_min: 120,
+                atr_period: 14,
+                low_percentile: 10.0,
+                high_percentile: 95.0,
+                target_atr_multiplier: 2.0,
+                allowed_regimes: vec![VolatilityRegime::Normal, VolatilityRegime::High],
+            }),
+            exit_cfg: Some(ExitCfg {
+                stop_loss_prc: Some(1.0),
+                stop_loss_atr: Some(2.0),
+                take_profit_prc: None,
+                trailing: None,
+                break_even_prc: None,
+                use_broker_stops: false, // у OrderServiceHistBoxImpl нет стоп-заявок
+            }),
+        };
+        let state = Arc::new(CandleState::new());
+        let last_price_state = Arc::new(LastPriceState::new());
+        let mut bollinger_strategy = PatternStrategy::new(Arc::clone(&state), Arc::clone(&last_price_state), Arc::clone(&order_service_mock), HistClock, StrategyComponents::default(), hist_data.instruments.first().unwrap().clone(), BollingerSignals::new(bollinger_settings));
 
-            hammer_strategy.update().await.expect("Error updating first strategy");
-            i += 1;
-            // println!("i={}", i);
-            // if i > 1000 { // 261933 all
-            //     break;
-            // }
-        }
+        run_hist("BollingerStrategy", &mut bollinger_strategy, &hist_data, &state, &last_price_state, &order_service_mock).await;
 
-        println!("Elapsed test time: {:.10?}", now.elapsed());
-        let new_balance = &order_service_mock.read().unwrap().balance;
-        println!("HammerStrategy for
-          instruments={:?},
-          year={:?}
-          start_balance={:?}
-          trash_hold={:?},
-          balance={:?}
-          profit={:?}
-          finished.", instruments.iter().map(|s| s.ticker.clone()).collect::<Vec<_>>(), year, start_balance, trash_hold, new_balance, new_balance.wr() - start_balance.wr());
+        assert_eq!(true, false);
+    }
+
+    #[tokio::test]
+    async fn test_script_strategy() {
+        let hist_data = load_hist_data().await;
+        let order_service_mock = new_order_service_mock();
+
+        let script_settings = ScriptStrategySettings {
+            path: "./scripts/bollinger_rsi.rules".to_string(),
+            window_size_min: 120,
+            reload_sec: 60,
+            limits: ScriptLimitsCfg { max_source_len: 10_000, max_statements: 50, max_depth: 16, max_ops: 1_000, max_period: 200 },
+            exit_cfg: None,
+        };
+        let state = Arc::new(CandleState::new());
+        let last_price_state = Arc::new(LastPriceState::new());
+        let mut script_strategy = PatternStrategy::new(Arc::clone(&state), Arc::clone(&last_price_state), Arc::clone(&order_service_mock), HistClock, StrategyComponents::default(), hist_data.instruments.first().unwrap().clone(), ScriptSignals::new(script_settings));
+
+        run_hist("ScriptStrategy", &mut script_strategy, &hist_data, &state, &last_price_state, &order_service_mock).await;
 
         assert_eq!(true, false);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_ma_crossover_strategy() {
+        let hist_data = load_hist_data().await;
+        let order_service_mock = new_order_service_mock();
+
+        let ma_crossover_settings = MaCrossoverStrategySettings {
+            ma_cfg: MovingAverageCfg { kind: MovingAverageKind::Ema, fast_period: 9, slow_period: 21 },
+            interval: SubscriptionInterval::OneMinute,
+            window_size_min: 120,
+            // в исторических данных только минутные свечи, поэтому фильтр на том же интервале, но на длинном окне
+            confirmation: Some(MultiTimeframeCfg {
+                rules: vec![TimeframeRule {
+                    interval: SubscriptionInterval::OneMinute,
+                    window_size_min: 240,
+                    condition: TimeframeCondition::TrendDirection(TrendCfg {
+                        max_candle_skip: 1,
+                        adx_period: 14,
+                        swing_depth: 2,
+                        flat_slope_prc: 0.01,
+                        min_strength: 0.0,
+                    }, TrendDirection::Bullish),
+                }],
+                combine: TimeframeCombine::All,
+            }),
+            volatility_cfg: Some(VolatilityCfg {
+                window_size_min: 120,
+                atr_period: 14,
+                low_percentile: 10.0,
+                high_percentile: 95.0,
+                target_atr_multiplier: 2.0,
+                allowed_regimes: vec![],
+            }),
+            exit_cfg: Some(ExitCfg {
+                stop_loss_prc: None,
+                stop_loss_atr: Some(2.0),
+                take_profit_prc: None,
+                trailing: Some(TrailingCfg::Atr(3.0)),
+                break_even_prc: None,
+                use_broker_stops: false, // у OrderServiceHistBoxImpl нет стоп-заявок
+            }),
+        };
+        let state = Arc::new(CandleState::new());
+        let last_price_state = Arc::new(LastPriceState::new());
+        let mut ma_crossover_strategy = PatternStrategy::new(Arc::clone(&state), Arc::clone(&last_price_state), Arc::clone(&order_service_mock), HistClock, StrategyComponents::default(), hist_data.instruments.first().unwrap().clone(), MaCrossoverSignals::new(ma_crossover_settings));
+
+        run_hist("MaCrossoverStrategy", &mut ma_crossover_strategy, &hist_data, &state, &last_price_state, &order_service_mock).await;
+
+        assert_eq!(true, false);
+    }
+
+    #[tokio::test]
+    async fn test_donchian_strategy() {
+        let hist_data = load_hist_data().await;
+        let order_service_mock = new_order_service_mock();
+
+        let donchian_settings = DonchianStrategySettings {
+            entry_period: 20,
+            exit_period: 10,
+            interval: SubscriptionInterval::OneMinute,
+            window_size_min: 60,
+            volume_cfg: Some(VolumeCfg {
+                avg_period: 20,
+                spike_ratio: 3.0,
+                confirm_ratio: 1.5,
+                trend_volume_ratio: 1.2,
+                profile_buckets: 20,
+            }),
+            volatility_cfg: Some(VolatilityCfg {
+                window_size_min: 120,
+                atr_period: 14,
+                low_percentile: 20.0,
+                high_percentile: 90.0,
+                target_atr_multiplier: 2.0,
+                allowed_regimes: vec![VolatilityRegime::Normal, VolatilityRegime::High],
+            }),
+            exit_cfg: Some(ExitCfg {
+                stop_loss_prc: None,
+                stop_loss_atr: Some(2.0),
+                take_profit_prc: None,
+                trailing: Some(TrailingCfg::Atr(3.0)),
+                break_even_prc: None,
+                use_broker_stops: false, // у OrderServiceHistBoxImpl нет стоп-заявок
+            }),
+            allow_short: true,
+        };
+        let state = Arc::new(CandleState::new());
+        let last_price_state = Arc::new(LastPriceState::new());
+        let mut donchian_strategy = PatternStrategy::new(Arc::clone(&state), Arc::clone(&last_price_state), Arc::clone(&order_service_mock), HistClock, StrategyComponents::default(), hist_data.instruments.first().unwrap().clone(), DonchianSignals::new(donchian_settings));
+
+        run_hist("DonchianStrategy", &mut donchian_strategy, &hist_data, &state, &last_price_state, &order_service_mock).await;
+
+        assert_eq!(true, false);
+    }
+
+    #[tokio::test]
+    async fn test_grid_strategy() {
+        let hist_data = load_hist_data().await;
+        let order_service_mock = new_order_service_mock();
+
+        let grid_settings = GridStrategySettings {
+            step_prc: 0.5,
+            levels_count: 5,
+            lots_per_level: 1,
+            orders_check_sec: 60,
+        };
+        let state = Arc::new(CandleState::new());
+        let last_price_state = Arc::new(LastPriceState::new());
+        let mut grid_strategy = GridStrategy::new(Arc::clone(&last_price_state), Arc::clone(&order_service_mock), HistClock, None, hist_data.instruments.first().unwrap().clone(), grid_settings);
+
+        run_hist("GridStrategy", &mut grid_strategy, &hist_data, &state, &last_price_state, &order_service_mock).await;
+
+        assert_eq!(true, false);
+    }
+}
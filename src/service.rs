@@ -1,3 +1,7 @@
+pub mod execution_service;
 pub mod operations_service;
+pub mod order_retry;
 pub mod order_service;
+pub mod order_tracker;
+pub mod trading_calendar;
 pub mod user_service;
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use prost_types::Timestamp;
+use tinkoff_invest_api::tcs::{Candle, OrderDirection, OrderType, PostOrderResponse, Quotation};
+use tonic::{Response, Status};
+use crate::service::order_service::OrderService;
+use crate::service::order_tracker::{OrderFill, OrderTracker};
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
+use crate::strategy::strategy::OrderIntents;
+use crate::trading_cfg::ExecutionAlgo;
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+const DAY_SEC: i64 = 24 * 60 * 60;
+
+// крупная заявка, которую ExecutionService исполняет дочерними заявками
+#[derive(Debug, Clone)]
+pub struct ParentOrder {
+    pub figi: String,
+    pub instrument_id: String,
+    pub direction: OrderDirection,
+    // в лотах
+    pub quantity: i64,
+    // цена лимитных заявок айсберга, по умолчанию -- цена на момент постановки
+    pub limit_price: Option<Quotation>,
+    pub algo: ExecutionAlgo,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChildFill {
+    pub order_id: String,
+    pub quantity: i64,
+    // цена одной бумаги
+    pub price: f64,
+    pub commission: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutionReport {
+    pub parent: ParentOrder,
+    // цена на момент постановки родительской заявки, от нее считается проскальзывание
+    pub arrival_price: f64,
+    pub fills: Vec<ChildFill>,
+    pub is_completed: bool,
+}
+
+impl ExecutionReport {
+    pub fn filled_lots(&self) -> i64 {
+        self.fills.iter().map(|fill| fill.quantity).sum()
+    }
+
+    pub fn remaining_lots(&self) -> i64 {
+        self.parent.quantity - self.filled_lots()
+    }
+
+    pub fn avg_price(&self) -> Option<f64> {
+        let filled = self.filled_lots();
+        if filled == 0 {
+            return None;
+        }
+        Some(self.fills.iter().map(|fill| fill.price * fill.quantity as f64).sum::<f64>() / filled as f64)
+    }
+
+    // в % от arrival_price, положительное -- исполнились хуже цены постановки
+    pub fn slippage_prc(&self) -> Option<f64> {
+        let avg_price = self.avg_price()?;
+        if self.arrival_price == 0.0 {
+            return None;
+        }
+        let slippage = match self.parent.direction {
+            OrderDirection::Sell => self.arrival_price - avg_price,
+            _ => avg_price - self.arrival_price,
+        };
+        Some(slippage / self.arrival_price * 100.0)
+    }
+}
+
+struct ParentExecution {
+    report: ExecutionReport,
+    start: i64,
+    // (секунд от start, лотов) для TWAP и VWAP
+    schedule: Vec<(i64, i64)>,
+    // номер следующей дочерней заявки, из него и parent_id складывается ключ ее order_request_id
+    next_slice: usize,
+    // дочерние заявки до финального статуса, в отчет попадают только их исполнения
+    children: OrderTracker<()>,
+}
+
+impl ParentExecution {
+    // выставлено, но еще не исполнено и не снято
+    fn lots_in_flight(&self) -> i64 {
+        self.children.orders().map(|order| order.lots_requested - order.lots_executed).sum()
+    }
+
+    // брокер не всегда сразу присылает цену, тогда берется лимитная цена или цена постановки
+    fn push_fill(&mut self, fill: OrderFill<()>) {
+        let price = fill.price.map(|price| price.to_f())
+            .or(self.report.parent.limit_price.as_ref().map(|price| price.to_f()))
+            .unwrap_or(self.report.arrival_price);
+        self.report.fills.push(ChildFill { order_id: fill.order_id, quantity: fill.lots, price, commission: fill.commission });
+    }
+}
+
+// делит quantity по весам методом наибольших остатков, сумма частей всегда равна quantity
+pub fn split_by_weights(quantity: i64, weights: &[f64]) -> Vec<i64> {
+    if weights.is_empty() {
+        return Vec::new();
+    }
+    let total: f64 = weights.iter().filter(|weight| **weight > 0.0).sum();
+    let weights: Vec<f64> = if total > 0.0 {
+        weights.iter().map(|weight| weight.max(0.0) / total).collect()
+    } else {
+        vec![1.0 / weights.len() as f64; weights.len()]
+    };
+    let exact: Vec<f64> = weights.iter().map(|weight| weight * quantity as f64).collect();
+    let mut parts: Vec<i64> = exact.iter().map(|part| part.floor() as i64).collect();
+    let mut by_remainder: Vec<usize> = (0..parts.len()).collect();
+    by_remainder.sort_by(|a, b| (exact[*b] - parts[*b] as f64).total_cmp(&(exact[*a] - parts[*a] as f64)));
+    let rest = quantity - parts.iter().sum::<i64>();
+    for index in by_remainder.into_iter().take(rest.max(0) as usize) {
+        parts[index] += 1;
+    }
+    parts
+}
+
+// части с нулем лотов пропускаются
+pub fn schedule(quantity: i64, duration_sec: i64, weights: &[f64]) -> Vec<(i64, i64)> {
+    let slice_sec = duration_sec / weights.len().max(1) as i64;
+    split_by_weights(quantity, weights).into_iter().enumerate()
+        .filter(|(_, lots)| *lots > 0)
+        .map(|(index, lots)| (index as i64 * slice_sec, lots))
+        .collect()
+}
+
+// объем исторических свечей по частям окна [start, start + duration_sec) того же времени суток
+pub fn volume_curve(candles: &[Candle], start: &Timestamp, duration_sec: i64, slices: usize) -> Vec<f64> {
+    let mut curve = vec![0.0; slices];
+    if slices == 0 || duration_sec <= 0 {
+        return curve;
+    }
+    for candle in candles {
+        let time = match &candle.time {
+            Some(time) => time.seconds,
+            None => continue,
+        };
+        let offset = (time - start.seconds).rem_euclid(DAY_SEC);
+        if offset < duration_sec {
+            curve[(offset * slices as i64 / duration_sec) as usize] += candle.volume as f64;
+        }
+    }
+    curve
+}
+
+// исполняет родительские заявки по алгоритму, дочерние заявки выставляются на update
+pub struct ExecutionService<O: OrderService, C: Clock> {
+    order_service: O,
+    clock: C,
+    candle_state: Arc<CandleState>,
+    executions: HashMap<String, ParentExecution>,
+    next_parent_id: u64,
+    order_intents: OrderIntents,
+}
+
+impl<O: OrderService, C: Clock> ExecutionService<O, C> {
+    pub fn new(order_service: O, clock: C, candle_state: Arc<CandleState>) -> Self {
+        Self { order_service, clock, candle_state, executions: HashMap::new(), next_parent_id: 0, order_intents: OrderIntents::default() }
+    }
+
+    pub async fn submit(&mut self, parent: ParentOrder, arrival_price: f64) -> String {
+        let now = self.clock.now();
+        let schedule = match &parent.algo {
+            ExecutionAlgo::Twap { duration_sec, slices } => schedule(parent.quantity, *duration_sec, &vec![1.0; (*slices).max(1)]),
+            ExecutionAlgo::Vwap { duration_sec, slices, lookback_days } => {
+                let range = SizedRange::new_1m(Timestamp { seconds: now.seconds - lookback_days * DAY_SEC, nanos: 0 }, now.clone());
+                let candles = self.candle_state.get_candles(&parent.instrument_id, range).await.unwrap_or_default();
+                // без истории объемов VWAP вырождается в TWAP
+                let curve = volume_curve(&candles, &now, *duration_sec, (*slices).max(1));
+                schedule(parent.quantity, *duration_sec, &curve)
+            }
+            ExecutionAlgo::Iceberg { .. } => Vec::new(),
+        };
+        let children = match &parent.algo {
+            ExecutionAlgo::Iceberg { orders_check_sec, .. } => OrderTracker::new(*orders_check_sec),
+            _ => OrderTracker::default(),
+        };
+        self.next_parent_id += 1;
+        let parent_id = format!("parent-{}", self.next_parent_id);
+        println!("Execution {} submitted: {:?}, schedule={:?}", parent_id, parent, schedule);
+        self.executions.insert(parent_id.clone(), ParentExecution {
+            report: ExecutionReport { parent, arrival_price, fills: Vec::new(), is_completed: false },
+            start: now.seconds,
+            schedule,
+            next_slice: 0,
+            children,
+        });
+        parent_id
+    }
+
+    pub fn report(&self, parent_id: &str) -> Option<&ExecutionReport> {
+        self.executions.get(parent_id).map(|execution| &execution.report)
+    }
+
+    // завершенная заявка больше не нужна владельцу, отчет отдается последний раз
+    pub fn remove(&mut self, parent_id: &str) -> Option<ExecutionReport> {
+        self.executions.remove(parent_id).map(|execution| execution.report)
+    }
+
+    // снимает выставленные части, исполненное до снятия попадает в отчет
+    pub async fn cancel(&mut self, parent_id: &str) {
+        let execution = match self.executions.get_mut(parent_id) {
+            Some(execution) => execution,
+            None => return,
+        };
+        let order_ids: Vec<String> = execution.children.orders().map(|order| order.order_id.clone()).collect();
+        for order_id in order_ids {
+            if let Err(e) = self.order_service.cancel_order(order_id.clone()).await {
+                eprintln!("Error while cancel child order={} of {}: {}", order_id, parent_id, e.message());
+            }
+            match self.order_service.get_order_state(order_id.clone()).await {
+                Ok(state) => {
+                    if let Some(fill) = execution.children.apply_state(&state.into_inner()) {
+                        execution.push_fill(fill);
+                    }
+                }
+                Err(e) => eprintln!("Error while getting state of child order={} of {}: {}", order_id, parent_id, e.message()),
+            }
+        }
+        execution.report.is_completed = true;
+    }
+
+    pub async fn update(&mut self) {
+        let now = self.clock.now();
+        let parent_ids: Vec<String> = self.executions.iter()
+            .filter(|(_, execution)| !execution.report.is_completed)
+            .map(|(parent_id, _)| parent_id.clone())
+            .collect();
+        for parent_id in parent_ids {
+            let mut execution = self.executions.remove(&parent_id).unwrap();
+            for fill in execution.children.poll(&mut self.order_service, now.seconds).await {
+                execution.push_fill(fill);
+            }
+            match execution.report.parent.algo.clone() {
+                ExecutionAlgo::Twap { .. } | ExecutionAlgo::Vwap { .. } => self.update_schedule(&parent_id, &mut execution, &now).await,
+                ExecutionAlgo::Iceberg { visible_lots, .. } => self.update_iceberg(&parent_id, &mut execution, visible_lots).await,
+            }
+            if execution.report.remaining_lots() <= 0 {
+                execution.report.is_completed = true;
+                println!("Execution {} completed: avg_price={:?}, slippage_prc={:?}", parent_id, execution.report.avg_price(), execution.report.slippage_prc());
+            }
+            self.executions.insert(parent_id, execution);
+        }
+    }
+
+    // часть выставляется, когда подошло ее время. Последняя ждет завершения предыдущих и добирает все, что они не исполнили.
+    // Ошибка не двигает next_slice, часть повторится на следующем update с тем же order_request_id
+    async fn update_schedule(&mut self, parent_id: &str, execution: &mut ParentExecution, now: &Timestamp) {
+        let last_slice = match execution.schedule.len().checked_sub(1) {
+            Some(last_slice) => last_slice,
+            None => return,
+        };
+        loop {
+            let (offset, lots) = execution.schedule[execution.next_slice.min(last_slice)];
+            if execution.start + offset > now.seconds {
+                return;
+            }
+            let is_last = execution.next_slice >= last_slice;
+            let lots = if is_last {
+                if execution.lots_in_flight() > 0 {
+                    return;
+                }
+                execution.report.remaining_lots()
+            } else {
+                lots.min(execution.report.remaining_lots() - execution.lots_in_flight())
+            };
+            if lots <= 0 {
+                if is_last {
+                    return;
+                }
+                execution.next_slice += 1;
+                continue;
+            }
+            if !self.post_child(parent_id, execution, lots, None, OrderType::Market).await || is_last {
+                return;
+            }
+        }
+    }
+
+    // следующая часть айсберга выставляется, когда предыдущая исполнена или снята
+    async fn update_iceberg(&mut self, parent_id: &str, execution: &mut ParentExecution, visible_lots: i64) {
+        if execution.children.orders().next().is_some() {
+            return;
+        }
+        let lots = visible_lots.max(1).min(execution.report.remaining_lots());
+        if lots <= 0 {
+            return;
+        }
+        let limit_price = execution.report.parent.limit_price.clone()
+            .unwrap_or(Quotation::from_f(execution.report.arrival_price));
+        self.post_child(parent_id, execution, lots, Some(limit_price), OrderType::Limit).await;
+    }
+
+    // id дочерней заявки держится, пока брокер не ответил или не отказал окончательно, поэтому повтор после таймаута не выставит ее второй раз
+    async fn post_child(&mut self, parent_id: &str, execution: &mut ParentExecution, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> bool {
+        let key = format!("{}_{}", parent_id, execution.next_slice);
+        let order_request_id = self.order_intents.request_id(&key);
+        let parent = &execution.report.parent;
+        let result: Result<Response<PostOrderResponse>, Status> = match parent.direction {
+            OrderDirection::Sell => self.order_service.order_sell(order_request_id, parent.figi.clone(), parent.instrument_id.clone(), quantity, price, order_type).await,
+            _ => self.order_service.order_buy(order_request_id, parent.figi.clone(), parent.instrument_id.clone(), quantity, price, order_type).await,
+        };
+        self.order_intents.resolve(&key, &result);
+        match result {
+            Ok(response) => {
+                if let Some(fill) = execution.children.track(&response.into_inner(), ()) {
+                    execution.push_fill(fill);
+                }
+                execution.next_slice += 1;
+                true
+            }
+            Err(e) => {
+                eprintln!("Error while posting child order of {}: {}", parent_id, e.message());
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost_types::Timestamp;
+    use tinkoff_invest_api::tcs::{Candle, OrderDirection};
+    use crate::service::execution_service::{schedule, split_by_weights, volume_curve, ChildFill, ExecutionReport, ParentOrder};
+    use crate::trading_cfg::ExecutionAlgo;
+
+    #[test]
+    fn test_split_and_schedule() {
+        assert_eq!(split_by_weights(10, &[1.0, 1.0, 1.0]), vec![4, 3, 3]);
+        assert_eq!(split_by_weights(10, &[0.0, 0.0]), vec![5, 5]);
+        assert_eq!(split_by_weights(7, &[1.0, 3.0, 0.0]), vec![2, 5, 0]);
+        // пустые части не выставляются
+        assert_eq!(schedule(2, 300, &[1.0, 1.0, 1.0]), vec![(0, 1), (100, 1)]);
+    }
+
+    #[test]
+    fn test_volume_curve() {
+        let candle = |seconds: i64, volume: i64| Candle { time: Some(Timestamp { seconds, nanos: 0 }), volume, ..Default::default() };
+        let start = Timestamp { seconds: 10 * 86400 + 600, nanos: 0 };
+        // вчера в то же время: 100 в первой половине окна, 300 во второй, вне окна не считается
+        let candles = [candle(9 * 86400 + 600, 100), candle(9 * 86400 + 660, 300), candle(9 * 86400 + 720, 1000)];
+        assert_eq!(volume_curve(&candles, &start, 120, 2), vec![100.0, 300.0]);
+    }
+
+    #[test]
+    fn test_report() {
+        let fill = |quantity: i64, price: f64| ChildFill { order_id: "1".to_string(), quantity, price, commission: 0.0 };
+        let mut report = ExecutionReport {
+            parent: ParentOrder {
+                figi: "".to_string(),
+                instrument_id: "".to_string(),
+                direction: OrderDirection::Buy,
+                quantity: 4,
+                limit_price: None,
+                algo: ExecutionAlgo::Twap { duration_sec: 60, slices: 2 },
+            },
+            arrival_price: 100.0,
+            fills: vec![fill(1, 101.0), fill(2, 102.5)],
+            is_completed: false,
+        };
+        assert_eq!(report.remaining_lots(), 1);
+        assert_eq!(report.avg_price(), Some(102.0));
+        assert_eq!(report.slippage_prc(), Some(2.0));
+        report.parent.direction = OrderDirection::Sell;
+        assert_eq!(report.slippage_prc(), Some(-2.0));
+    }
+}
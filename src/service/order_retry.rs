@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tinkoff_invest_api::tcs::{OrderState, PostOrderResponse};
+use tonic::Code;
+use crate::trading_cfg::OrderRetryCfg;
+
+const ATTEMPTS_LOG_CAPACITY: usize = 1000;
+
+// ошибка временная: заявку можно выставить еще раз с тем же order_id
+pub fn is_retryable(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted | Code::Internal | Code::Unknown)
+}
+
+// запрос мог дойти до брокера, прежде чем повторять, надо поискать заявку
+pub fn is_ambiguous(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded | Code::Internal | Code::Unknown)
+}
+
+// пауза перед попыткой attempt + 1, attempt считается с 1
+pub fn backoff(cfg: &OrderRetryCfg, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+    Duration::from_millis(cfg.initial_backoff_ms.saturating_mul(factor).min(cfg.max_backoff_ms))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttemptOutcome {
+    // брокер принял заявку и вернул свой order_id
+    Accepted(String),
+    Failed(Code, String),
+    // после неоднозначной ошибки заявка нашлась у брокера
+    FoundExisting(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderAttempt {
+    // order_id запроса, один на все попытки
+    pub order_request_id: String,
+    pub attempt: u32,
+    pub outcome: AttemptOutcome,
+}
+
+// последние попытки выставления заявок, старые вытесняются
+#[derive(Debug)]
+pub struct OrderAttemptLog {
+    attempts: VecDeque<OrderAttempt>,
+    capacity: usize,
+}
+
+impl Default for OrderAttemptLog {
+    fn default() -> Self {
+        Self::new(ATTEMPTS_LOG_CAPACITY)
+    }
+}
+
+impl OrderAttemptLog {
+    pub fn new(capacity: usize) -> Self {
+        Self { attempts: VecDeque::new(), capacity }
+    }
+
+    pub fn record(&mut self, attempt: OrderAttempt) {
+        match &attempt.outcome {
+            AttemptOutcome::Failed(code, message) => eprintln!("Order request={} attempt={} failed: {:?} {}", attempt.order_request_id, attempt.attempt, code, message),
+            outcome => println!("Order request={} attempt={}: {:?}", attempt.order_request_id, attempt.attempt, outcome),
+        }
+        if self.attempts.len() >= self.capacity {
+            self.attempts.pop_front();
+        }
+        self.attempts.push_back(attempt);
+    }
+
+    pub fn attempts(&self, order_request_id: &str) -> Vec<OrderAttempt> {
+        self.attempts.iter().filter(|attempt| attempt.order_request_id == order_request_id).cloned().collect()
+    }
+
+    // order_id брокера, если заявку по этому запросу он уже принял
+    pub fn order_id(&self, order_request_id: &str) -> Option<String> {
+        self.attempts(order_request_id).into_iter().rev().find_map(|attempt| match attempt.outcome {
+            AttemptOutcome::Accepted(order_id) | AttemptOutcome::FoundExisting(order_id) => Some(order_id),
+            AttemptOutcome::Failed(..) => None,
+        })
+    }
+}
+
+// найденная у брокера заявка отдается вызывающему как ответ на выставление
+pub fn order_state_to_response(state: OrderState) -> PostOrderResponse {
+    PostOrderResponse {
+        order_id: state.order_id,
+        execution_report_status: state.execution_report_status,
+        lots_requested: state.lots_requested,
+        lots_executed: state.lots_executed,
+        initial_order_price: state.initial_order_price,
+        // в ответе на выставление это цена одной бумаги, в состоянии заявки -- стоимость всех исполненных лотов
+        executed_order_price: state.average_position_price,
+        total_order_amount: state.total_order_amount,
+        initial_commission: state.initial_commission,
+        executed_commission: state.executed_commission,
+        aci_value: None,
+        figi: state.figi,
+        direction: state.direction,
+        initial_security_price: state.initial_security_price,
+        order_type: state.order_type,
+        message: "found by order_request_id".to_string(),
+        initial_order_price_pt: None,
+        instrument_uid: state.instrument_uid,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tonic::Code;
+    use crate::service::order_retry::{backoff, is_ambiguous, is_retryable, AttemptOutcome, OrderAttempt, OrderAttemptLog};
+    use crate::trading_cfg::OrderRetryCfg;
+
+    #[test]
+    fn test_backoff() {
+        let cfg = OrderRetryCfg { max_attempts: 5, initial_backoff_ms: 200, max_backoff_ms: 1000 };
+        assert_eq!(backoff(&cfg, 1), Duration::from_millis(200));
+        assert_eq!(backoff(&cfg, 3), Duration::from_millis(800));
+        assert_eq!(backoff(&cfg, 4), Duration::from_millis(1000));
+        assert_eq!(backoff(&cfg, 100), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_codes() {
+        assert!(is_retryable(Code::Unavailable) && is_ambiguous(Code::Unavailable));
+        // лимит запросов: заявка точно не выставлена
+        assert!(is_retryable(Code::ResourceExhausted) && !is_ambiguous(Code::ResourceExhausted));
+        assert!(!is_retryable(Code::InvalidArgument));
+        assert!(!is_retryable(Code::FailedPrecondition));
+    }
+
+    #[test]
+    fn test_attempts_log() {
+        let attempt =
+            |order_request_id: &str, attempt: u32| OrderAttempt { order_request_id: order_request_id.to_string(), attempt, outcome: AttemptOutcome::Failed(Code::Unavailable, "".to_string()) };
+        let mut log = OrderAttemptLog::new(2);
+        log.record(attempt("1", 1));
+        log.record(attempt("1", 2));
+        log.record(attempt("2", 1));
+        assert_eq!(log.attempts("1").len(), 1);
+        assert_eq!(log.attempts("1")[0].attempt, 2);
+        assert_eq!(log.attempts("2").len(), 1);
+    }
+
+    #[test]
+    fn test_order_id() {
+        let mut log = OrderAttemptLog::default();
+        log.record(OrderAttempt { order_request_id: "1".to_string(), attempt: 1, outcome: AttemptOutcome::Failed(Code::Unavailable, "".to_string()) });
+        assert_eq!(log.order_id("1"), None);
+        log.record(OrderAttempt { order_request_id: "1".to_string(), attempt: 1, outcome: AttemptOutcome::FoundExisting("order".to_string()) });
+        assert_eq!(log.order_id("1"), Some("order".to_string()));
+        assert_eq!(log.order_id("2"), None);
+    }
+}
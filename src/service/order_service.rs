@@ -1,48 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use tinkoff_invest_api::DefaultInterceptor;
-use tinkoff_invest_api::tcs::{Account, GetOrdersRequest, LastPrice, MoneyValue, OrderDirection, OrderState, OrderType, PostOrderRequest, PostOrderResponse, Quotation, SandboxPayInRequest};
+use tinkoff_invest_api::tcs::{Account, CancelOrderRequest, CancelOrderResponse, CancelStopOrderResponse, GetOrderStateRequest, GetOrdersRequest, LastPrice, MoneyValue, OrderDirection, OrderExecutionReportStatus, OrderState, OrderType, PostOrderRequest, PostOrderResponse, PostStopOrderResponse, Quotation, ReplaceOrderRequest, SandboxPayInRequest, StopOrder, StopOrderDirection, StopOrderType};
 use tinkoff_invest_api::tcs::orders_service_client::OrdersServiceClient;
 use tinkoff_invest_api::tcs::sandbox_service_client::SandboxServiceClient;
 use tonic::codegen::InterceptedService;
 use tonic::transport::Channel;
-use uuid::Uuid;
 use duplicate::duplicate_item;
+use tokio::sync::Mutex;
+use tokio::time;
 use tonic::{Code, Response, Status};
+use crate::service::order_retry::{backoff, is_ambiguous, is_retryable, order_state_to_response, AttemptOutcome, OrderAttempt, OrderAttemptLog};
+use crate::state::last_price_state::{LastPriceState, LastPriceStateStatistic};
+use crate::trading_cfg::OrderRetryCfg;
+use crate::utils::clock::Clock;
 use crate::utils::quotation::QuotationExtension;
 
+// один сервис на несколько стратегий, заявки отправляются по очереди
+pub type SharedOrderService<O> = Arc<Mutex<O>>;
+
 pub trait OrderService {
-    async fn order_buy(&mut self, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status>;
-    async fn order_sell(&mut self, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status>;
+    // order_request_id задает вызывающий: повтор с тем же id брокер не исполнит второй раз
+    async fn order_buy(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status>;
+    async fn order_sell(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status>;
+    // только активные заявки
     async fn get_orders(&mut self) -> Vec<OrderState>;
+    async fn cancel_order(&mut self, order_id: String) -> Result<Response<CancelOrderResponse>, Status>;
+    // выставляет новую заявку вместо лимитной order_id, у новой заявки свой order_id.
+    // idempotency_key задает вызывающий: повтор с тем же ключом не заменит заявку второй раз
+    async fn replace_order(&mut self, order_id: String, idempotency_key: String, quantity: i64, price: Option<Quotation>) -> Result<Response<PostOrderResponse>, Status>;
+    // в том числе исполненные и отмененные заявки
+    async fn get_order_state(&mut self, order_id: String) -> Result<Response<OrderState>, Status>;
+    // заявка живет на стороне брокера до отмены
+    async fn post_stop_order(&mut self, request: StopOrderRequest) -> Result<Response<PostStopOrderResponse>, Status>;
+    async fn cancel_stop_order(&mut self, stop_order_id: String) -> Result<Response<CancelStopOrderResponse>, Status>;
+    // только активные стоп-заявки
+    async fn get_stop_orders(&mut self) -> Vec<StopOrder>;
+}
+
+#[derive(Debug, Clone)]
+pub struct StopOrderRequest {
+    pub instrument_id: String,
+    // в лотах
+    pub quantity: i64,
+    // цена лимитной заявки, нужна только для StopLimit
+    pub price: Option<Quotation>,
+    pub stop_price: Quotation,
+    pub direction: StopOrderDirection,
+    pub stop_order_type: StopOrderType,
+}
+
+// стоп-заявки, которые исполняет сам сервис: в песочнице нет StopOrders API, в исторических данных нет брокера
+#[derive(Debug, Default)]
+pub struct StopOrderBook {
+    orders: Vec<StopOrder>,
+    next_id: u64,
+}
+
+impl StopOrderBook {
+    pub fn post(&mut self, request: StopOrderRequest) -> Result<String, Box<Status>> {
+        if request.quantity <= 0 {
+            return Err(Box::new(Status::invalid_argument(format!("Incorrect quantity={} for stop order", request.quantity))));
+        }
+        if request.stop_order_type == StopOrderType::StopLimit && request.price.is_none() {
+            return Err(Box::new(Status::invalid_argument("Stop-limit order requires price")));
+        }
+        let to_money = |price: Quotation| MoneyValue { currency: "".to_string(), units: price.units, nano: price.nano };
+        self.next_id += 1;
+        let stop_order_id = format!("stop-{}", self.next_id);
+        self.orders.push(StopOrder {
+            stop_order_id: stop_order_id.clone(),
+            lots_requested: request.quantity,
+            direction: request.direction as i32,
+            order_type: request.stop_order_type as i32,
+            price: request.price.map(to_money),
+            stop_price: Some(to_money(request.stop_price)),
+            instrument_uid: request.instrument_id,
+            ..Default::default()
+        });
+        Ok(stop_order_id)
+    }
+
+    pub fn cancel(&mut self, stop_order_id: &str) -> Result<(), Box<Status>> {
+        match self.orders.iter().position(|order| order.stop_order_id == stop_order_id) {
+            Some(index) => {
+                self.orders.remove(index);
+                Ok(())
+            }
+            None => Err(Box::new(Status::not_found(format!("Stop order={} not found", stop_order_id)))),
+        }
+    }
+
+    pub fn orders(&self) -> Vec<StopOrder> {
+        self.orders.clone()
+    }
+
+    pub fn instrument_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.orders.iter().map(|order| order.instrument_uid.clone()).collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    // сработавшие по цене заявки убираются из книги, исполнять их должен вызывающий
+    pub fn triggered(&mut self, instrument_id: &str, price: &Quotation) -> Vec<StopOrder> {
+        let price = price.to_f();
+        let (triggered, active) = self.orders.drain(..).partition(|order| {
+            let stop_price = match &order.stop_price {
+                Some(stop_price) => Quotation::from_money(stop_price).to_f(),
+                None => return false,
+            };
+            let is_buy = order.direction == StopOrderDirection::Buy as i32;
+            order.instrument_uid == instrument_id && if order.order_type == StopOrderType::TakeProfit as i32 {
+                if is_buy { price <= stop_price } else { price >= stop_price }
+            } else {
+                if is_buy { price >= stop_price } else { price <= stop_price }
+            }
+        });
+        self.orders = active;
+        triggered
+    }
+}
+
+fn stop_order_direction(order: &StopOrder) -> OrderDirection {
+    if order.direction == StopOrderDirection::Buy as i32 { OrderDirection::Buy } else { OrderDirection::Sell }
 }
 
 pub struct OrderServiceImpl {
     account: Account,
     client: OrdersServiceClient<InterceptedService<Channel, DefaultInterceptor>>,
+    retry_cfg: OrderRetryCfg,
+    attempts: OrderAttemptLog,
 }
 
 pub struct OrderServiceSandboxImpl {
     account: Account,
     client: SandboxServiceClient<InterceptedService<Channel, DefaultInterceptor>>,
+    // в песочнице нет StopOrders API, стоп-заявки исполняются trigger_stop_orders
+    stop_orders: StopOrderBook,
+    retry_cfg: OrderRetryCfg,
+    attempts: OrderAttemptLog,
 }
 
-pub struct OrderServiceHistBoxImpl {
+pub struct OrderServiceHistBoxImpl<C: Clock> {
+    // время исторического прогона, по нему считается плата за заем
+    clock: C,
     commission: u8,
     // percentage 0-100
     pub balance: Quotation,
     // fixme map<instrument, Quotation> for multi instruments
     trash_hold: u64,
     pub current_price: Quotation,
+    // выставленные лимитные заявки, исполняются в set_current_price
+    orders: Vec<OrderState>,
+    // исполненные и отмененные заявки для get_order_state
+    closed_orders: Vec<OrderState>,
+    stop_orders: StopOrderBook,
+    next_order_id: u64,
+    // позиции в штуках, отрицательные -- шорт
+    positions: HashMap<String, i64>,
+    // плата за заем бумаг в % в день от стоимости шорта
+    borrow_fee_prc: f64,
+    // когда последний раз списывали плату за заем по инструменту
+    borrow_fee_time: HashMap<String, i64>,
 }
 
 impl OrderServiceImpl {
-    pub fn new(account: Account, client: OrdersServiceClient<InterceptedService<Channel, DefaultInterceptor>>) -> Self {
-        Self { account, client }
+    pub fn new(account: Account, client: OrdersServiceClient<InterceptedService<Channel, DefaultInterceptor>>, retry_cfg: OrderRetryCfg) -> Self {
+        Self { account, client, retry_cfg, attempts: OrderAttemptLog::default() }
     }
 }
 
 impl OrderServiceSandboxImpl {
-    pub fn new(account: Account, client: SandboxServiceClient<InterceptedService<Channel, DefaultInterceptor>>) -> Self {
-        Self { account, client }
+    pub fn new(account: Account, client: SandboxServiceClient<InterceptedService<Channel, DefaultInterceptor>>, retry_cfg: OrderRetryCfg) -> Self {
+        Self { account, client, stop_orders: StopOrderBook::default(), retry_cfg, attempts: OrderAttemptLog::default() }
+    }
+
+    async fn stop_post(&mut self, request: StopOrderRequest) -> Result<Response<PostStopOrderResponse>, Status> {
+        let stop_order_id = self.stop_orders.post(request).map_err(|e| *e)?;
+        Ok(Response::new(PostStopOrderResponse { stop_order_id }))
+    }
+
+    async fn stop_cancel(&mut self, stop_order_id: &str) -> Result<Response<CancelStopOrderResponse>, Status> {
+        self.stop_orders.cancel(stop_order_id).map_err(|e| *e)?;
+        Ok(Response::new(CancelStopOrderResponse { time: None }))
+    }
+
+    // вызывается на каждую новую цену: сработавшие стоп-заявки уходят в песочницу рыночными, stop-limit -- лимитными
+    pub async fn trigger_stop_orders(&mut self, last_price_state: &LastPriceState) {
+        for instrument_id in self.stop_orders.instrument_ids() {
+            let price = match last_price_state.get_last_price(&instrument_id).await {
+                Some(price) => price,
+                None => continue,
+            };
+            for order in self.stop_orders.triggered(&instrument_id, &price) {
+                let (price, order_type) = match order.order_type == StopOrderType::StopLimit as i32 {
+                    true => (order.price.as_ref().map(Quotation::from_money), OrderType::Limit),
+                    false => (None, OrderType::Market),
+                };
+                // id стоп-заявки -- ключ идемпотентности, повторное срабатывание не выставит вторую заявку
+                let response = match stop_order_direction(&order) {
+                    OrderDirection::Buy => self.order_buy(order.stop_order_id.clone(), order.figi.clone(), order.instrument_uid.clone(), order.lots_requested, price, order_type).await,
+                    _ => self.order_sell(order.stop_order_id.clone(), order.figi.clone(), order.instrument_uid.clone(), order.lots_requested, price, order_type).await,
+                };
+                match response {
+                    Ok(_) => println!("Stop order={} triggered at price={:?}", order.stop_order_id, order.stop_price),
+                    Err(e) => eprintln!("Error while executing stop order={}: {}", order.stop_order_id, e.message()),
+                }
+            }
+        }
     }
     async fn pay_in(&mut self, amount: MoneyValue) {
         let _ = self.client.sandbox_pay_in(SandboxPayInRequest {
@@ -52,39 +218,366 @@ impl OrderServiceSandboxImpl {
     }
 }
 
-impl OrderServiceHistBoxImpl {
-    pub fn new(balance: Quotation, commission: u8, trash_hold: u64) -> Self { Self { commission, balance, trash_hold, current_price: Quotation { units: 0, nano: 0 } } }
+impl<C: Clock> OrderServiceHistBoxImpl<C> {
+    pub fn new(balance: Quotation, commission: u8, trash_hold: u64, borrow_fee_prc: f64, clock: C) -> Self {
+        Self {
+            clock,
+            commission,
+            balance,
+            trash_hold,
+            current_price: Quotation { units: 0, nano: 0 },
+            orders: Vec::new(),
+            closed_orders: Vec::new(),
+            stop_orders: StopOrderBook::default(),
+            next_order_id: 0,
+            positions: HashMap::new(),
+            borrow_fee_prc,
+            borrow_fee_time: HashMap::new(),
+        }
+    }
     pub fn get_balance(&self) -> Quotation { self.balance.clone() }
+
+    // списывает плату за заем с прошлого списания по текущей цене
+    fn charge_borrow_fee(&mut self, instrument_id: &String) {
+        let now = self.clock.now().seconds;
+        let position = self.positions.get(instrument_id).cloned().unwrap_or(0);
+        if position < 0 {
+            let since = self.borrow_fee_time.get(instrument_id).cloned().unwrap_or(now);
+            let fee = -position as f64 * self.current_price.to_f() * self.borrow_fee_prc / 100.0 * (now - since) as f64 / 86400.0;
+            self.balance = (self.balance.wr() - Quotation::from_f(fee).wr()).uwr();
+            while self.balance.nano < 0 {
+                self.balance.units -= 1;
+                self.balance.nano += 1_000_000_000;
+            }
+        }
+        self.borrow_fee_time.insert(instrument_id.clone(), now);
+    }
+
+    // quantity со знаком: покупка положительная, продажа отрицательная
+    fn update_position(&mut self, instrument_id: &String, quantity: i64) {
+        self.charge_borrow_fee(instrument_id);
+        let position = self.positions.entry(instrument_id.clone()).or_insert(0);
+        *position += quantity;
+        if *position >= 0 {
+            self.borrow_fee_time.remove(instrument_id);
+        }
+    }
+
+    // новая цена исполняет лимитные заявки, до которых дошла: покупки не выше цены, продажи не ниже
+    pub fn set_current_price(&mut self, price: Quotation) {
+        self.current_price = price.clone();
+        let (filled, active): (Vec<OrderState>, Vec<OrderState>) = self.orders.drain(..).partition(|order| {
+            let limit = Quotation::from_money(order.initial_security_price.as_ref().unwrap());
+            if order.direction == OrderDirection::Buy as i32 {
+                price.wr() <= limit.wr()
+            } else {
+                price.wr() >= limit.wr()
+            }
+        });
+        self.orders = active;
+        for order in filled {
+            let limit = Quotation::from_money(order.initial_security_price.as_ref().unwrap());
+            if order.direction == OrderDirection::Buy as i32 {
+                self.balance = (self.balance.wr() - limit.wr() * order.lots_requested).uwr();
+                self.update_position(&order.instrument_uid, order.lots_requested);
+            } else {
+                self.balance = (self.balance.wr() + limit.wr() * order.lots_requested).uwr();
+                self.update_position(&order.instrument_uid, -order.lots_requested);
+            }
+            while self.balance.nano < 0 {
+                self.balance.units -= 1;
+                self.balance.nano += 1_000_000_000;
+            }
+            println!(" ======================== Limit order={} filled, new balance={},{} ========================", order.order_id, self.balance.units, self.balance.nano);
+            // как у брокера: executed_order_price -- стоимость исполненных лотов, average_position_price -- цена одной бумаги
+            let total = (limit.wr() * order.lots_requested).uwr();
+            self.closed_orders.push(OrderState {
+                execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusFill as i32,
+                lots_executed: order.lots_requested,
+                executed_order_price: Some(MoneyValue { currency: "".to_string(), units: total.units, nano: total.nano }),
+                average_position_price: order.initial_security_price.clone(),
+                ..order
+            });
+        }
+        self.trigger_stop_orders();
+    }
+
+    // сработавшие стоп-заявки исполняются по текущей цене, stop-limit выставляется лимитной
+    fn trigger_stop_orders(&mut self) {
+        let price = self.current_price.clone();
+        for instrument_id in self.stop_orders.instrument_ids() {
+            for order in self.stop_orders.triggered(&instrument_id, &price) {
+                let direction = stop_order_direction(&order);
+                let response = match (order.order_type == StopOrderType::StopLimit as i32, order.price.as_ref()) {
+                    (true, Some(limit)) => self.post_limit_order(order.stop_order_id.clone(), order.figi.clone(), order.instrument_uid.clone(), order.lots_requested, Quotation::from_money(limit), direction),
+                    _ => self.market_order(order.stop_order_id.clone(), order.figi.clone(), order.instrument_uid.clone(), order.lots_requested, direction),
+                };
+                match response {
+                    Ok(_) => println!(" ======================== Stop order={} triggered at price={:?} ========================", order.stop_order_id, price),
+                    Err(e) => eprintln!("Error while executing stop order={}: {}", order.stop_order_id, e.message()),
+                }
+            }
+        }
+    }
+
+    fn next_order_id(&mut self) -> String {
+        self.next_order_id += 1;
+        format!("hist-{}", self.next_order_id)
+    }
+
+    // рыночная заявка и заявка по лучшей цене исполняются сразу по текущей цене
+    fn market_order(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, direction: OrderDirection) -> Result<Response<PostOrderResponse>, Box<Status>> {
+        if quantity <= 0 {
+            return Err(Box::new(Status::new(
+                Code::Cancelled,
+                format!("Incorrect quantity while trading instrument_id={:?}, quantity={:?}", instrument_id, quantity),
+            )));
+        }
+        let price = self.current_price.clone();
+        if direction == OrderDirection::Buy {
+            if self.balance.units < self.trash_hold as i64 {
+                panic!("Strategy lost: trying buy when balance:{:?} < trash_hold:{:?}", self.balance.units, self.trash_hold)
+            }
+            if self.balance.units <= 0 || self.balance.nano < 0 {
+                return Err(Box::new(Status::new(
+                    Code::Cancelled,
+                    format!("Not enough money balance={:?} while buying instrument_id={:?}, quantity={:?}, price={:?} ", self.balance, instrument_id, quantity, price),
+                )));
+            }
+            if self.balance.units - price.clone().units * quantity < 0 {
+                panic!("Not enough money for buy: balance={:#?}, price={:#?}", self.balance, price)
+            }
+            self.update_position(&instrument_id, quantity);
+            self.balance = (self.balance.wr() - price.clone().wr() * quantity).uwr();
+        } else {
+            // шорт должен быть полностью обеспечен деньгами
+            let position = self.positions.get(&instrument_id).cloned().unwrap_or(0) - quantity;
+            if position < 0 && self.balance.to_f() < -position as f64 * price.to_f() {
+                return Err(Box::new(Status::new(
+                    Code::FailedPrecondition,
+                    format!("Not enough margin balance={:?} for short instrument_id={:?}, position={:?}, price={:?}", self.balance, instrument_id, position, price),
+                )));
+            }
+            self.update_position(&instrument_id, -quantity);
+            self.balance = (self.balance.wr() + price.clone().wr() * quantity).uwr();
+        }
+        let commission = (self.commission as f64 / 100.0) as i64;
+        self.balance = (self.balance.wr() - price.clone().wr() * quantity * commission).uwr();
+        while self.balance.nano < 0 {
+            self.balance.units -= 1;
+            self.balance.nano += 1_000_000_000;
+        }
+        let side = if direction == OrderDirection::Buy { "buy" } else { "sell" };
+        println!(" ======================== New balance after {}={},{} ========================", side, self.balance.units, self.balance.nano);
+
+        let order_id = self.next_order_id();
+        let price_money = MoneyValue { currency: "".to_string(), units: price.units, nano: price.nano };
+        let total = (price.wr() * quantity).uwr();
+        let commission_money = MoneyValue { currency: "".to_string(), units: self.commission as i64, nano: 0 };
+        // как у брокера: в состоянии заявки executed_order_price -- стоимость исполненных лотов, в ответе -- цена одной бумаги
+        self.closed_orders.push(OrderState {
+            order_id: order_id.clone(),
+            execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusFill as i32,
+            lots_requested: quantity,
+            lots_executed: quantity,
+            executed_order_price: Some(MoneyValue { currency: "".to_string(), units: total.units, nano: total.nano }),
+            average_position_price: Some(price_money.clone()),
+            executed_commission: Some(commission_money.clone()),
+            figi: figi.clone(),
+            direction: direction as i32,
+            order_type: OrderType::Market as i32,
+            instrument_uid: instrument_id.clone(),
+            order_request_id,
+            ..Default::default()
+        });
+        Ok(Response::new(PostOrderResponse {
+            order_id,
+            execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusFill as i32,
+            lots_requested: quantity,
+            lots_executed: quantity,
+            initial_order_price: None,
+            executed_order_price: Some(price_money),
+            total_order_amount: None,
+            initial_commission: None,
+            executed_commission: Some(commission_money),
+            aci_value: None,
+            figi,
+            direction: direction as i32,
+            initial_security_price: None,
+            order_type: OrderType::Market as i32,
+            message: format!("hist training {}", side),
+            initial_order_price_pt: None,
+            instrument_uid: instrument_id,
+        }))
+    }
+
+    // цена лимитной заявки, рыночная и по лучшей цене исполняются без нее
+    fn limit_price(order_type: OrderType, price: Option<Quotation>) -> Result<Option<Quotation>, Box<Status>> {
+        match (order_type, price) {
+            (OrderType::Limit, Some(price)) => Ok(Some(price)),
+            (OrderType::Limit, None) => Err(Box::new(Status::invalid_argument("Limit order requires price"))),
+            (OrderType::Market | OrderType::Bestprice, _) => Ok(None),
+            (OrderType::Unspecified, _) => Err(Box::new(Status::invalid_argument("Order type is not specified"))),
+        }
+    }
+
+    // повтор с тем же order_request_id возвращает уже выставленную заявку, как у брокера. Без limit_price заявка рыночная
+    fn post_order(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, limit_price: Option<Quotation>, direction: OrderDirection) -> Result<Response<PostOrderResponse>, Box<Status>> {
+        if let Some(order) = self.orders.iter().chain(self.closed_orders.iter()).find(|order| order.order_request_id == order_request_id) {
+            return Ok(Response::new(order_state_to_response(order.clone())));
+        }
+        match limit_price {
+            Some(price) => self.post_limit_order(order_request_id, figi, instrument_id, quantity, price, direction),
+            None => self.market_order(order_request_id, figi, instrument_id, quantity, direction),
+        }
+    }
+
+    fn post_limit_order(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Quotation, direction: OrderDirection) -> Result<Response<PostOrderResponse>, Box<Status>> {
+        if quantity <= 0 {
+            return Err(Box::new(Status::new(Code::InvalidArgument, format!("Incorrect quantity={} for limit order", quantity))));
+        }
+        let order_id = self.next_order_id();
+        let price_money = MoneyValue { currency: "".to_string(), units: price.units, nano: price.nano };
+        self.orders.push(OrderState {
+            order_id: order_id.clone(),
+            execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusNew as i32,
+            lots_requested: quantity,
+            lots_executed: 0,
+            initial_order_price: None,
+            executed_order_price: None,
+            total_order_amount: None,
+            average_position_price: None,
+            initial_commission: None,
+            executed_commission: None,
+            figi: figi.clone(),
+            direction: direction as i32,
+            initial_security_price: Some(price_money.clone()),
+            stages: Vec::new(),
+            service_commission: None,
+            currency: "".to_string(),
+            order_type: OrderType::Limit as i32,
+            order_date: None,
+            instrument_uid: instrument_id.clone(),
+            order_request_id,
+        });
+        Ok(Response::new(PostOrderResponse {
+            order_id,
+            execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusNew as i32,
+            lots_requested: quantity,
+            lots_executed: 0,
+            initial_order_price: None,
+            executed_order_price: None,
+            total_order_amount: None,
+            initial_commission: None,
+            executed_commission: None,
+            aci_value: None,
+            figi,
+            direction: direction as i32,
+            initial_security_price: Some(price_money),
+            order_type: OrderType::Limit as i32,
+            message: "hist training limit order".to_string(),
+            initial_order_price_pt: None,
+            instrument_uid: instrument_id,
+        }))
+    }
 }
 
 #[duplicate_item(
-service_impl                 _post_order             _get_orders;
-[ OrderServiceImpl ]         [ post_order ]          [ get_orders ];
-[ OrderServiceSandboxImpl ]  [ post_sandbox_order ]  [ get_sandbox_orders ];
+service_impl                 _post_order             _get_orders             _get_order_state;
+[ OrderServiceImpl ]         [ post_order ]          [ get_orders ]          [ get_order_state ];
+[ OrderServiceSandboxImpl ]  [ post_sandbox_order ]  [ get_sandbox_orders ]  [ get_sandbox_order_state ];
+)]
+impl service_impl {
+    // все попытки идут с одним order_id запроса, повтор с ним брокер не исполнит второй раз.
+    // Заявку, которую брокер по этому id уже принял, не выставляем снова, а отдаем ее состояние
+    async fn post_order_with_retry(&mut self, request: PostOrderRequest) -> Result<Response<PostOrderResponse>, Status> {
+        if let Some(state) = self.logged_order(&request.order_id).await {
+            return Ok(Response::new(order_state_to_response(state)));
+        }
+        let mut attempt = 1;
+        loop {
+            let error = match self.client._post_order(request.clone()).await {
+                Ok(response) => {
+                    self.record_attempt(&request.order_id, attempt, AttemptOutcome::Accepted(response.get_ref().order_id.clone()));
+                    return Ok(response);
+                }
+                Err(e) => e,
+            };
+            self.record_attempt(&request.order_id, attempt, AttemptOutcome::Failed(error.code(), error.message().to_string()));
+            // ответ потерян, а заявка могла быть выставлена
+            if is_ambiguous(error.code()) {
+                if let Some(state) = self.find_order(&request.order_id).await {
+                    self.record_attempt(&request.order_id, attempt, AttemptOutcome::FoundExisting(state.order_id.clone()));
+                    return Ok(Response::new(order_state_to_response(state)));
+                }
+            }
+            if !is_retryable(error.code()) || attempt >= self.retry_cfg.max_attempts {
+                return Err(error);
+            }
+            time::sleep(backoff(&self.retry_cfg, attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    // заявка, которую брокер уже принял по этому order_request_id, в том числе исполненная
+    async fn logged_order(&mut self, order_request_id: &str) -> Option<OrderState> {
+        let order_id = self.attempts.order_id(order_request_id)?;
+        match self.client._get_order_state(GetOrderStateRequest { account_id: self.account.id.clone(), order_id: order_id.clone() }).await {
+            Ok(response) => Some(response.into_inner()),
+            Err(e) => {
+                eprintln!("Error while getting state of order={} for request={}: {}", order_id, order_request_id, e.message());
+                None
+            }
+        }
+    }
+
+    // сначала по журналу попыток, затем среди активных заявок. Исполненную заявку без ответа брокера не найти -- ее защищает повтор с тем же order_id
+    async fn find_order(&mut self, order_request_id: &str) -> Option<OrderState> {
+        if let Some(state) = self.logged_order(order_request_id).await {
+            return Some(state);
+        }
+        match self.client._get_orders(GetOrdersRequest { account_id: self.account.id.clone() }).await {
+            Ok(response) => response.into_inner().orders.into_iter().find(|order| order.order_request_id == order_request_id),
+            Err(e) => {
+                eprintln!("Error while looking for order request={}: {}", order_request_id, e.message());
+                None
+            }
+        }
+    }
+
+    fn record_attempt(&mut self, order_request_id: &str, attempt: u32, outcome: AttemptOutcome) {
+        self.attempts.record(OrderAttempt { order_request_id: order_request_id.to_string(), attempt, outcome });
+    }
+}
+
+#[duplicate_item(
+service_impl                 _post_order             _get_orders             _cancel_order             _replace_order             _get_order_state             _post_stop                                                                                                               _cancel_stop                                                                                                    _stop_list;
+[ OrderServiceImpl ]         [ post_order ]          [ get_orders ]          [ cancel_order ]          [ replace_order ]          [ get_order_state ]          [ Err(Status::unimplemented(format!("No StopOrders client, stop order for {} is not posted", request.instrument_id))) ]  [ Err(Status::unimplemented(format!("No StopOrders client, stop order={} is not cancelled", stop_order_id))) ]  [ Vec::new() ];
+[ OrderServiceSandboxImpl ]  [ post_sandbox_order ]  [ get_sandbox_orders ]  [ cancel_sandbox_order ]  [ replace_sandbox_order ]  [ get_sandbox_order_state ]  [ self.stop_post(request).await ]                                                                                        [ self.stop_cancel(&stop_order_id).await ]                                                                      [ self.stop_orders.orders() ];
 )]
 impl OrderService for service_impl {
-    async fn order_buy(&mut self, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
-        self.client._post_order(PostOrderRequest {
+    async fn order_buy(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
+        self.post_order_with_retry(PostOrderRequest {
             figi: figi,
             quantity: quantity,
             price: price,
             direction: OrderDirection::Buy as i32,
             account_id: self.account.id.clone(),
             order_type: order_type as i32,
-            order_id: Uuid::new_v4().to_string(),
+            order_id: order_request_id,
             instrument_id: instrument_id,
         }).await
     }
 
-    async fn order_sell(&mut self, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
-        self.client._post_order(PostOrderRequest {
+    async fn order_sell(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
+        self.post_order_with_retry(PostOrderRequest {
             figi: figi,
             quantity: quantity,
             price: price,
             direction: OrderDirection::Sell as i32,
             account_id: self.account.id.clone(),
             order_type: order_type as i32,
-            order_id: Uuid::new_v4().to_string(),
+            order_id: order_request_id,
             instrument_id: instrument_id,
         }).await
     }
@@ -94,121 +587,189 @@ impl OrderService for service_impl {
             account_id: self.account.id.clone()
         }).await.unwrap().into_inner().orders
     }
+
+    async fn cancel_order(&mut self, order_id: String) -> Result<Response<CancelOrderResponse>, Status> {
+        self.client._cancel_order(CancelOrderRequest {
+            account_id: self.account.id.clone(),
+            order_id,
+        }).await
+    }
+
+    async fn replace_order(&mut self, order_id: String, idempotency_key: String, quantity: i64, price: Option<Quotation>) -> Result<Response<PostOrderResponse>, Status> {
+        self.client._replace_order(ReplaceOrderRequest {
+            account_id: self.account.id.clone(),
+            order_id,
+            idempotency_key,
+            quantity,
+            price,
+            price_type: 0,
+        }).await
+    }
+
+    async fn get_order_state(&mut self, order_id: String) -> Result<Response<OrderState>, Status> {
+        self.client._get_order_state(GetOrderStateRequest {
+            account_id: self.account.id.clone(),
+            order_id,
+        }).await
+    }
+
+    // в tinkoff-invest-api нет клиента StopOrders для прода с нашим токеном, стоп-заявки ведет только песочница
+    async fn post_stop_order(&mut self, request: StopOrderRequest) -> Result<Response<PostStopOrderResponse>, Status> {
+        _post_stop
+    }
+
+    async fn cancel_stop_order(&mut self, stop_order_id: String) -> Result<Response<CancelStopOrderResponse>, Status> {
+        _cancel_stop
+    }
+
+    async fn get_stop_orders(&mut self) -> Vec<StopOrder> {
+        _stop_list
+    }
 }
 
-impl OrderService for OrderServiceHistBoxImpl {
-    async fn order_buy(&mut self, figi: String, instrument_id: String, quantity: i64, _price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
-        if self.balance.units < self.trash_hold as i64 {
-            panic!("Strategy lost: trying buy when balance:{:?} < trash_hold:{:?}", self.balance.units, self.trash_hold)
-        }
-        if self.balance.units > 0 && self.balance.nano >= 0 && quantity > 0 {
-            let price = if _price.is_some() || order_type != OrderType::Market {
-                panic!("Only market order support in hist order service")
-            } else {
-                self.current_price.clone()
-            };
+impl<C: Clock> OrderService for OrderServiceHistBoxImpl<C> {
+    async fn order_buy(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
+        let limit_price = Self::limit_price(order_type, price).map_err(|e| *e)?;
+        self.post_order(order_request_id, figi, instrument_id, quantity, limit_price, OrderDirection::Buy).map_err(|e| *e)
+    }
 
-            if self.balance.units - price.clone().units * quantity < 0 {
-                panic!("Not enough money for buy: balance={:#?}, price={:#?}", self.balance, price)
-            }
+    async fn order_sell(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
+        let limit_price = Self::limit_price(order_type, price).map_err(|e| *e)?;
+        self.post_order(order_request_id, figi, instrument_id, quantity, limit_price, OrderDirection::Sell).map_err(|e| *e)
+    }
 
-            self.balance = (self.balance.wr() - price.clone().wr() * quantity).uwr();
-            let commission = (self.commission as f64 / 100.0) as i64;
-            self.balance = (self.balance.wr() - price.clone().wr() * quantity * commission).uwr();
+    async fn get_orders(&mut self) -> Vec<OrderState> {
+        self.orders.clone()
+    }
 
-            while self.balance.nano < 0 {
-                self.balance.units -= 1;
-                self.balance.nano += 1_000_000_000;
+    async fn cancel_order(&mut self, order_id: String) -> Result<Response<CancelOrderResponse>, Status> {
+        match self.orders.iter().position(|order| order.order_id == order_id) {
+            Some(index) => {
+                let order = self.orders.remove(index);
+                self.closed_orders.push(OrderState {
+                    execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusCancelled as i32,
+                    ..order
+                });
+                Ok(Response::new(CancelOrderResponse { time: None }))
             }
+            None => Err(Status::not_found(format!("Order={} not found in hist order service", order_id))),
+        }
+    }
 
-            println!(" ======================== New balance after buy={},{} ========================", self.balance.units, self.balance.nano);
-
-            Ok(Response::new(PostOrderResponse {
-                order_id: "hist".to_string(),
-                execution_report_status: 0,
-                lots_requested: quantity,
-                lots_executed: quantity,
-                initial_order_price: None,
-                executed_order_price: Some(MoneyValue {
-                    currency: "".to_string(),
-                    units: price.clone().units,
-                    nano: price.clone().nano,
-                }),
-                total_order_amount: None,
-                initial_commission: None,
-                executed_commission: Some(MoneyValue {
-                    currency: "".to_string(),
-                    units: self.commission as i64,
-                    nano: 0,
-                }),
-                aci_value: None,
-                figi,
-                direction: OrderDirection::Buy as i32,
-                initial_security_price: None,
-                order_type: 0,
-                message: "hist training buy".to_string(),
-                initial_order_price_pt: None,
-                instrument_uid: instrument_id.clone(),
-            }))
-        } else {
-            Err(Status::new(
-                Code::Cancelled,
-                format!("Not enough money balance={:?} while buying instrument_id={:?}, quantity={:?}, price={:?} ", self.balance, instrument_id, quantity, _price),
-            ))
+    // как у брокера: старая заявка отменяется, новая выставляется в ту же сторону
+    async fn replace_order(&mut self, order_id: String, idempotency_key: String, quantity: i64, price: Option<Quotation>) -> Result<Response<PostOrderResponse>, Status> {
+        // повтор с тем же ключом: заявка уже заменена, отдается новая
+        if let Some(existing) = self.orders.iter().chain(self.closed_orders.iter()).find(|order| order.order_request_id == idempotency_key) {
+            return Ok(Response::new(order_state_to_response(existing.clone())));
         }
+        let order = match self.orders.iter().find(|order| order.order_id == order_id) {
+            Some(order) => order.clone(),
+            None => return Err(Status::not_found(format!("Order={} not found in hist order service", order_id))),
+        };
+        let price = match price.or(order.initial_security_price.as_ref().map(Quotation::from_money)) {
+            Some(price) => price,
+            None => return Err(Status::invalid_argument("Limit order requires price")),
+        };
+        self.cancel_order(order_id).await?;
+        let direction = if order.direction == OrderDirection::Buy as i32 { OrderDirection::Buy } else { OrderDirection::Sell };
+        self.post_limit_order(idempotency_key, order.figi, order.instrument_uid, quantity, price, direction).map_err(|e| *e)
     }
 
-    async fn order_sell(&mut self, figi: String, instrument_id: String, quantity: i64, _price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
-        if quantity > 0 {
+    async fn get_order_state(&mut self, order_id: String) -> Result<Response<OrderState>, Status> {
+        self.orders.iter().chain(self.closed_orders.iter())
+            .find(|order| order.order_id == order_id)
+            .map(|order| Response::new(order.clone()))
+            .ok_or_else(|| Status::not_found(format!("Order={} not found in hist order service", order_id)))
+    }
 
-            let price = if _price.is_some() || order_type != OrderType::Market {
-                panic!("Only market order support in hist order service")
-            } else {
-                self.current_price.clone()
-            };
+    // стоп-заявки срабатывают в set_current_price
+    async fn post_stop_order(&mut self, request: StopOrderRequest) -> Result<Response<PostStopOrderResponse>, Status> {
+        let stop_order_id = self.stop_orders.post(request).map_err(|e| *e)?;
+        Ok(Response::new(PostStopOrderResponse { stop_order_id }))
+    }
 
-            self.balance = (self.balance.wr() + price.clone().wr() * quantity).uwr();
-            let commission = (self.commission as f64 / 100.0) as i64;
-            self.balance = (self.balance.wr() - price.clone().wr() * quantity * commission).uwr();
-
-            println!(" ======================== New balance after sell={},{} ========================", self.balance.units, self.balance.nano);
-
-            Ok(Response::new(PostOrderResponse {
-                order_id: "hist".to_string(),
-                execution_report_status: 0,
-                lots_requested: quantity,
-                lots_executed: quantity,
-                initial_order_price: None,
-                executed_order_price: Some(MoneyValue {
-                    currency: "".to_string(),
-                    units: price.clone().units,
-                    nano: price.clone().nano,
-                }),
-                total_order_amount: None,
-                initial_commission: None,
-                executed_commission: Some(MoneyValue {
-                    currency: "".to_string(),
-                    units: self.commission as i64,
-                    nano: 0,
-                }),
-                aci_value: None,
-                figi,
-                direction: OrderDirection::Sell as i32,
-                initial_security_price: None,
-                order_type: 0,
-                message: "hist training sell".to_string(),
-                initial_order_price_pt: None,
-                instrument_uid: instrument_id.clone(),
-            }))
-        } else {
-            Err(Status::new(
-                Code::Cancelled,
-                format!("Incorrect price while selling instrument_id={:?}, quantity={:?}, price={:?} ", instrument_id, quantity, _price),
-            ))
-        }
+    async fn cancel_stop_order(&mut self, stop_order_id: String) -> Result<Response<CancelStopOrderResponse>, Status> {
+        self.stop_orders.cancel(&stop_order_id).map_err(|e| *e)?;
+        Ok(Response::new(CancelStopOrderResponse { time: None }))
+    }
+
+    async fn get_stop_orders(&mut self) -> Vec<StopOrder> {
+        self.stop_orders.orders()
+    }
+}
+
+impl<O: OrderService> OrderService for SharedOrderService<O> {
+    async fn order_buy(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
+        self.lock().await.order_buy(order_request_id, figi, instrument_id, quantity, price, order_type).await
+    }
+
+    async fn order_sell(&mut self, order_request_id: String, figi: String, instrument_id: String, quantity: i64, price: Option<Quotation>, order_type: OrderType) -> Result<Response<PostOrderResponse>, Status> {
+        self.lock().await.order_sell(order_request_id, figi, instrument_id, quantity, price, order_type).await
     }
 
     async fn get_orders(&mut self) -> Vec<OrderState> {
-        Vec::new()
+        self.lock().await.get_orders().await
+    }
+
+    async fn cancel_order(&mut self, order_id: String) -> Result<Response<CancelOrderResponse>, Status> {
+        self.lock().await.cancel_order(order_id).await
+    }
+
+    async fn replace_order(&mut self, order_id: String, idempotency_key: String, quantity: i64, price: Option<Quotation>) -> Result<Response<PostOrderResponse>, Status> {
+        self.lock().await.replace_order(order_id, idempotency_key, quantity, price).await
+    }
+
+    async fn get_order_state(&mut self, order_id: String) -> Result<Response<OrderState>, Status> {
+        self.lock().await.get_order_state(order_id).await
+    }
+
+    async fn post_stop_order(&mut self, request: StopOrderRequest) -> Result<Response<PostStopOrderResponse>, Status> {
+        self.lock().await.post_stop_order(request).await
+    }
+
+    async fn cancel_stop_order(&mut self, stop_order_id: String) -> Result<Response<CancelStopOrderResponse>, Status> {
+        self.lock().await.cancel_stop_order(stop_order_id).await
+    }
+
+    async fn get_stop_orders(&mut self) -> Vec<StopOrder> {
+        self.lock().await.get_stop_orders().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{Quotation, StopOrder, StopOrderDirection, StopOrderType};
+    use crate::service::order_service::{StopOrderBook, StopOrderRequest};
+
+    fn request(direction: StopOrderDirection, stop_order_type: StopOrderType, stop_price: i64) -> StopOrderRequest {
+        StopOrderRequest {
+            instrument_id: "uid".to_string(),
+            quantity: 1,
+            price: Some(Quotation { units: stop_price, nano: 0 }),
+            stop_price: Quotation { units: stop_price, nano: 0 },
+            direction,
+            stop_order_type,
+        }
+    }
+
+    #[test]
+    fn test_stop_order_book() {
+        let mut book = StopOrderBook::default();
+        let stop_loss = book.post(request(StopOrderDirection::Sell, StopOrderType::StopLoss, 90)).unwrap();
+        let take_profit = book.post(request(StopOrderDirection::Sell, StopOrderType::TakeProfit, 110)).unwrap();
+        let short_stop = book.post(request(StopOrderDirection::Buy, StopOrderType::StopLimit, 120)).unwrap();
+        assert!(book.post(StopOrderRequest { price: None, ..request(StopOrderDirection::Buy, StopOrderType::StopLimit, 1) }).is_err());
+        assert_eq!(book.orders().len(), 3);
+
+        assert!(book.triggered("uid", &Quotation { units: 100, nano: 0 }).is_empty());
+        assert!(book.triggered("other", &Quotation { units: 50, nano: 0 }).is_empty());
+
+        let ids = |orders: Vec<StopOrder>| orders.into_iter().map(|order| order.stop_order_id).collect::<Vec<String>>();
+        assert_eq!(ids(book.triggered("uid", &Quotation { units: 89, nano: 0 })), vec![stop_loss]);
+        // тейк-профит на продажу -- при росте, стоп на покупку для шорта -- тоже при росте
+        assert_eq!(ids(book.triggered("uid", &Quotation { units: 125, nano: 0 })), vec![take_profit, short_stop.clone()]);
+
+        assert!(book.orders().is_empty());
+        assert!(book.cancel(&short_stop).is_err());
     }
 }
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use tinkoff_invest_api::tcs::{MoneyValue, OrderDirection, OrderExecutionReportStatus, OrderState, PostOrderResponse, Quotation};
+use crate::service::order_service::OrderService;
+use crate::utils::quotation::QuotationExtension;
+
+const ORDER_STATE_POLL_SEC: i64 = 2;
+
+// исполненная часть заявки, цена и комиссия -- только этой части
+#[derive(Debug, Clone)]
+pub struct OrderFill<T> {
+    pub order_id: String,
+    pub instrument_id: String,
+    pub direction: OrderDirection,
+    // в лотах
+    pub lots: i64,
+    // цена одной бумаги, брокер не всегда присылает ее сразу
+    pub price: Option<Quotation>,
+    pub commission: f64,
+    pub tag: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackedOrder<T> {
+    pub order_id: String,
+    pub instrument_id: String,
+    pub direction: OrderDirection,
+    pub status: OrderExecutionReportStatus,
+    pub lots_requested: i64,
+    pub lots_executed: i64,
+    // средняя цена исполненных лотов
+    pub avg_price: Option<f64>,
+    pub commission: f64,
+    // что заявка значит для владельца, например открытие позиции стратегии
+    pub tag: T,
+}
+
+impl<T> TrackedOrder<T> {
+    // после этих статусов исполнений больше не будет
+    pub fn is_final(&self) -> bool {
+        matches!(self.status, OrderExecutionReportStatus::ExecutionReportStatusFill
+            | OrderExecutionReportStatus::ExecutionReportStatusRejected
+            | OrderExecutionReportStatus::ExecutionReportStatusCancelled)
+    }
+}
+
+// общее у ответа на выставление и состояния заявки
+struct OrderReport {
+    order_id: String,
+    status: i32,
+    lots_executed: i64,
+    // средняя цена одной бумаги
+    avg_price: Option<MoneyValue>,
+    executed_commission: Option<MoneyValue>,
+}
+
+impl From<&PostOrderResponse> for OrderReport {
+    fn from(response: &PostOrderResponse) -> Self {
+        Self {
+            order_id: response.order_id.clone(),
+            status: response.execution_report_status,
+            lots_executed: response.lots_executed,
+            // в ответе на выставление executed_order_price -- цена одной бумаги
+            avg_price: response.executed_order_price.clone(),
+            executed_commission: response.executed_commission.clone(),
+        }
+    }
+}
+
+impl From<&OrderState> for OrderReport {
+    fn from(state: &OrderState) -> Self {
+        Self {
+            order_id: state.order_id.clone(),
+            status: state.execution_report_status,
+            lots_executed: state.lots_executed,
+            // в состоянии заявки executed_order_price -- стоимость всех исполненных лотов, цена одной бумаги отдельно
+            avg_price: state.average_position_price.clone(),
+            executed_commission: state.executed_commission.clone(),
+        }
+    }
+}
+
+fn money_to_f(money: &Option<MoneyValue>) -> Option<f64> {
+    money.as_ref().map(|money| Quotation::from_money(money).to_f()).filter(|value| *value > 0.0)
+}
+
+// ведет выставленные заявки до финального статуса, опрашивая GetOrderState. Исполнения отдаются по мере появления, частичные -- по частям
+pub struct OrderTracker<T> {
+    orders: HashMap<String, TrackedOrder<T>>,
+    poll_sec: i64,
+    last_poll: i64,
+}
+
+impl<T: Clone> Default for OrderTracker<T> {
+    fn default() -> Self {
+        Self::new(ORDER_STATE_POLL_SEC)
+    }
+}
+
+impl<T: Clone> OrderTracker<T> {
+    pub fn new(poll_sec: i64) -> Self {
+        Self { orders: HashMap::new(), poll_sec, last_poll: 0 }
+    }
+
+    // рыночная заявка часто исполняется сразу, тогда исполнение есть уже в ответе
+    pub fn track(&mut self, response: &PostOrderResponse, tag: T) -> Option<OrderFill<T>> {
+        self.orders.insert(response.order_id.clone(), TrackedOrder {
+            order_id: response.order_id.clone(),
+            instrument_id: response.instrument_uid.clone(),
+            direction: if response.direction == OrderDirection::Sell as i32 { OrderDirection::Sell } else { OrderDirection::Buy },
+            status: OrderExecutionReportStatus::ExecutionReportStatusNew,
+            lots_requested: response.lots_requested,
+            lots_executed: 0,
+            avg_price: None,
+            commission: 0.0,
+            tag,
+        });
+        self.apply(OrderReport::from(response))
+    }
+
+    pub fn apply_state(&mut self, state: &OrderState) -> Option<OrderFill<T>> {
+        self.apply(OrderReport::from(state))
+    }
+
+    // цена новой части -- из разницы оборотов до и после, заявка в финальном статусе перестает отслеживаться
+    fn apply(&mut self, report: OrderReport) -> Option<OrderFill<T>> {
+        let order = self.orders.get_mut(&report.order_id)?;
+        order.status = OrderExecutionReportStatus::from_i32(report.status).unwrap_or(order.status);
+        let lots = report.lots_executed - order.lots_executed;
+        let fill = if lots > 0 {
+            let avg_price = money_to_f(&report.avg_price);
+            let price = match (avg_price, order.avg_price) {
+                (Some(avg_price), Some(prev_price)) => Some((avg_price * report.lots_executed as f64 - prev_price * order.lots_executed as f64) / lots as f64),
+                (Some(avg_price), None) if order.lots_executed == 0 => Some(avg_price),
+                _ => None,
+            };
+            let commission = money_to_f(&report.executed_commission).unwrap_or(order.commission);
+            let fill = OrderFill {
+                order_id: order.order_id.clone(),
+                instrument_id: order.instrument_id.clone(),
+                direction: order.direction,
+                lots,
+                price: price.map(Quotation::from_f),
+                commission: commission - order.commission,
+                tag: order.tag.clone(),
+            };
+            order.lots_executed = report.lots_executed;
+            order.avg_price = avg_price.or(order.avg_price);
+            order.commission = commission;
+            Some(fill)
+        } else {
+            None
+        };
+        if order.is_final() {
+            println!("Order={} finished with status={:?}, executed {}/{} lots, avg_price={:?}, commission={}",
+                     order.order_id, order.status, order.lots_executed, order.lots_requested, order.avg_price, order.commission);
+            self.orders.remove(&report.order_id);
+        }
+        fill
+    }
+
+    pub fn orders(&self) -> impl Iterator<Item = &TrackedOrder<T>> {
+        self.orders.values()
+    }
+
+    // пока по инструменту есть незавершенная заявка, новые по нему не выставляются
+    pub fn is_pending(&self, instrument_id: &str) -> bool {
+        self.orders().any(|order| order.instrument_id == instrument_id)
+    }
+
+    pub async fn poll<O: OrderService>(&mut self, order_service: &mut O, now: i64) -> Vec<OrderFill<T>> {
+        let mut fills = Vec::new();
+        if self.orders.is_empty() || now - self.last_poll < self.poll_sec {
+            return fills;
+        }
+        self.last_poll = now;
+        let order_ids: Vec<String> = self.orders.keys().cloned().collect();
+        for order_id in order_ids {
+            match order_service.get_order_state(order_id.clone()).await {
+                Ok(state) => fills.extend(self.apply_state(&state.into_inner())),
+                Err(e) => eprintln!("Error while getting state of order={}: {}", order_id, e.message()),
+            }
+        }
+        fills
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{MoneyValue, OrderDirection, OrderExecutionReportStatus, OrderState, PostOrderResponse, Quotation};
+    use crate::service::order_tracker::OrderTracker;
+
+    fn money(units: i64) -> Option<MoneyValue> {
+        Some(MoneyValue { currency: "".to_string(), units, nano: 0 })
+    }
+
+    // как у брокера: executed_order_price -- стоимость исполненного, average_position_price -- цена одной бумаги
+    fn state(status: OrderExecutionReportStatus, lots_executed: i64, price: i64, commission: i64) -> OrderState {
+        OrderState {
+            order_id: "1".to_string(),
+            execution_report_status: status as i32,
+            lots_executed,
+            executed_order_price: money(price * lots_executed),
+            average_position_price: money(price),
+            executed_commission: money(commission),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_partial_fills() {
+        let mut tracker = OrderTracker::new(0);
+        let response = PostOrderResponse {
+            order_id: "1".to_string(),
+            execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusNew as i32,
+            lots_requested: 4,
+            direction: OrderDirection::Sell as i32,
+            instrument_uid: "uid".to_string(),
+            ..Default::default()
+        };
+        assert!(tracker.track(&response, "open").is_none());
+        assert!(tracker.is_pending("uid"));
+
+        // 1 лот по 100, потом еще 2 -- средняя 102, значит новые по 103
+        let fill = tracker.apply_state(&state(OrderExecutionReportStatus::ExecutionReportStatusPartiallyfill, 1, 100, 1)).unwrap();
+        assert_eq!((fill.lots, fill.price, fill.commission, fill.direction), (1, Some(Quotation { units: 100, nano: 0 }), 1.0, OrderDirection::Sell));
+        let fill = tracker.apply_state(&state(OrderExecutionReportStatus::ExecutionReportStatusPartiallyfill, 3, 102, 3)).unwrap();
+        assert_eq!((fill.lots, fill.price, fill.commission, fill.tag), (2, Some(Quotation { units: 103, nano: 0 }), 2.0, "open"));
+        // повтор того же состояния новых исполнений не дает
+        assert!(tracker.apply_state(&state(OrderExecutionReportStatus::ExecutionReportStatusPartiallyfill, 3, 102, 3)).is_none());
+
+        assert!(tracker.apply_state(&state(OrderExecutionReportStatus::ExecutionReportStatusCancelled, 3, 102, 3)).is_none());
+        assert!(!tracker.is_pending("uid"));
+        assert_eq!(tracker.orders().count(), 0);
+    }
+
+    #[test]
+    fn test_immediate_fill() {
+        let mut tracker = OrderTracker::new(0);
+        let response = PostOrderResponse {
+            order_id: "1".to_string(),
+            execution_report_status: OrderExecutionReportStatus::ExecutionReportStatusFill as i32,
+            lots_requested: 2,
+            lots_executed: 2,
+            executed_order_price: money(50),
+            instrument_uid: "uid".to_string(),
+            ..Default::default()
+        };
+        let fill = tracker.track(&response, ()).unwrap();
+        assert_eq!((fill.lots, fill.price, fill.direction), (2, Some(Quotation { units: 50, nano: 0 }), OrderDirection::Buy));
+        assert!(!tracker.is_pending("uid"));
+    }
+}
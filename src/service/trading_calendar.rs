@@ -0,0 +1,266 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{Datelike, Duration, FixedOffset, NaiveDate, NaiveTime, TimeZone, Weekday};
+use prost_types::Timestamp;
+use serde::{Deserialize, Serialize};
+use tinkoff_invest_api::DefaultInterceptor;
+use tinkoff_invest_api::tcs::{TradingDay, TradingSchedulesRequest, TradingSchedulesResponse};
+use tinkoff_invest_api::tcs::instruments_service_client::InstrumentsServiceClient;
+use tonic::codegen::InterceptedService;
+use tonic::transport::Channel;
+
+// Мосбиржа работает по Москве, перехода на летнее время нет
+const MOSCOW_OFFSET_SEC: i32 = 3 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionKind {
+    Main,
+    Evening,
+    Weekend,
+    // аукционы открытия и закрытия: цена определяется по итогам, стратегии в них не торгуют
+    Auction,
+}
+
+// время в секундах unix, [start, end)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionInterval {
+    pub kind: SessionKind,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl SessionInterval {
+    fn contains(&self, seconds: i64) -> bool {
+        self.start <= seconds && seconds < self.end
+    }
+}
+
+// расписание биржи на несколько дней. День без интервалов -- неторговый
+#[derive(Debug, Clone, Default)]
+pub struct TradingCalendar {
+    pub intervals: Vec<SessionInterval>,
+}
+
+impl TradingCalendar {
+    pub fn new(mut intervals: Vec<SessionInterval>) -> Self {
+        intervals.sort_by_key(|interval| interval.start);
+        Self { intervals }
+    }
+
+    // по ответу TradingSchedules. Основная сессия -- между аукционами открытия и закрытия
+    pub fn from_schedule(exchange: &str, response: &TradingSchedulesResponse) -> Self {
+        let mut intervals = Vec::new();
+        for schedule in response.exchanges.iter().filter(|schedule| schedule.exchange == exchange) {
+            for day in schedule.days.iter().filter(|day| day.is_trading_day) {
+                intervals.extend(day_intervals(day));
+            }
+        }
+        Self::new(intervals)
+    }
+
+    // статичное расписание Мосбиржи: будни -- основная и вечерняя сессии, выходные -- сессия выходного дня.
+    // Праздники по нему не видны, это запасной вариант на случай недоступности API и кэша
+    pub fn moex_fallback(from: &Timestamp, days: i64) -> Self {
+        let moscow = FixedOffset::east_opt(MOSCOW_OFFSET_SEC).unwrap();
+        let first_date = moscow.timestamp_opt(from.seconds, 0).unwrap().date_naive();
+        let mut intervals = Vec::new();
+        for day in 0..days {
+            let date = first_date + Duration::days(day);
+            let at = |hour: u32, min: u32| moscow_seconds(date, hour, min);
+            match date.weekday() {
+                Weekday::Sat | Weekday::Sun => {
+                    intervals.push(SessionInterval { kind: SessionKind::Auction, start: at(9, 50), end: at(10, 0) });
+                    intervals.push(SessionInterval { kind: SessionKind::Weekend, start: at(10, 0), end: at(19, 0) });
+                }
+                _ => {
+                    intervals.push(SessionInterval { kind: SessionKind::Auction, start: at(9, 50), end: at(10, 0) });
+                    intervals.push(SessionInterval { kind: SessionKind::Main, start: at(10, 0), end: at(18, 40) });
+                    intervals.push(SessionInterval { kind: SessionKind::Auction, start: at(18, 40), end: at(18, 50) });
+                    intervals.push(SessionInterval { kind: SessionKind::Auction, start: at(19, 0), end: at(19, 5) });
+                    intervals.push(SessionInterval { kind: SessionKind::Evening, start: at(19, 5), end: at(23, 50) });
+                }
+            }
+        }
+        Self::new(intervals)
+    }
+
+    // сессия с непрерывной торговлей, аукционы сюда не входят
+    pub fn session_at(&self, now: &Timestamp) -> Option<&SessionInterval> {
+        self.intervals.iter()
+            .find(|interval| interval.kind != SessionKind::Auction && interval.contains(now.seconds))
+    }
+
+    pub fn is_auction(&self, now: &Timestamp) -> bool {
+        self.intervals.iter()
+            .any(|interval| interval.kind == SessionKind::Auction && interval.contains(now.seconds))
+    }
+
+    // конец торгового дня: основная сессия продолжается вечерней, закрываться надо перед последней сессией дня
+    pub fn day_close(&self, session: &SessionInterval) -> i64 {
+        let date = moscow_date(session.start);
+        self.intervals.iter()
+            .filter(|interval| interval.kind != SessionKind::Auction && moscow_date(interval.start) == date)
+            .map(|interval| interval.end)
+            .max()
+            .unwrap_or(session.end)
+    }
+
+    // есть ли в календаре данные на момент now или позже; без них любая фаза -- Closed
+    pub fn covers(&self, now: &Timestamp) -> bool {
+        self.intervals.iter().any(|interval| interval.end > now.seconds)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = csv::Writer::from_path(path)?;
+        for interval in &self.intervals {
+            writer.serialize(interval)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let intervals = reader.deserialize().collect::<Result<Vec<SessionInterval>, _>>()?;
+        Ok(Self::new(intervals))
+    }
+}
+
+fn moscow_seconds(date: NaiveDate, hour: u32, min: u32) -> i64 {
+    let moscow = FixedOffset::east_opt(MOSCOW_OFFSET_SEC).unwrap();
+    moscow.from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(hour, min, 0).unwrap())).unwrap().timestamp()
+}
+
+fn moscow_date(seconds: i64) -> NaiveDate {
+    FixedOffset::east_opt(MOSCOW_OFFSET_SEC).unwrap().timestamp_opt(seconds, 0).unwrap().date_naive()
+}
+
+fn day_intervals(day: &TradingDay) -> Vec<SessionInterval> {
+    let seconds = |time: &Option<Timestamp>| time.as_ref().map(|time| time.seconds);
+    let mut intervals = Vec::new();
+    let mut push = |kind: SessionKind, start: Option<i64>, end: Option<i64>| {
+        if let (Some(start), Some(end)) = (start, end) {
+            if start < end {
+                intervals.push(SessionInterval { kind, start, end });
+            }
+        }
+    };
+    push(SessionKind::Auction, seconds(&day.opening_auction_start_time), seconds(&day.opening_auction_end_time));
+    push(SessionKind::Auction, seconds(&day.closing_auction_start_time), seconds(&day.closing_auction_end_time));
+    push(SessionKind::Auction, seconds(&day.evening_opening_auction_start_time), seconds(&day.evening_start_time));
+
+    let main_kind = match day.date.as_ref().map(|date| moscow_date(date.seconds).weekday()) {
+        Some(Weekday::Sat) | Some(Weekday::Sun) => SessionKind::Weekend,
+        _ => SessionKind::Main,
+    };
+    let main_start = seconds(&day.opening_auction_end_time).or(seconds(&day.start_time));
+    let main_end = seconds(&day.closing_auction_start_time).or(seconds(&day.end_time));
+    push(main_kind, main_start, main_end);
+    push(SessionKind::Evening, seconds(&day.evening_start_time), seconds(&day.evening_end_time));
+    intervals
+}
+
+pub fn cache_path(cache_dir: &str, exchange: &str, from: &Timestamp, days: i64) -> PathBuf {
+    PathBuf::from(cache_dir).join(format!("{}_{}_{}.csv", exchange, moscow_date(from.seconds), days))
+}
+
+// календарь на days дней с from: сначала кэш на диске, потом TradingSchedules, если и API недоступно -- статичное расписание Мосбиржи
+pub async fn load_trading_calendar(
+    client: &mut InstrumentsServiceClient<InterceptedService<Channel, DefaultInterceptor>>,
+    exchange: &str,
+    from: Timestamp,
+    days: i64,
+    cache_dir: &str,
+) -> TradingCalendar {
+    let path = cache_path(cache_dir, exchange, &from, days);
+    if path.is_file() {
+        match TradingCalendar::load(&path) {
+            Ok(calendar) => return calendar,
+            Err(e) => eprintln!("Error while loading trading calendar from {:?}: {}", path, e),
+        }
+    }
+    let to = Timestamp { seconds: from.seconds + days * 24 * 3600, nanos: from.nanos };
+    let response = client.trading_schedules(TradingSchedulesRequest {
+        exchange: exchange.to_string(),
+        from: Some(from.clone()),
+        to: Some(to),
+    }).await;
+    match response {
+        Ok(response) => {
+            let calendar = TradingCalendar::from_schedule(exchange, &response.into_inner());
+            calendar.save(&path)
+                .unwrap_or_else(|e| eprintln!("Error while saving trading calendar to {:?}: {}", path, e));
+            calendar
+        }
+        Err(e) => {
+            eprintln!("Error while loading trading schedules, use static MOEX calendar: {}", e.message());
+            TradingCalendar::moex_fallback(&from, days)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+    use prost_types::Timestamp;
+    use tinkoff_invest_api::tcs::{TradingDay, TradingSchedule, TradingSchedulesResponse};
+    use crate::service::trading_calendar::{moscow_seconds, SessionKind, TradingCalendar};
+
+    fn at(date: NaiveDate, hour: u32, min: u32) -> Timestamp {
+        Timestamp { seconds: moscow_seconds(date, hour, min), nanos: 0 }
+    }
+
+    #[test]
+    fn test_moex_fallback() {
+        // пятница
+        let friday = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let saturday = NaiveDate::from_ymd_opt(2024, 3, 2).unwrap();
+        let calendar = TradingCalendar::moex_fallback(&at(friday, 0, 0), 2);
+
+        assert_eq!(calendar.session_at(&at(friday, 9, 55)), None);
+        assert!(calendar.is_auction(&at(friday, 9, 55)));
+        let main = calendar.session_at(&at(friday, 12, 0)).unwrap().clone();
+        assert_eq!(main.kind, SessionKind::Main);
+        assert_eq!(main.end, moscow_seconds(friday, 18, 40));
+        assert_eq!(calendar.day_close(&main), moscow_seconds(friday, 23, 50));
+        assert_eq!(calendar.session_at(&at(friday, 20, 0)).unwrap().kind, SessionKind::Evening);
+        assert_eq!(calendar.session_at(&at(friday, 23, 55)), None);
+        assert_eq!(calendar.session_at(&at(saturday, 12, 0)).unwrap().kind, SessionKind::Weekend);
+    }
+
+    #[test]
+    fn test_from_schedule() {
+        let monday = NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        let holiday = NaiveDate::from_ymd_opt(2024, 3, 8).unwrap();
+        let response = TradingSchedulesResponse {
+            exchanges: vec![TradingSchedule {
+                exchange: "MOEX".to_string(),
+                days: vec![
+                    TradingDay {
+                        date: Some(at(monday, 0, 0)),
+                        is_trading_day: true,
+                        start_time: Some(at(monday, 9, 50)),
+                        end_time: Some(at(monday, 18, 50)),
+                        opening_auction_start_time: Some(at(monday, 9, 50)),
+                        opening_auction_end_time: Some(at(monday, 10, 0)),
+                        closing_auction_start_time: Some(at(monday, 18, 40)),
+                        closing_auction_end_time: Some(at(monday, 18, 50)),
+                        ..Default::default()
+                    },
+                    TradingDay { date: Some(at(holiday, 0, 0)), is_trading_day: false, ..Default::default() },
+                ],
+            }],
+        };
+        let calendar = TradingCalendar::from_schedule("MOEX", &response);
+
+        assert!(calendar.is_auction(&at(monday, 18, 45)));
+        let main = calendar.session_at(&at(monday, 10, 0)).unwrap();
+        assert_eq!((main.start, main.end), (moscow_seconds(monday, 10, 0), moscow_seconds(monday, 18, 40)));
+        assert_eq!(calendar.session_at(&at(monday, 20, 0)), None);
+        assert_eq!(calendar.session_at(&at(holiday, 12, 0)), None);
+    }
+}
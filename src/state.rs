@@ -1,7 +1,7 @@
 use std::sync::Arc;
 use std::time::Duration;
 use flume::Sender;
-use tinkoff_invest_api::tcs::{CandleInstrument, LastPriceInstrument, MarketDataRequest, PortfolioResponse, SubscriptionInterval};
+use tinkoff_invest_api::tcs::{CandleInstrument, LastPriceInstrument, MarketDataRequest, SubscriptionInterval};
 use tinkoff_invest_api::tcs::Share;
 use tinkoff_invest_api::tcs::market_data_request::Payload::{SubscribeCandlesRequest, SubscribeLastPriceRequest};
 use tinkoff_invest_api::tcs::market_data_response::Payload::{Candle, LastPrice, SubscribeCandlesResponse, SubscribeLastPriceResponse};
@@ -10,16 +10,16 @@ use tinkoff_invest_api::{tcs, TinkoffInvestService};
 use tokio::{task, time};
 use tokio::task::JoinHandle;
 use crate::prepare_md_stream;
-use crate::service::order_service::OrderServiceSandboxImpl;
+use crate::service::operations_service::OperationsService;
 use crate::state::candle_state::CandleState;
 use crate::state::last_price_state::LastPriceState;
+use crate::state::portfolio_state::PortfolioState;
 use crate::state::state::State;
-use crate::strategy::first_strategy::FirstStrategy;
-use crate::strategy::strategy::Strategy;
 
 pub mod state;
 pub mod last_price_state;
 pub mod candle_state;
+pub mod portfolio_state;
 
 fn map_to_candle_subscribe_request(shares: &Vec<Share>) -> Vec<CandleInstrument> {
     let mut res = Vec::new();
@@ -47,12 +47,12 @@ fn map_to_last_price_subscribe_request(shares: &Vec<Share>) -> Vec<LastPriceInst
     }).collect()
 }
 
+// после каждого обновления цены отправляет instrument_uid в ticks, по ним обновляются стратегии
 pub async fn run_updater_last_price(
     service: &TinkoffInvestService,
     instruments: Vec<Share>,
     state: Arc<LastPriceState>,
-    order_service: OrderServiceSandboxImpl,
-    positions: PortfolioResponse,
+    ticks: Sender<String>,
 ) -> (Sender<MarketDataRequest>, JoinHandle<()>) {
     let request = MarketDataRequest {
         payload: Some(SubscribeLastPriceRequest(tcs::SubscribeLastPriceRequest {
@@ -63,9 +63,6 @@ pub async fn run_updater_last_price(
     let (tx, mut streaming) = prepare_md_stream(service, request).await;
 
     let updater = task::spawn(async move {
-        let mut first_strategy = FirstStrategy::new(Arc::clone(&state), order_service, instruments.clone());
-        first_strategy.warm_up(positions).await.expect("Error while warm up first_strategy");
-
         loop {
             match streaming.message().await.unwrap() {
                 Some(next_message) => {
@@ -78,7 +75,8 @@ pub async fn run_updater_last_price(
                             state.update(&last_price)
                                 .unwrap_or_else(|err| eprintln!("Error updating last_price_state: {}", err));
 
-                            first_strategy.update().await.expect("Error updating first strategy");
+                            ticks.send(last_price.instrument_uid.clone())
+                                .unwrap_or_else(|err| eprintln!("Error sending last_price tick: {}", err));
                         }
                         _ => {
                             println!("MarketData last_price unknown message payload: {:#?}", payload);
@@ -134,3 +132,13 @@ pub async fn run_updater_candles(service: &TinkoffInvestService, instruments: Ve
     );
     (tx, updater)
 }
+
+// портфель не приходит стримом, поэтому периодически запрашиваем его целиком
+pub async fn run_updater_portfolio<O: OperationsService>(mut operations_service: O, state: Arc<PortfolioState>, period: Duration) {
+    loop {
+        let portfolio = operations_service.get_portfolio().await;
+        state.update(&portfolio)
+            .unwrap_or_else(|err| eprintln!("Error updating portfolio_state: {}", err));
+        time::sleep(period).await;
+    }
+}
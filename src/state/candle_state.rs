@@ -3,8 +3,15 @@ use std::error::Error;
 use std::sync::RwLock;
 use prost_types::Timestamp;
 use tinkoff_invest_api::tcs::{Candle, SubscriptionInterval};
+use crate::analytics::correlation::{aligned_closes, cointegration, log_spread, CointegrationStats, CorrelationStats, SpreadStats};
+use crate::analytics::levels::{find_levels, Level};
+use crate::analytics::moving_average::{ma_crossover, MaCrossover};
+use crate::analytics::oscillators::{bollinger, closes, donchian, rsi, BollingerBands, DonchianChannel};
+use crate::analytics::trend::{TrendAnalysis, TrendDirection};
+use crate::analytics::volatility::VolatilityStats;
+use crate::analytics::volume::{breakout_direction, directional_volume, relative_volume, volume_profile, VolumeProfile};
 use crate::state::state::State;
-use crate::trading_cfg::{HammerCfg, TrendCfg};
+use crate::trading_cfg::{BollingerCfg, CorrelationCfg, HammerCfg, LevelsCfg, MovingAverageCfg, MultiTimeframeCfg, RsiCfg, TimeframeCombine, TimeframeCondition, TimeframeRule, TrendCfg, VolatilityCfg, VolumeCfg};
 use crate::utils::candle::CandleExtension;
 use crate::utils::cmp::Cmp;
 use crate::utils::quotation::QuotationExtension;
@@ -24,22 +31,54 @@ pub struct CandleState {
 }
 
 pub trait CandleStateStatistic {
-    async fn get_last_candle(&self, instrument_uid: &String, interval: SubscriptionInterval) -> Option<Candle>;
-    async fn get_candles(&self, instrument_uid: &String, range: SizedRange) -> Option<Vec<Candle>>;
+    async fn get_last_candle(&self, instrument_uid: &str, interval: SubscriptionInterval) -> Option<Candle>;
+    async fn get_candles(&self, instrument_uid: &str, range: SizedRange) -> Option<Vec<Candle>>;
     // бычий молот, рынок пойдет вверх
     async fn is_hammer_bullish(&self, hammer_cfg: &HammerCfg, candle: Candle) -> bool;
     // медвежий молот, рынок пойдет вниз
     async fn is_hammer_bearish(&self, hammer_cfg: &HammerCfg, candle: Candle) -> bool;
     // боковое движение когда мин-макс последующих свеч находятся в пределах предудущей
-    async fn is_trend_flat(&self, trend_cfg: &TrendCfg, instrument_uid: &String, range: SizedRange) -> bool;
+    async fn is_trend_flat(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> bool;
     // нисходящий(медвежий) тренд -- каждый последующий минимум обновляет предыдущий
-    async fn is_trend_bearish(&self, trend_cfg: &TrendCfg, instrument_uid: &String, range: SizedRange) -> bool;
+    async fn is_trend_bearish(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> bool;
     // восходящий(бычий) тренд -- каждый последующий максимум обновляет предыдущий
-    async fn is_trend_bullish(&self, trend_cfg: &TrendCfg, instrument_uid: &String, range: SizedRange) -> bool;
+    async fn is_trend_bullish(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> bool;
+    // направление, наклон регрессии, R², ADX и экстремумы за range
+    async fn get_trend(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> Option<TrendAnalysis>;
+    // уровни поддержки и сопротивления за range, отсортированные по силе
+    async fn get_levels(&self, levels_cfg: &LevelsCfg, instrument_uid: &str, range: SizedRange) -> Option<Vec<Level>>;
+    // объем последней свечи range относительно среднего объема предыдущих
+    async fn get_relative_volume(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> Option<f64>;
+    async fn is_volume_spike(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> bool;
+    // последняя свеча пробила high/low range и объем выше среднего
+    async fn is_breakout_volume_confirmed(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> bool;
+    // объем по направлению тренда преобладает над объемом против него
+    async fn is_trend_volume_confirmed(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange, direction: TrendDirection) -> bool;
+    // распределение объема по ценам и point of control
+    async fn get_volume_profile(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> Option<VolumeProfile>;
+    // реализованная волатильность, ATR, перцентили диапазона свеч и режим волатильности за range
+    async fn get_volatility(&self, volatility_cfg: &VolatilityCfg, instrument_uid: &str, range: SizedRange) -> Option<VolatilityStats>;
+    // полосы Боллинджера по последним bollinger_cfg.period закрытиям в range
+    async fn get_bollinger(&self, bollinger_cfg: &BollingerCfg, instrument_uid: &str, range: SizedRange) -> Option<BollingerBands>;
+    async fn get_rsi(&self, rsi_cfg: &RsiCfg, instrument_uid: &str, range: SizedRange) -> Option<f64>;
+    // канал Дончиана по period свечам range перед последней
+    async fn get_donchian(&self, period: usize, instrument_uid: &str, range: SizedRange) -> Option<DonchianChannel>;
+    // быстрая и медленная средние по закрытиям и пересечение на последней свече range
+    async fn get_ma_crossover(&self, ma_cfg: &MovingAverageCfg, instrument_uid: &str, range: SizedRange) -> Option<MaCrossover>;
+    // корреляция доходностей и бета instrument_uid относительно benchmark_uid
+    async fn get_correlation(&self, correlation_cfg: &CorrelationCfg, instrument_uid: &str, benchmark_uid: &str, range: SizedRange) -> Option<CorrelationStats>;
+    // тест Энгла-Грейнджера и hedge ratio для спреда instrument_uid - hedge_ratio * other_uid
+    async fn get_cointegration(&self, instrument_uid: &str, other_uid: &str, range: SizedRange) -> Option<CointegrationStats>;
+    // спред лог-цен instrument_uid и other_uid, z-score по последним zscore_window свечам
+    async fn get_spread(&self, zscore_window: usize, instrument_uid: &str, other_uid: &str, range: SizedRange) -> Option<SpreadStats>;
+    // условие на одном таймфрейме, окно rule.window_size_min заканчивается в now
+    async fn check_timeframe(&self, rule: &TimeframeRule, instrument_uid: &str, now: &Timestamp) -> bool;
+    // условия на нескольких таймфреймах, объединенные по multi_cfg.combine
+    async fn check_timeframes(&self, multi_cfg: &MultiTimeframeCfg, instrument_uid: &str, now: &Timestamp) -> bool;
 }
 
 impl SizedRange {
-    fn new(interval: SubscriptionInterval, start: Timestamp, end: Timestamp) -> Self {
+    pub fn new(interval: SubscriptionInterval, start: Timestamp, end: Timestamp) -> Self {
         if start._le(&end) {
             SizedRange { interval, start, end }
         } else {
@@ -79,17 +118,17 @@ impl State<Candle> for CandleState {
 }
 
 impl CandleStateStatistic for CandleState {
-    async fn get_last_candle(&self, instrument_uid: &String, interval: SubscriptionInterval) -> Option<Candle> {
+    async fn get_last_candle(&self, instrument_uid: &str, interval: SubscriptionInterval) -> Option<Candle> {
         match interval {
             SubscriptionInterval::OneMinute =>
-                self.candles_1_by_instrument_uid.read().unwrap().get_vec(instrument_uid).unwrap().last().cloned(),
+                self.candles_1_by_instrument_uid.read().unwrap().get_vec(instrument_uid).and_then(|c| c.last().cloned()),
             SubscriptionInterval::FiveMinutes =>
-                self.candles_5_by_instrument_uid.read().unwrap().get_vec(instrument_uid).unwrap().last().cloned(),
+                self.candles_5_by_instrument_uid.read().unwrap().get_vec(instrument_uid).and_then(|c| c.last().cloned()),
             SubscriptionInterval::Unspecified => None
         }
     }
 
-    async fn get_candles(&self, instrument_uid: &String, range: SizedRange) -> Option<Vec<Candle>> {
+    async fn get_candles(&self, instrument_uid: &str, range: SizedRange) -> Option<Vec<Candle>> {
         let state = match range.interval {
             SubscriptionInterval::OneMinute => Some(self.candles_1_by_instrument_uid.read().unwrap()),
             SubscriptionInterval::FiveMinutes => Some(self.candles_5_by_instrument_uid.read().unwrap()),
@@ -97,7 +136,7 @@ impl CandleStateStatistic for CandleState {
         };
         match state {
             Some(state) => {
-                let candles = state.get_vec(instrument_uid).unwrap();
+                let candles = state.get_vec(instrument_uid)?;
                 let mut answer = Vec::new();
                 // мы смотрим от текущего времени в прошлое, поэтому оптимальнее идти с конца
                 for candle in candles.iter().rev() {
@@ -144,8 +183,11 @@ impl CandleStateStatistic for CandleState {
         }
     }
 
-    async fn is_trend_flat(&self, trend_cfg: &TrendCfg, instrument_uid: &String, range: SizedRange) -> bool {
-        let candles = self.get_candles(instrument_uid, range).await.unwrap();
+    async fn is_trend_flat(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> bool {
+        let candles = self.get_candles(instrument_uid, range).await.unwrap_or_default();
+        if candles.is_empty() {
+            return false;
+        }
 
         let _low = candles.get(0).unwrap().clone().low.unwrap();
         let _high = candles.get(0).unwrap().clone().high.unwrap();
@@ -170,8 +212,8 @@ impl CandleStateStatistic for CandleState {
         is_trend_flat
     }
 
-    async fn is_trend_bearish(&self, trend_cfg: &TrendCfg, instrument_uid: &String, range: SizedRange) -> bool {
-        let candles = self.get_candles(instrument_uid, range).await.unwrap();
+    async fn is_trend_bearish(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> bool {
+        let candles = self.get_candles(instrument_uid, range).await.unwrap_or_default();
 
         if candles.len() == 0 {
             false
@@ -200,8 +242,8 @@ impl CandleStateStatistic for CandleState {
         }
     }
 
-    async fn is_trend_bullish(&self, trend_cfg: &TrendCfg, instrument_uid: &String, range: SizedRange) -> bool {
-        let candles = self.get_candles(instrument_uid, range).await.unwrap();
+    async fn is_trend_bullish(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> bool {
+        let candles = self.get_candles(instrument_uid, range).await.unwrap_or_default();
 
         if candles.len() == 0 {
             false
@@ -229,4 +271,222 @@ impl CandleStateStatistic for CandleState {
             is_trend_bullish
         }
     }
-}
\ No newline at end of file
+
+    async fn get_trend(&self, trend_cfg: &TrendCfg, instrument_uid: &str, range: SizedRange) -> Option<TrendAnalysis> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        TrendAnalysis::new(trend_cfg, &candles)
+    }
+
+    async fn get_levels(&self, levels_cfg: &LevelsCfg, instrument_uid: &str, range: SizedRange) -> Option<Vec<Level>> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        if candles.is_empty() {
+            return None;
+        }
+        candles.reverse();
+        Some(find_levels(levels_cfg, &candles))
+    }
+
+    async fn get_relative_volume(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> Option<f64> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        relative_volume(&candles, volume_cfg.avg_period)
+    }
+
+    async fn is_volume_spike(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> bool {
+        self.get_relative_volume(volume_cfg, instrument_uid, range).await
+            .map(|ratio| ratio >= volume_cfg.spike_ratio)
+            .unwrap_or(false)
+    }
+
+    async fn is_breakout_volume_confirmed(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> bool {
+        match self.get_candles(instrument_uid, range).await {
+            Some(mut candles) => {
+                candles.reverse();
+                breakout_direction(&candles) != 0 &&
+                    relative_volume(&candles, volume_cfg.avg_period).map(|ratio| ratio >= volume_cfg.confirm_ratio).unwrap_or(false)
+            }
+            None => false
+        }
+    }
+
+    async fn is_trend_volume_confirmed(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange, direction: TrendDirection) -> bool {
+        let candles = match self.get_candles(instrument_uid, range).await {
+            Some(candles) => candles,
+            None => return false,
+        };
+        let (up_volume, down_volume) = directional_volume(&candles);
+        match direction {
+            TrendDirection::Bullish => up_volume as f64 >= down_volume as f64 * volume_cfg.trend_volume_ratio && up_volume > 0,
+            TrendDirection::Bearish => down_volume as f64 >= up_volume as f64 * volume_cfg.trend_volume_ratio && down_volume > 0,
+            TrendDirection::Flat => true,
+        }
+    }
+
+    async fn get_volume_profile(&self, volume_cfg: &VolumeCfg, instrument_uid: &str, range: SizedRange) -> Option<VolumeProfile> {
+        let candles = self.get_candles(instrument_uid, range).await?;
+        volume_profile(&candles, volume_cfg.profile_buckets)
+    }
+
+    async fn get_volatility(&self, volatility_cfg: &VolatilityCfg, instrument_uid: &str, range: SizedRange) -> Option<VolatilityStats> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        VolatilityStats::new(volatility_cfg, &candles)
+    }
+
+    async fn get_bollinger(&self, bollinger_cfg: &BollingerCfg, instrument_uid: &str, range: SizedRange) -> Option<BollingerBands> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        bollinger(&closes(&candles), bollinger_cfg.period, bollinger_cfg.std_multiplier)
+    }
+
+    async fn get_rsi(&self, rsi_cfg: &RsiCfg, instrument_uid: &str, range: SizedRange) -> Option<f64> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        rsi(&closes(&candles), rsi_cfg.period)
+    }
+
+    async fn get_donchian(&self, period: usize, instrument_uid: &str, range: SizedRange) -> Option<DonchianChannel> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        donchian(&candles, period)
+    }
+
+    async fn get_ma_crossover(&self, ma_cfg: &MovingAverageCfg, instrument_uid: &str, range: SizedRange) -> Option<MaCrossover> {
+        let mut candles = self.get_candles(instrument_uid, range).await?;
+        candles.reverse();
+        ma_crossover(ma_cfg.kind, &closes(&candles), ma_cfg.fast_period, ma_cfg.slow_period)
+    }
+
+    async fn get_correlation(&self, correlation_cfg: &CorrelationCfg, instrument_uid: &str, benchmark_uid: &str, range: SizedRange) -> Option<CorrelationStats> {
+        let candles = self.get_candles(instrument_uid, range.clone()).await?;
+        let benchmark_candles = self.get_candles(benchmark_uid, range).await?;
+        let (prices, benchmark_prices) = aligned_closes(&candles, &benchmark_candles);
+        CorrelationStats::new(&prices, &benchmark_prices, correlation_cfg.rolling_window)
+    }
+
+    async fn get_cointegration(&self, instrument_uid: &str, other_uid: &str, range: SizedRange) -> Option<CointegrationStats> {
+        let candles = self.get_candles(instrument_uid, range.clone()).await?;
+        let other_candles = self.get_candles(other_uid, range).await?;
+        let (prices, other_prices) = aligned_closes(&candles, &other_candles);
+        cointegration(&prices, &other_prices)
+    }
+
+    async fn get_spread(&self, zscore_window: usize, instrument_uid: &str, other_uid: &str, range: SizedRange) -> Option<SpreadStats> {
+        let candles = self.get_candles(instrument_uid, range.clone()).await?;
+        let other_candles = self.get_candles(other_uid, range).await?;
+        let (prices, other_prices) = aligned_closes(&candles, &other_candles);
+        log_spread(&prices, &other_prices, zscore_window)
+    }
+
+    async fn check_timeframe(&self, rule: &TimeframeRule, instrument_uid: &str, now: &Timestamp) -> bool {
+        let start = Timestamp { seconds: now.seconds - rule.window_size_min as i64 * 60, nanos: now.nanos };
+        let range = SizedRange::new(rule.interval, start, now.clone());
+        match &rule.condition {
+            TimeframeCondition::TrendBullish(trend_cfg) => self.is_trend_bullish(trend_cfg, instrument_uid, range).await,
+            TimeframeCondition::TrendBearish(trend_cfg) => self.is_trend_bearish(trend_cfg, instrument_uid, range).await,
+            TimeframeCondition::TrendFlat(trend_cfg) => self.is_trend_flat(trend_cfg, instrument_uid, range).await,
+            TimeframeCondition::TrendDirection(trend_cfg, direction) => self.get_trend(trend_cfg, instrument_uid, range).await
+                .map(|trend| trend.confirms(*direction, trend_cfg.min_strength))
+                .unwrap_or(false),
+            TimeframeCondition::HammerBullish(hammer_cfg) => match self.get_last_candle(instrument_uid, rule.interval).await {
+                Some(candle) => self.is_hammer_bullish(hammer_cfg, candle).await,
+                None => false
+            },
+            TimeframeCondition::HammerBearish(hammer_cfg) => match self.get_last_candle(instrument_uid, rule.interval).await {
+                Some(candle) => self.is_hammer_bearish(hammer_cfg, candle).await,
+                None => false
+            },
+            TimeframeCondition::VolumeSpike(volume_cfg) => self.is_volume_spike(volume_cfg, instrument_uid, range).await,
+        }
+    }
+
+    async fn check_timeframes(&self, multi_cfg: &MultiTimeframeCfg, instrument_uid: &str, now: &Timestamp) -> bool {
+        let mut results = Vec::new();
+        for rule in &multi_cfg.rules {
+            results.push(self.check_timeframe(rule, instrument_uid, now).await);
+        }
+        match multi_cfg.combine {
+            TimeframeCombine::All => results.iter().all(|res| *res),
+            TimeframeCombine::Any => results.iter().any(|res| *res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost_types::Timestamp;
+    use tinkoff_invest_api::tcs::{Candle, SubscriptionInterval};
+    use crate::analytics::trend::TrendDirection;
+    use crate::state::candle_state::{CandleState, CandleStateStatistic};
+    use crate::state::state::State;
+    use crate::trading_cfg::{HammerCfg, MultiTimeframeCfg, TimeframeCombine, TimeframeCondition, TimeframeRule, TrendCfg};
+    use crate::utils::candle::new_test_candle;
+
+    // молот на 1m берем только если на 5m нисходящий тренд
+    fn hammer_on_bearish_cfg() -> MultiTimeframeCfg {
+        MultiTimeframeCfg {
+            rules: vec![
+                TimeframeRule {
+                    interval: SubscriptionInterval::OneMinute,
+                    window_size_min: 1,
+                    condition: TimeframeCondition::HammerBullish(HammerCfg { bottom_start: 50, bottom_end: 70, up_start: 80, up_end: 100 }),
+                },
+                TimeframeRule {
+                    interval: SubscriptionInterval::FiveMinutes,
+                    window_size_min: 60,
+                    condition: TimeframeCondition::TrendDirection(
+                        TrendCfg { max_candle_skip: 1, adx_period: 14, swing_depth: 1, flat_slope_prc: 0.05, min_strength: 0.0 },
+                        TrendDirection::Bearish),
+                },
+            ],
+            combine: TimeframeCombine::All,
+        }
+    }
+
+    fn candle_5m(i: i64, close: f64) -> Candle {
+        let mut candle = new_test_candle(i * 5, close + 0.5, close + 1.0, close - 0.5, close, 100);
+        candle.interval = SubscriptionInterval::FiveMinutes as i32;
+        candle
+    }
+
+    fn state(closes_5m: &[f64], candle_1m: Candle) -> CandleState {
+        let state = CandleState::new();
+        for (i, close) in closes_5m.iter().enumerate() {
+            state.update(&candle_5m(i as i64, *close)).unwrap();
+        }
+        state.update(&candle_1m).unwrap();
+        state
+    }
+
+    #[tokio::test]
+    async fn test_hammer_on_bearish_trend() {
+        // пила вниз: каждый отскок не доходит до предыдущего максимума
+        let bearish = [110.0, 108.0, 109.0, 106.0, 107.0, 104.0, 105.0, 102.0, 103.0, 100.0];
+        let hammer = new_test_candle(50, 99.4, 101.0, 97.0, 101.0, 100);
+        let now = Timestamp { seconds: 50 * 60, nanos: 0 };
+        let state = state(&bearish, hammer);
+        let cfg = hammer_on_bearish_cfg();
+
+        assert!(state.check_timeframe(&cfg.rules[0], "test", &now).await);
+        assert!(state.check_timeframe(&cfg.rules[1], "test", &now).await);
+        assert!(state.check_timeframes(&cfg, "test", &now).await);
+    }
+
+    #[tokio::test]
+    async fn test_hammer_on_bullish_trend() {
+        let bullish = [100.0, 102.0, 101.0, 104.0, 103.0, 106.0, 105.0, 108.0, 107.0, 110.0];
+        let hammer = new_test_candle(50, 109.4, 111.0, 107.0, 111.0, 100);
+        let now = Timestamp { seconds: 50 * 60, nanos: 0 };
+        let state = state(&bullish, hammer);
+        let mut cfg = hammer_on_bearish_cfg();
+
+        // молот есть, но старший таймфрейм его не подтверждает
+        assert!(state.check_timeframe(&cfg.rules[0], "test", &now).await);
+        assert!(!state.check_timeframe(&cfg.rules[1], "test", &now).await);
+        assert!(!state.check_timeframes(&cfg, "test", &now).await);
+
+        cfg.combine = TimeframeCombine::Any;
+        assert!(state.check_timeframes(&cfg, "test", &now).await);
+    }
+}
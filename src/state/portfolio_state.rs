@@ -0,0 +1,67 @@
+use std::sync::RwLock;
+use tinkoff_invest_api::tcs::{MoneyValue, PortfolioResponse, Quotation, Share};
+use crate::state::state::State;
+use crate::utils::quotation::QuotationExtension;
+
+// последний снимок портфеля. Обновляется целиком по get_portfolio
+pub struct PortfolioState {
+    portfolio: RwLock<Option<PortfolioResponse>>,
+}
+
+pub trait PortfolioStateStatistic {
+    // стоимость всего портфеля
+    async fn get_equity(&self) -> Option<f64>;
+    // свободные рубли
+    async fn get_cash(&self) -> Option<f64>;
+    // отрицательное для шорта
+    async fn get_position_lots(&self, instrument: &Share) -> i64;
+    // (instrument_uid, штук) по всем акциям портфеля, отрицательное для шорта
+    async fn get_share_positions(&self) -> Vec<(String, i64)>;
+}
+
+impl State<PortfolioResponse> for PortfolioState {
+    fn new() -> Self {
+        PortfolioState { portfolio: RwLock::new(None) }
+    }
+    fn update(&self, event: &PortfolioResponse) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.portfolio.write().unwrap();
+        *state = Some(event.clone());
+        Ok(())
+    }
+}
+
+fn money_to_f(money: &Option<MoneyValue>) -> Option<f64> {
+    money.as_ref().map(|money| Quotation::from_money(money).to_f())
+}
+
+impl PortfolioStateStatistic for PortfolioState {
+    async fn get_equity(&self) -> Option<f64> {
+        let state = self.portfolio.read().unwrap();
+        state.as_ref().and_then(|portfolio| money_to_f(&portfolio.total_amount_portfolio))
+    }
+
+    async fn get_cash(&self) -> Option<f64> {
+        let state = self.portfolio.read().unwrap();
+        state.as_ref().and_then(|portfolio| money_to_f(&portfolio.total_amount_currencies))
+    }
+
+    async fn get_position_lots(&self, instrument: &Share) -> i64 {
+        let state = self.portfolio.read().unwrap();
+        state.as_ref()
+            .and_then(|portfolio| portfolio.positions.iter().find(|position| position.instrument_uid == instrument.uid))
+            .and_then(|position| position.quantity.clone())
+            .map(|quantity| quantity.units / instrument.lot.max(1) as i64)
+            .unwrap_or(0)
+    }
+
+    async fn get_share_positions(&self) -> Vec<(String, i64)> {
+        let state = self.portfolio.read().unwrap();
+        state.as_ref()
+            .map(|portfolio| portfolio.positions.iter()
+                .filter(|position| position.instrument_type == "share")
+                .map(|position| (position.instrument_uid.clone(), position.quantity.as_ref().map(|quantity| quantity.units).unwrap_or(0)))
+                .filter(|(_, units)| *units != 0)
+                .collect())
+            .unwrap_or_default()
+    }
+}
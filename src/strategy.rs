@@ -1,3 +1,16 @@
 pub mod first_strategy;
 pub mod strategy;
 pub mod hammer_strategy;
+pub mod bollinger_strategy;
+pub mod ma_crossover_strategy;
+pub mod donchian_strategy;
+pub mod grid_strategy;
+pub mod pair_strategy;
+pub mod script_strategy;
+pub mod rule_script;
+pub mod ensemble;
+pub mod exit_manager;
+pub mod position_sizer;
+pub mod session_guard;
+pub mod pattern_store;
+pub mod registry;
@@ -0,0 +1,84 @@
+use tinkoff_invest_api::tcs::{Quotation, Share, SubscriptionInterval};
+use crate::service::order_service::OrderService;
+use crate::state::candle_state::{CandleState, CandleStateStatistic};
+use crate::strategy::strategy::{ExitLevels, OpenedPattern, PatternRunner, PatternSignals, PositionDirection};
+use crate::trading_cfg::{BollingerStrategySettings, ExitCfg, VolatilityCfg};
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+// возврат к средней: покупка под нижней полосой Боллинджера при перепроданности RSI, продажа на средней полосе
+pub struct BollingerSignals {
+    settings: BollingerStrategySettings,
+}
+
+impl BollingerSignals {
+    pub fn new(settings: BollingerStrategySettings) -> Self {
+        Self { settings }
+    }
+}
+
+impl PatternSignals for BollingerSignals {
+    const NAME: &'static str = "BollingerStrategy";
+
+    fn exit_cfg(&self) -> Option<&ExitCfg> {
+        self.settings.exit_cfg.as_ref()
+    }
+
+    fn volatility_cfg(&self) -> Option<&VolatilityCfg> {
+        self.settings.volatility_cfg.as_ref()
+    }
+
+    async fn check_pattern<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, instrument: &Share, stat: &CandleState) -> Option<OpenedPattern> {
+        let range = runner.window_range(SubscriptionInterval::OneMinute, self.settings.window_size_min);
+        let last_candle = stat.get_last_candle(&instrument.uid, SubscriptionInterval::OneMinute).await?;
+        let bands = stat.get_bollinger(&self.settings.bollinger_cfg, &instrument.uid, range.clone()).await?;
+        let rsi = stat.get_rsi(&self.settings.rsi_cfg, &instrument.uid, range).await?;
+        let close = last_candle.close.clone().unwrap().to_f();
+        if close >= bands.lower || rsi > self.settings.rsi_cfg.oversold || bands.width_prc() < self.settings.bollinger_cfg.min_width_prc {
+            return None;
+        }
+        if let Some(volatility_cfg) = &self.settings.volatility_cfg {
+            let volatility = stat.get_volatility(volatility_cfg, &instrument.uid, runner.window_range(SubscriptionInterval::OneMinute, volatility_cfg.window_size_min)).await?;
+            if !volatility_cfg.allowed_regimes.contains(&volatility.regime) {
+                return None;
+            }
+        }
+        let atr = runner.current_atr(self.volatility_cfg(), self.interval()).await;
+        let stop_price = runner.stop_price(PositionDirection::Long, close, atr);
+        // чем глубже перепроданность, тем сильнее сигнал
+        let quantity = runner.lots(PositionDirection::Long, close, stop_price, 1.0 - rsi / self.settings.rsi_cfg.oversold).await;
+        if quantity == 0 {
+            return None;
+        }
+        Some(OpenedPattern {
+            figi: instrument.figi.clone(),
+            direction: PositionDirection::Long,
+            quantity,
+            price_open: None,
+            price_close: Some(Quotation::from_f(bands.middle)),
+            instrument_id: instrument.uid.clone(),
+            exit: ExitLevels::default(),
+        })
+    }
+
+    async fn signal_sell<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern> {
+        let mut close_request = Vec::new();
+        let last_price = match runner.current_price().await {
+            Some(price) => price,
+            None => return close_request,
+        };
+        let middle = stat.get_bollinger(&self.settings.bollinger_cfg, &runner.instrument.uid, runner.window_range(SubscriptionInterval::OneMinute, self.settings.window_size_min)).await
+            .map(|bands| bands.middle);
+        for order in &runner.opened_patterns {
+            let is_middle_reached = middle.map(|middle| last_price.to_f() >= middle).unwrap_or(false);
+            let is_exit = match &runner.exit_manager {
+                Some(exit_manager) => exit_manager.exit_reason(order, &last_price).is_some(),
+                None => false,
+            };
+            if is_middle_reached || is_exit {
+                close_request.push(order.clone());
+            }
+        }
+        close_request
+    }
+}
@@ -0,0 +1,130 @@
+use prost_types::Timestamp;
+use tinkoff_invest_api::tcs::{Share, SubscriptionInterval};
+use crate::service::order_service::OrderService;
+use crate::state::candle_state::{CandleState, CandleStateStatistic};
+use crate::strategy::strategy::{ExitLevels, OpenedPattern, PatternRunner, PatternSignals, PositionDirection};
+use crate::trading_cfg::{DonchianStrategySettings, ExitCfg, VolatilityCfg};
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+// пробой канала Дончиана: покупка при закрытии выше максимума entry_period свечей, продажа при закрытии ниже минимума exit_period свечей или по стопу.
+// С allow_short зеркально: шорт при закрытии ниже минимума entry_period свечей, выход выше максимума exit_period свечей
+pub struct DonchianSignals {
+    settings: DonchianStrategySettings,
+    // свеча, на которой уже входили -- пробой на ней держится до следующей свечи
+    last_entry_time: Option<Timestamp>,
+}
+
+impl DonchianSignals {
+    pub fn new(settings: DonchianStrategySettings) -> Self {
+        Self { settings, last_entry_time: None }
+    }
+}
+
+impl PatternSignals for DonchianSignals {
+    const NAME: &'static str = "DonchianStrategy";
+
+    fn exit_cfg(&self) -> Option<&ExitCfg> {
+        self.settings.exit_cfg.as_ref()
+    }
+
+    fn volatility_cfg(&self) -> Option<&VolatilityCfg> {
+        self.settings.volatility_cfg.as_ref()
+    }
+
+    // все окна стратегии -- на settings.interval
+    fn interval(&self) -> SubscriptionInterval {
+        self.settings.interval
+    }
+
+    fn allow_short(&self) -> bool {
+        self.settings.allow_short
+    }
+
+    async fn on_entry<O: OrderService, C: Clock>(&mut self, runner: &PatternRunner<O, C>) {
+        self.last_entry_time = runner.statistic.get_last_candle(&runner.instrument.uid, self.settings.interval).await
+            .and_then(|candle| candle.time);
+    }
+
+    async fn check_pattern<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, instrument: &Share, stat: &CandleState) -> Option<OpenedPattern> {
+        let last_candle = stat.get_last_candle(&instrument.uid, self.settings.interval).await?;
+        if last_candle.time.is_some() && last_candle.time == self.last_entry_time {
+            return None;
+        }
+        let range = runner.window_range(self.settings.interval, self.settings.window_size_min);
+        let channel = stat.get_donchian(self.settings.entry_period, &instrument.uid, range.clone()).await?;
+        let close = last_candle.close.clone()?.to_f();
+        let (direction, breakout_prc) = if close > channel.upper {
+            (PositionDirection::Long, (close - channel.upper) / channel.upper * 100.0)
+        } else if close < channel.lower && self.settings.allow_short {
+            (PositionDirection::Short, (channel.lower - close) / channel.lower * 100.0)
+        } else {
+            return None;
+        };
+        if let Some(volume_cfg) = &self.settings.volume_cfg {
+            // закрытие за пределами всего окна на объеме выше среднего
+            if !stat.is_breakout_volume_confirmed(volume_cfg, &instrument.uid, range.clone()).await {
+                return None;
+            }
+            // пока цена не ушла за point of control, она остается в зоне основного объема окна
+            let profile = stat.get_volume_profile(volume_cfg, &instrument.uid, range).await?;
+            let is_outside_poc = match direction {
+                PositionDirection::Long => close > profile.poc,
+                PositionDirection::Short => close < profile.poc,
+            };
+            if !is_outside_poc {
+                return None;
+            }
+            println!("Donchian breakout ticker={} direction={:?} close={} poc={} poc_share={:.2} total_volume={}",
+                     instrument.ticker, direction, close, profile.poc, profile.poc_share(), profile.total_volume);
+        }
+        // в узком рынке пробои чаще ложные
+        if let Some(volatility_cfg) = &self.settings.volatility_cfg {
+            let volatility = stat.get_volatility(volatility_cfg, &instrument.uid, runner.window_range(self.settings.interval, volatility_cfg.window_size_min)).await?;
+            if !volatility_cfg.allowed_regimes.contains(&volatility.regime) {
+                return None;
+            }
+        }
+        let price = runner.current_price().await?.to_f();
+        let atr = runner.current_atr(self.volatility_cfg(), self.interval()).await;
+        let stop_price = runner.stop_price(direction, price, atr);
+        // сила сигнала -- насколько закрытие ушло за канал, 1% считаем сильным сигналом
+        let quantity = runner.lots(direction, price, stop_price, breakout_prc).await;
+        if quantity == 0 {
+            return None;
+        }
+        Some(OpenedPattern {
+            figi: instrument.figi.clone(),
+            direction,
+            quantity,
+            price_open: None,
+            price_close: None,
+            instrument_id: instrument.uid.clone(),
+            exit: ExitLevels::default(),
+        })
+    }
+
+    async fn signal_sell<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern> {
+        let mut close_request = Vec::new();
+        let last_price = match runner.current_price().await {
+            Some(price) => price,
+            None => return close_request,
+        };
+        let exit_channel = stat.get_donchian(self.settings.exit_period, &runner.instrument.uid, runner.window_range(self.settings.interval, self.settings.window_size_min)).await;
+        for order in &runner.opened_patterns {
+            let is_channel_broken = match (&exit_channel, order.direction) {
+                (Some(channel), PositionDirection::Long) => last_price.to_f() < channel.lower,
+                (Some(channel), PositionDirection::Short) => last_price.to_f() > channel.upper,
+                (None, _) => false,
+            };
+            let is_exit = match &runner.exit_manager {
+                Some(exit_manager) => exit_manager.exit_reason(order, &last_price).is_some(),
+                None => false,
+            };
+            if is_channel_broken || is_exit {
+                close_request.push(order.clone());
+            }
+        }
+        close_request
+    }
+}
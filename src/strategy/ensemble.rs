@@ -0,0 +1,144 @@
+use crate::trading_cfg::EnsembleCfg;
+
+// голос одного детектора: score -1..1, плюс -- за покупку, минус -- за продажу; confidence 0-1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub score: f64,
+    pub confidence: f64,
+}
+
+impl Signal {
+    pub fn new(name: &str, score: f64, confidence: f64) -> Self {
+        Self { name: name.to_string(), score: score.clamp(-1.0, 1.0), confidence: confidence.clamp(0.0, 1.0) }
+    }
+
+    // условие-детектор: выполнено -- полный голос в сторону sign, нет -- воздерживается
+    pub fn from_check(name: &str, is_confirmed: bool, sign: f64) -> Self {
+        Self::new(name, if is_confirmed { sign } else { 0.0 }, 1.0)
+    }
+
+    fn vote(&self) -> f64 {
+        self.score * self.confidence
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnsembleDecision {
+    Buy,
+    Sell,
+    Hold,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnsembleScore {
+    // взвешенное среднее голосов, -1..1
+    pub score: f64,
+    pub decision: EnsembleDecision,
+    // детектор, отменивший решение по порогу
+    pub vetoed_by: Option<String>,
+    pub signals: Vec<Signal>,
+}
+
+impl EnsembleScore {
+    // 0-1 для PositionSizer
+    pub fn strength(&self) -> f64 {
+        self.score.abs().min(1.0)
+    }
+
+    // голоса детекторов одной строкой для логов: name=score*confidence
+    pub fn votes(&self) -> String {
+        self.signals.iter()
+            .map(|signal| format!("{}={}*{}", signal.name, signal.score, signal.confidence))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+pub fn combine(cfg: &EnsembleCfg, signals: Vec<Signal>) -> EnsembleScore {
+    let mut weighted_sum = 0.0;
+    let mut weights_sum = 0.0;
+    for signal in &signals {
+        let weight = cfg.weights.get(&signal.name).copied().unwrap_or(cfg.default_weight);
+        if weight <= 0.0 {
+            continue;
+        }
+        weighted_sum += weight * signal.vote();
+        weights_sum += weight;
+    }
+    let score = if weights_sum > 0.0 { weighted_sum / weights_sum } else { 0.0 };
+
+    let decision = if score > 0.0 && score >= cfg.buy_threshold {
+        EnsembleDecision::Buy
+    } else if score < 0.0 && -score >= cfg.sell_threshold {
+        EnsembleDecision::Sell
+    } else {
+        EnsembleDecision::Hold
+    };
+    let sign = match decision {
+        EnsembleDecision::Buy => 1.0,
+        EnsembleDecision::Sell => -1.0,
+        EnsembleDecision::Hold => 0.0,
+    };
+    let vetoed_by = cfg.vetoes.iter()
+        .find(|veto| signals.iter().any(|signal| signal.name == veto.signal && -sign * signal.vote() >= veto.min_against))
+        .filter(|_| decision != EnsembleDecision::Hold)
+        .map(|veto| veto.signal.clone());
+
+    EnsembleScore {
+        score,
+        decision: if vetoed_by.is_some() { EnsembleDecision::Hold } else { decision },
+        vetoed_by,
+        signals,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::strategy::ensemble::{combine, EnsembleDecision, Signal};
+    use crate::trading_cfg::{EnsembleCfg, VetoCfg};
+
+    fn cfg() -> EnsembleCfg {
+        EnsembleCfg {
+            weights: HashMap::from([("hammer".to_string(), 2.0), ("trend".to_string(), 1.0), ("noise".to_string(), 0.0)]),
+            default_weight: 1.0,
+            buy_threshold: 0.5,
+            sell_threshold: 0.5,
+            vetoes: vec![VetoCfg { signal: "timeframes".to_string(), min_against: 0.5 }],
+        }
+    }
+
+    #[test]
+    fn test_combine() {
+        // (2 * 1 + 1 * 0.5 + 1 * 0) / 4
+        let result = combine(&cfg(), vec![
+            Signal::new("hammer", 1.0, 1.0),
+            Signal::new("trend", 1.0, 0.5),
+            Signal::from_check("volume", false, 1.0),
+            Signal::new("noise", -1.0, 1.0),
+        ]);
+        assert!((result.score - 0.625).abs() < 1e-9);
+        assert_eq!(result.decision, EnsembleDecision::Buy);
+        assert!((result.strength() - 0.625).abs() < 1e-9);
+
+        let result = combine(&cfg(), vec![Signal::new("hammer", -1.0, 1.0), Signal::new("trend", 1.0, 1.0)]);
+        assert_eq!(result.decision, EnsembleDecision::Hold);
+        let result = combine(&cfg(), vec![Signal::new("hammer", -1.0, 1.0), Signal::new("trend", -1.0, 1.0)]);
+        assert_eq!(result.decision, EnsembleDecision::Sell);
+    }
+
+    #[test]
+    fn test_veto() {
+        // без вето (2 * 1 - 1 * 0.5) / 3 = 0.5 -- покупка
+        let result = combine(&cfg(), vec![Signal::new("hammer", 1.0, 1.0), Signal::new("timeframes", -1.0, 0.5)]);
+        assert_eq!(result.decision, EnsembleDecision::Hold);
+        assert_eq!(result.vetoed_by, Some("timeframes".to_string()));
+        assert_eq!(result.votes(), "hammer=1*1 timeframes=-1*0.5");
+
+        // голос за решение вето не включает
+        let result = combine(&cfg(), vec![Signal::new("hammer", 1.0, 1.0), Signal::new("timeframes", 1.0, 1.0)]);
+        assert_eq!(result.decision, EnsembleDecision::Buy);
+        assert_eq!(result.vetoed_by, None);
+    }
+}
@@ -0,0 +1,256 @@
+use tinkoff_invest_api::tcs::{Quotation, StopOrderDirection, StopOrderType};
+use crate::service::order_service::{OrderService, StopOrderRequest};
+use crate::strategy::strategy::{OpenedPattern, PositionDirection};
+use crate::trading_cfg::{ExitCfg, TrailingCfg};
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    StopLoss,
+    TakeProfit,
+}
+
+// стоп-лосс, тейк-профит, трейлинг и безубыток для открытых позиций. У шорта все уровни зеркальны: стоп выше цены, цель ниже
+pub struct ExitManager {
+    exit_cfg: ExitCfg,
+}
+
+impl ExitManager {
+    pub fn new(exit_cfg: ExitCfg) -> Self {
+        Self { exit_cfg }
+    }
+
+    // начальный стоп, нужен до открытия позиции для расчета ее размера
+    pub fn stop_price(&self, direction: PositionDirection, price_open: f64, atr: Option<f64>) -> Option<f64> {
+        let sign = direction.sign() as f64;
+        let stop_by_prc = self.exit_cfg.stop_loss_prc.map(|prc| price_open * (1.0 - sign * prc / 100.0));
+        let stop_by_atr = self.exit_cfg.stop_loss_atr.zip(atr).map(|(multiplier, atr)| price_open - sign * atr * multiplier);
+        match (stop_by_prc, stop_by_atr, direction) {
+            (Some(a), Some(b), PositionDirection::Long) => Some(a.max(b)),
+            (Some(a), Some(b), PositionDirection::Short) => Some(a.min(b)),
+            (a, b, _) => a.or(b),
+        }
+    }
+
+    // выставляет уровни по цене открытия. atr нужен только для настроек в ATR
+    pub fn attach(&self, pattern: &mut OpenedPattern, atr: Option<f64>) {
+        let price_open = match &pattern.price_open {
+            Some(price) => price.to_f(),
+            None => return,
+        };
+        let sign = pattern.direction.sign() as f64;
+        pattern.exit.price_stop = self.stop_price(pattern.direction, price_open, atr).map(Quotation::from_f);
+        if pattern.price_close.is_none() {
+            pattern.price_close = self.exit_cfg.take_profit_prc.map(|prc| Quotation::from_f(price_open * (1.0 + sign * prc / 100.0)));
+        }
+        pattern.exit.trailing_distance = match &self.exit_cfg.trailing {
+            Some(TrailingCfg::Percent(prc)) => Some(Quotation::from_f(price_open * prc / 100.0)),
+            Some(TrailingCfg::Atr(multiplier)) => atr.map(|atr| Quotation::from_f(atr * multiplier)),
+            None => None,
+        };
+        pattern.exit.price_best = pattern.price_open.clone();
+        // трейлинг сразу действует от цены открытия
+        let price_open = pattern.price_open.clone().unwrap();
+        self.update_levels(pattern, &price_open);
+    }
+
+    // двигает стоп трейлингом и в безубыток, возвращает true если стоп сдвинулся
+    pub fn update_levels(&self, pattern: &mut OpenedPattern, price: &Quotation) -> bool {
+        let price_open = match &pattern.price_open {
+            Some(price) => price.clone(),
+            None => return false,
+        };
+        let direction = pattern.direction;
+        if pattern.exit.price_best.as_ref().map(|best| is_better(direction, price, best)).unwrap_or(true) {
+            pattern.exit.price_best = Some(price.clone());
+        }
+
+        let mut new_stop = pattern.exit.price_stop.clone();
+        if let Some(break_even_prc) = self.exit_cfg.break_even_prc {
+            let profit_prc = direction.sign() as f64 * (price.to_f() - price_open.to_f()) / price_open.to_f() * 100.0;
+            if profit_prc >= break_even_prc {
+                new_stop = tighter_stop(direction, new_stop, price_open);
+            }
+        }
+        if let (Some(distance), Some(best)) = (&pattern.exit.trailing_distance, &pattern.exit.price_best) {
+            new_stop = tighter_stop(direction, new_stop, (best.wr() - distance.wr() * direction.sign()).uwr());
+        }
+
+        let is_moved = match (&pattern.exit.price_stop, &new_stop) {
+            (Some(old), Some(new)) => is_better(direction, new, old),
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        pattern.exit.price_stop = new_stop;
+        is_moved
+    }
+
+    pub fn exit_reason(&self, pattern: &OpenedPattern, price: &Quotation) -> Option<ExitReason> {
+        if pattern.exit.price_stop.as_ref().map(|stop| !is_better(pattern.direction, price, stop)).unwrap_or(false) {
+            Some(ExitReason::StopLoss)
+        } else if pattern.price_close.as_ref().map(|target| is_better(pattern.direction, price, target)).unwrap_or(false) {
+            Some(ExitReason::TakeProfit)
+        } else {
+            None
+        }
+    }
+
+    // переставляет стоп-заявку у брокера на текущий price_stop. Если брокер не поддерживает стопы -- стоп проверяется по тикам
+    pub async fn sync_broker_stop<O: OrderService>(&self, order_service: &mut O, pattern: &mut OpenedPattern) {
+        if !self.exit_cfg.use_broker_stops {
+            return;
+        }
+        self.cancel_broker_stop(order_service, pattern).await;
+        let stop_price = match &pattern.exit.price_stop {
+            Some(price) => price.clone(),
+            None => return,
+        };
+        match order_service.post_stop_order(StopOrderRequest {
+            instrument_id: pattern.instrument_id.clone(),
+            quantity: pattern.quantity,
+            price: None,
+            stop_price,
+            direction: match pattern.direction {
+                PositionDirection::Long => StopOrderDirection::Sell,
+                PositionDirection::Short => StopOrderDirection::Buy,
+            },
+            stop_order_type: StopOrderType::StopLoss,
+        }).await {
+            Ok(response) => pattern.exit.stop_order_id = Some(response.into_inner().stop_order_id),
+            Err(e) => eprintln!("Error while posting stop order for instrument_id={}: {}", pattern.instrument_id, e.message()),
+        }
+    }
+
+    // позиции, чьих стоп-заявок больше нет среди активных у брокера: стоп исполнился и закрыл позицию.
+    // Свой стоп перед отменой забывается, поэтому пропасть из списка заявка могла только по исполнению
+    pub async fn take_executed_stops<O: OrderService>(&self, order_service: &mut O, patterns: &mut Vec<OpenedPattern>) -> Vec<OpenedPattern> {
+        if patterns.iter().all(|pattern| pattern.exit.stop_order_id.is_none()) {
+            return Vec::new();
+        }
+        let active: Vec<String> = order_service.get_stop_orders().await.into_iter().map(|order| order.stop_order_id).collect();
+        let (executed, opened) = patterns.drain(..).partition(|pattern: &OpenedPattern| pattern.exit.stop_order_id.as_ref()
+            .map(|stop_order_id| !active.contains(stop_order_id))
+            .unwrap_or(false));
+        *patterns = opened;
+        executed
+    }
+
+    pub async fn cancel_broker_stop<O: OrderService>(&self, order_service: &mut O, pattern: &mut OpenedPattern) {
+        if let Some(stop_order_id) = pattern.exit.stop_order_id.take() {
+            if let Err(e) = order_service.cancel_stop_order(stop_order_id.clone()).await {
+                eprintln!("Error while cancel stop order={}: {}", stop_order_id, e.message());
+            }
+        }
+    }
+}
+
+// цена price выгоднее other для позиции: выше для лонга, ниже для шорта
+fn is_better(direction: PositionDirection, price: &Quotation, other: &Quotation) -> bool {
+    match direction {
+        PositionDirection::Long => price.wr() > other.wr(),
+        PositionDirection::Short => price.wr() < other.wr(),
+    }
+}
+
+// из двух стопов -- ближний к цене
+fn tighter_stop(direction: PositionDirection, stop: Option<Quotation>, other: Quotation) -> Option<Quotation> {
+    match stop {
+        Some(stop) if !is_better(direction, &other, &stop) => Some(stop),
+        _ => Some(other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::Quotation;
+    use crate::strategy::exit_manager::{ExitManager, ExitReason};
+    use crate::strategy::strategy::{ExitLevels, OpenedPattern, PositionDirection};
+    use crate::trading_cfg::{ExitCfg, TrailingCfg};
+    use crate::utils::quotation::QuotationExtension;
+
+    fn pattern(price_open: f64) -> OpenedPattern {
+        OpenedPattern {
+            figi: "test".to_string(),
+            direction: PositionDirection::Long,
+            quantity: 1,
+            price_open: Some(Quotation::from_f(price_open)),
+            price_close: None,
+            instrument_id: "test".to_string(),
+            exit: ExitLevels::default(),
+        }
+    }
+
+    #[test]
+    fn test_stop_and_take_profit() {
+        let exit_manager = ExitManager::new(ExitCfg {
+            stop_loss_prc: Some(2.0),
+            stop_loss_atr: Some(1.0),
+            take_profit_prc: Some(5.0),
+            trailing: None,
+            break_even_prc: None,
+            use_broker_stops: false,
+        });
+        let mut opened = pattern(100.0);
+        exit_manager.attach(&mut opened, Some(1.5));
+        // ATR-стоп 98.5 ближе к цене, чем 2% стоп 98
+        assert_eq!(opened.exit.price_stop.clone().unwrap().to_f(), 98.5);
+        assert_eq!(opened.price_close.clone().unwrap().to_f(), 105.0);
+
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(99.0)), None);
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(98.5)), Some(ExitReason::StopLoss));
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(105.5)), Some(ExitReason::TakeProfit));
+    }
+
+    #[test]
+    fn test_trailing_and_break_even() {
+        let exit_manager = ExitManager::new(ExitCfg {
+            stop_loss_prc: Some(3.0),
+            stop_loss_atr: None,
+            take_profit_prc: None,
+            trailing: Some(TrailingCfg::Percent(2.0)),
+            break_even_prc: Some(1.0),
+            use_broker_stops: false,
+        });
+        let mut opened = pattern(100.0);
+        exit_manager.attach(&mut opened, None);
+        assert_eq!(opened.exit.price_stop.clone().unwrap().to_f(), 98.0);
+
+        // безубыток важнее трейлинга, пока максимум ниже 102
+        assert!(exit_manager.update_levels(&mut opened, &Quotation::from_f(101.0)));
+        assert_eq!(opened.exit.price_stop.clone().unwrap().to_f(), 100.0);
+
+        assert!(exit_manager.update_levels(&mut opened, &Quotation::from_f(104.0)));
+        assert_eq!(opened.exit.price_stop.clone().unwrap().to_f(), 102.0);
+
+        // откат не двигает стоп вниз
+        assert!(!exit_manager.update_levels(&mut opened, &Quotation::from_f(103.0)));
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(101.9)), Some(ExitReason::StopLoss));
+    }
+
+    #[test]
+    fn test_short_levels() {
+        let exit_manager = ExitManager::new(ExitCfg {
+            stop_loss_prc: Some(2.0),
+            stop_loss_atr: Some(1.0),
+            take_profit_prc: Some(5.0),
+            trailing: Some(TrailingCfg::Percent(3.0)),
+            break_even_prc: None,
+            use_broker_stops: false,
+        });
+        let mut opened = pattern(100.0);
+        opened.direction = PositionDirection::Short;
+        exit_manager.attach(&mut opened, Some(1.5));
+        // у шорта стоп выше цены, ATR-стоп 101.5 ближе 2% стопа 102
+        assert_eq!(opened.exit.price_stop.clone().unwrap().to_f(), 101.5);
+        assert_eq!(opened.price_close.clone().unwrap().to_f(), 95.0);
+
+        // трейлинг от минимума: 97 + 3
+        assert!(exit_manager.update_levels(&mut opened, &Quotation::from_f(97.0)));
+        assert_eq!(opened.exit.price_stop.clone().unwrap().to_f(), 100.0);
+        assert!(!exit_manager.update_levels(&mut opened, &Quotation::from_f(98.0)));
+
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(99.0)), None);
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(100.0)), Some(ExitReason::StopLoss));
+        assert_eq!(exit_manager.exit_reason(&opened, &Quotation::from_f(94.5)), Some(ExitReason::TakeProfit));
+    }
+}
@@ -1,82 +1,205 @@
 use std::sync::{Arc, RwLock};
-use tinkoff_invest_api::tcs::{OrderType, PortfolioResponse, Quotation, Share};
-use crate::service::order_service::{OrderService, OrderServiceSandboxImpl};
+use tinkoff_invest_api::tcs::{PortfolioResponse, Quotation, Share};
+use crate::service::order_service::OrderService;
+use crate::service::order_tracker::OrderFill;
 use crate::state::last_price_state::{LastPriceState, LastPriceStateStatistic};
-use crate::strategy::strategy::{map_position_to_pattern, OpenedPattern, Strategy};
+use crate::strategy::exit_manager::ExitManager;
+use crate::strategy::pattern_store::PatternStore;
+use crate::strategy::position_sizer::PositionSizer;
+use crate::strategy::session_guard::{current_phase, SessionGuard, SessionPhase};
+use crate::strategy::strategy::{apply_fill, map_position_to_pattern, order_close, order_open, ExitLevels, FillEffect, OpenedPattern, OrderIntents, PatternOrder, PatternOrderTracker, PositionDirection, Strategy, StrategyComponents};
+use crate::trading_cfg::FirstStrategySettings;
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
 
 // стратегия -- купить дешевле, продать дороже. Для обкатки модели.
-pub struct FirstStrategy {
+pub struct FirstStrategy<O: OrderService, C: Clock> {
     statistic: Arc<LastPriceState>,
-    // order_service: Arc<OrderServiceImpl>,
-    order_service: OrderServiceSandboxImpl,
+    order_service: O,
+    clock: C,
     instruments: Vec<Share>,
     opened_patterns: RwLock<Vec<OpenedPattern>>,
+    exit_manager: Option<ExitManager>,
+    position_sizer: Option<PositionSizer>,
+    pattern_store: Option<PatternStore>,
+    session_guard: Option<SessionGuard>,
+    order_tracker: PatternOrderTracker,
+    order_intents: OrderIntents,
 }
 
-impl FirstStrategy {
-    pub fn new(statistic: Arc<LastPriceState>, order_service: OrderServiceSandboxImpl, instruments: Vec<Share>) -> Self {
-        Self { statistic, order_service, instruments, opened_patterns: RwLock::new(Vec::new()) }
+impl<O: OrderService, C: Clock> FirstStrategy<O, C> {
+    pub fn new(
+        statistic: Arc<LastPriceState>,
+        order_service: O,
+        clock: C,
+        components: StrategyComponents,
+        instruments: Vec<Share>,
+        settings: FirstStrategySettings,
+    ) -> Self {
+        let StrategyComponents { position_sizer, pattern_store, session_guard } = components;
+        let exit_manager = settings.exit_cfg.map(ExitManager::new);
+        Self { statistic, order_service, clock, instruments, opened_patterns: RwLock::new(Vec::new()), exit_manager, position_sizer, pattern_store, session_guard, order_tracker: PatternOrderTracker::default(), order_intents: OrderIntents::default() }
+    }
+
+    fn save_state(&mut self) {
+        if let Some(store) = &mut self.pattern_store {
+            store.save(&self.opened_patterns.read().unwrap())
+                .unwrap_or_else(|e| eprintln!("Error while saving FirstStrategy state: {}", e));
+        }
+    }
+
+    // позиции меняются только по исполнениям, стоп у брокера выставляется на набранное количество
+    async fn apply_fills(&mut self, fills: Vec<OrderFill<PatternOrder>>) {
+        for mut fill in fills {
+            if fill.price.is_none() {
+                fill.price = self.statistic.get_last_price(&fill.instrument_id).await;
+            }
+            let opened_patterns = self.opened_patterns.get_mut().unwrap();
+            let index = match apply_fill(opened_patterns, &fill) {
+                Some(FillEffect::Opened(index)) => {
+                    if let Some(exit_manager) = &self.exit_manager {
+                        exit_manager.attach(&mut opened_patterns[index], None);
+                    }
+                    index
+                }
+                Some(FillEffect::Increased(index)) => index,
+                Some(FillEffect::Reduced(index)) => {
+                    println!("Partially closed={:#?}", opened_patterns[index]);
+                    continue;
+                }
+                Some(FillEffect::Closed(pattern)) => {
+                    println!("GOT PROFIT={:?}-{:?}", pattern.price_open, fill.price);
+                    continue;
+                }
+                None => continue,
+            };
+            if let Some(exit_manager) = &self.exit_manager {
+                exit_manager.sync_broker_stop(&mut self.order_service, &mut opened_patterns[index]).await;
+            }
+        }
     }
 }
 
-impl Strategy for FirstStrategy {
+impl<O: OrderService, C: Clock> Strategy for FirstStrategy<O, C> {
     type Statistic = LastPriceState;
 
-    async fn warm_up(&self, positions: PortfolioResponse) -> Result<(), Box<dyn std::error::Error>> {
-        for position in positions.positions {
-            for instrument in &self.instruments {
-                if instrument.uid == position.instrument_uid {
-                    let mut _opened_patterns = self.opened_patterns.write().unwrap();
-                    println!("Warm_up={:#?}", position.clone());
-                    _opened_patterns.push(map_position_to_pattern(position.clone()));
+    fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse {
+        match &mut self.pattern_store {
+            Some(store) => {
+                let (restored, positions) = store.restore(positions, &self.instruments);
+                println!("Restored={:#?}", restored);
+                self.opened_patterns.write().unwrap().extend(restored);
+                positions
+            }
+            None => positions,
+        }
+    }
+
+    async fn warm_up(&mut self, mut positions: PortfolioResponse) -> Result<PortfolioResponse, Box<dyn std::error::Error>> {
+        let mut unclaimed = Vec::new();
+        for position in std::mem::take(&mut positions.positions) {
+            let instrument = match self.instruments.iter().find(|instrument| instrument.uid == position.instrument_uid) {
+                Some(instrument) => instrument,
+                None => {
+                    unclaimed.push(position);
+                    continue;
                 }
+            };
+            let quantity = position.quantity.clone().map(|quantity| quantity.units).unwrap_or(0);
+            // стратегия открывает только лонги целыми лотами, остальные позиции открыты не ей
+            if quantity <= 0 || quantity % instrument.lot.max(1) as i64 != 0 || position.blocked {
+                eprintln!("FirstStrategy doesn't recognise position, skip it: {:#?}", position);
+                unclaimed.push(position);
+                continue;
             }
+            let mut _opened_patterns = self.opened_patterns.write().unwrap();
+            println!("Warm_up={:#?}", position.clone());
+            let mut pattern = map_position_to_pattern(position, instrument.lot);
+            if let Some(exit_manager) = &self.exit_manager {
+                exit_manager.attach(&mut pattern, None);
+            }
+            _opened_patterns.push(pattern);
         }
-        Ok(())
+        self.save_state();
+        positions.positions = unclaimed;
+        Ok(positions)
     }
 
     async fn update(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let orders_to_buy = self.signal_buy(&self.statistic).await;
-        let orders_to_sell = self.signal_sell(&self.statistic).await;
+        let fills = self.order_tracker.poll(&mut self.order_service, self.clock.now().seconds).await;
+        self.apply_fills(fills).await;
+        if let Some(exit_manager) = &self.exit_manager {
+            for pattern in exit_manager.take_executed_stops(&mut self.order_service, self.opened_patterns.get_mut().unwrap()).await {
+                println!("Closed by broker stop={:#?}", pattern);
+            }
+        }
+        let phase = current_phase(&self.session_guard, &self.clock);
+        if !phase.is_trading() {
+            self.save_state();
+            return Ok(());
+        }
+        let orders_to_buy = if phase.allows_entry() { self.signal_buy(&self.statistic).await } else { Vec::new() };
         for order in orders_to_buy {
-            let order_response = self.order_service.order_buy(
-                order.figi.clone(),
-                order.instrument_id.clone(),
-                order.quantity.clone(),
-                order.price_open.clone(),
-                OrderType::Market,
-            ).await;
-            match order_response {
+            if self.order_tracker.is_pending(&order.instrument_id) {
+                continue;
+            }
+            match order_open(&mut self.order_service, &mut self.order_intents, &order).await {
                 Ok(response) => {
-                    let mut opened_patterns = self.opened_patterns.write().unwrap();
                     let _response = response.into_inner();
-                    println!("BUY executed_order_price={:#?}", _response.clone().executed_order_price);
-                    opened_patterns.push(order);
+                    println!("BUY order={} status={}", _response.order_id, _response.execution_report_status);
+                    let fill = self.order_tracker.track(&_response, PatternOrder::Open(order));
+                    self.apply_fills(fill.into_iter().collect()).await;
+                }
+                Err(e) => eprintln!("Error in orders_to_buy: {}", e.message())
+            }
+        }
+
+        if let Some(exit_manager) = &self.exit_manager {
+            let mut opened_patterns = self.opened_patterns.read().unwrap().clone();
+            for order in opened_patterns.iter_mut() {
+                if self.order_tracker.is_pending(&order.instrument_id) {
+                    continue;
+                }
+                if let Some(price) = self.statistic.get_last_price(&order.instrument_id).await {
+                    if exit_manager.update_levels(order, &price) {
+                        exit_manager.sync_broker_stop(&mut self.order_service, order).await;
+                    }
                 }
-                err => eprint!("Error in orders_to_buy")
             }
+            *self.opened_patterns.write().unwrap() = opened_patterns;
         }
 
-        let mut closed_orders_index = Vec::new();
+        let orders_to_sell = if phase == SessionPhase::Flatten { self.opened_patterns.read().unwrap().clone() } else { self.signal_sell(&self.statistic).await };
         for order in orders_to_sell {
+            if self.order_tracker.is_pending(&order.instrument_id) {
+                continue;
+            }
+            // стоп снимается до закрытия, иначе он может сработать вместе с ним
+            if let Some(exit_manager) = &self.exit_manager {
+                let opened_patterns = self.opened_patterns.get_mut().unwrap();
+                if let Some(opened) = opened_patterns.iter_mut().find(|x| x.instrument_id == order.instrument_id) {
+                    exit_manager.cancel_broker_stop(&mut self.order_service, opened).await;
+                }
+            }
             println!("order_to_sell={:#?}", order.clone());
-            let closed_order = self.order_service.order_sell(
-                order.figi.clone(),
-                order.instrument_id.clone(),
-                order.quantity.clone(),
-                order.price_open.clone(),
-                OrderType::Market,
-            ).await;
-            if closed_order.is_ok() {
-                let _closed_order = closed_order.unwrap().into_inner();
-                println!("GOT PROFIT={:?}-{:?}", &order.price_open, &_closed_order.executed_order_price);
-                let index_to_remove = self.opened_patterns.read().unwrap().iter().position(|x| x.instrument_id == order.instrument_id).unwrap();
-                closed_orders_index.push(index_to_remove);
+            match order_close(&mut self.order_service, &mut self.order_intents, &order).await {
+                Ok(response) => {
+                    let fill = self.order_tracker.track(&response.into_inner(), PatternOrder::Close(order));
+                    self.apply_fills(fill.into_iter().collect()).await;
+                }
+                Err(e) => {
+                    eprintln!("Error in orders_to_sell: {}", e.message());
+                    // закрытие не принято -- позиция снова под стопом у брокера
+                    if let Some(exit_manager) = &self.exit_manager {
+                        let opened_patterns = self.opened_patterns.get_mut().unwrap();
+                        if let Some(opened) = opened_patterns.iter_mut().find(|x| x.instrument_id == order.instrument_id) {
+                            exit_manager.sync_broker_stop(&mut self.order_service, opened).await;
+                        }
+                    }
+                }
             }
         }
-        for order_index in closed_orders_index {
-            self.opened_patterns.write().unwrap().remove(order_index);
-        }
+        self.save_state();
         Ok(())
     }
 
@@ -96,17 +219,29 @@ impl Strategy for FirstStrategy {
             units: 300,
             nano: 100,
         };
-        let curr_price = stat.get_last_price(&instrument.uid).await;
-        if curr_price.is_some() &&
-            self.opened_patterns.read().unwrap().is_empty() &&
-            curr_price.clone().unwrap().units >= min_target.units &&
-            curr_price.clone().unwrap().nano > min_target.nano {
+        let curr_price = stat.get_last_price(&instrument.uid).await?;
+        if self.opened_patterns.read().unwrap().is_empty() &&
+            curr_price.units >= min_target.units &&
+            curr_price.nano > min_target.nano {
+            let quantity = match &self.position_sizer {
+                Some(position_sizer) => {
+                    let price = curr_price.to_f();
+                    let stop_price = self.exit_manager.as_ref().and_then(|exit_manager| exit_manager.stop_price(PositionDirection::Long, price, None));
+                    position_sizer.lots(instrument, PositionDirection::Long, price, stop_price, 1.0, &self.clock.now()).await
+                }
+                None => 1
+            };
+            if quantity == 0 {
+                return None;
+            }
             Some(OpenedPattern {
                 figi: instrument.figi.clone(),
-                quantity: 1,
+                direction: PositionDirection::Long,
+                quantity,
                 price_open: None,
                 price_close: None,
                 instrument_id: instrument.clone().uid,
+                exit: ExitLevels::default(),
             })
         } else {
             None
@@ -119,10 +254,14 @@ impl Strategy for FirstStrategy {
         for order in _opened_patterns {
             let curr_price = stat.get_last_price(&order.instrument_id).await;
             match (curr_price, order.price_open.clone()) {
-                (Some(c_price), Some(o_price)) => {
-                    if c_price.units > o_price.units ||
-                        (c_price.units == o_price.units &&
-                            c_price.nano > o_price.nano) {
+                (Some(c_price), Some(_)) if self.exit_manager.is_some() => {
+                    if self.exit_manager.as_ref().unwrap().exit_reason(&order, &c_price).is_some() {
+                        close_request.push(order.clone());
+                    }
+                }
+                // в плюсе с учетом направления
+                (Some(c_price), Some(_)) => {
+                    if order.pnl(&c_price, 1).unwrap_or(0.0) > 0.0 {
                         close_request.push(order.clone());
                     }
                 }
@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use tinkoff_invest_api::tcs::{OrderDirection, OrderType, PortfolioResponse, Quotation, Share};
+use uuid::Uuid;
+use crate::service::order_service::OrderService;
+use crate::state::last_price_state::{LastPriceState, LastPriceStateStatistic};
+use crate::strategy::strategy::{OpenedPattern, Strategy};
+use crate::trading_cfg::GridStrategySettings;
+use crate::strategy::session_guard::{current_phase, SessionGuard};
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+#[derive(Debug, Clone)]
+pub struct GridOrder {
+    // 0 -- опорная цена, отрицательные уровни ниже нее
+    pub level: i64,
+    pub direction: OrderDirection,
+    // лоты заявки, встречная заявка выставляется на столько же
+    pub quantity: i64,
+}
+
+// цена уровня = anchor + level * step, округленная до шага цены инструмента
+#[derive(Debug, Clone)]
+pub struct GridState {
+    pub anchor: f64,
+    pub step: f64,
+    // активные заявки сетки по order_id
+    pub orders: HashMap<String, GridOrder>,
+}
+
+impl GridState {
+    pub fn new(anchor: f64, step_prc: f64, min_price_increment: &Quotation) -> Self {
+        let step = (anchor * step_prc / 100.0).max(min_price_increment.to_f());
+        GridState { anchor, step, orders: HashMap::new() }
+    }
+
+    pub fn level_price(&self, level: i64, min_price_increment: &Quotation) -> Quotation {
+        Quotation::from_f(self.anchor + level as f64 * self.step).round_to(min_price_increment)
+    }
+
+    pub fn level_of(&self, price: f64) -> i64 {
+        ((price - self.anchor) / self.step).round() as i64
+    }
+
+    pub fn has_order(&self, level: i64, direction: OrderDirection) -> bool {
+        self.orders.values().any(|order| order.level == level && order.direction == direction)
+    }
+}
+
+// сетка лимитных заявок: на исполнение покупки выставляется продажа уровнем выше, на исполнение продажи -- покупка уровнем ниже
+pub struct GridStrategy<O: OrderService, C: Clock> {
+    statistic: Arc<LastPriceState>,
+    order_service: O,
+    clock: C,
+    instrument: Share,
+    settings: GridStrategySettings,
+    grid: Option<GridState>,
+    last_orders_check: i64,
+    session_guard: Option<SessionGuard>,
+}
+
+impl<O: OrderService, C: Clock> GridStrategy<O, C> {
+    pub fn new(statistic: Arc<LastPriceState>, order_service: O, clock: C, session_guard: Option<SessionGuard>, instrument: Share, settings: GridStrategySettings) -> Self {
+        Self { statistic, order_service, clock, instrument, settings, grid: None, last_orders_check: 0, session_guard }
+    }
+
+    fn min_price_increment(&self) -> Quotation {
+        self.instrument.min_price_increment.clone().unwrap_or(Quotation { units: 0, nano: 10_000_000 })
+    }
+
+    async fn place_order(&mut self, level: i64, direction: OrderDirection, quantity: i64) {
+        let min_price_increment = self.min_price_increment();
+        let grid = match self.grid.as_mut() {
+            Some(grid) => grid,
+            None => return,
+        };
+        let price = grid.level_price(level, &min_price_increment);
+        let response = match direction {
+            OrderDirection::Buy => self.order_service.order_buy(
+                Uuid::new_v4().to_string(), self.instrument.figi.clone(), self.instrument.uid.clone(), quantity, Some(price.clone()), OrderType::Limit,
+            ).await,
+            _ => self.order_service.order_sell(
+                Uuid::new_v4().to_string(), self.instrument.figi.clone(), self.instrument.uid.clone(), quantity, Some(price.clone()), OrderType::Limit,
+            ).await,
+        };
+        match response {
+            Ok(response) => {
+                grid.orders.insert(response.into_inner().order_id, GridOrder { level, direction, quantity });
+            }
+            Err(e) => eprintln!("Error while placing grid order level={} price={:?}: {}", level, price, e.message()),
+        }
+    }
+}
+
+impl<O: OrderService, C: Clock> Strategy for GridStrategy<O, C> {
+    type Statistic = LastPriceState;
+
+    // позиции не нужны и отдаются дальше: купленное сеткой уже стоит в заявках на продажу, сетка восстанавливается по активным заявкам
+    async fn warm_up(&mut self, positions: PortfolioResponse) -> Result<PortfolioResponse, Box<dyn Error>> {
+        let orders: Vec<_> = self.order_service.get_orders().await.into_iter()
+            .filter(|order| order.instrument_uid == self.instrument.uid && order.order_type == OrderType::Limit as i32)
+            .collect();
+        let anchor = match orders.first().and_then(|order| order.initial_security_price.as_ref()) {
+            Some(price) => Quotation::from_money(price).to_f(),
+            None => return Ok(positions),
+        };
+        let mut grid = GridState::new(anchor, self.settings.step_prc, &self.min_price_increment());
+        let mut duplicates = Vec::new();
+        for order in orders {
+            let price = match &order.initial_security_price {
+                Some(price) => Quotation::from_money(price).to_f(),
+                None => continue,
+            };
+            let level = grid.level_of(price);
+            let direction = if order.direction == OrderDirection::Buy as i32 { OrderDirection::Buy } else { OrderDirection::Sell };
+            if grid.has_order(level, direction) {
+                duplicates.push(order.order_id);
+                continue;
+            }
+            grid.orders.insert(order.order_id, GridOrder { level, direction, quantity: order.lots_requested });
+        }
+        for order_id in duplicates {
+            if let Err(e) = self.order_service.cancel_order(order_id.clone()).await {
+                eprintln!("Error while cancel duplicate grid order={}: {}", order_id, e.message());
+            }
+        }
+        println!("Grid for ticker={} rebuilt from orders: {:#?}", self.instrument.ticker, grid);
+        self.grid = Some(grid);
+        Ok(positions)
+    }
+
+    // сетка живет заявками у брокера, поэтому к концу дня не закрывается, а только не строится вне разрешенного для входа времени
+    async fn update(&mut self) -> Result<(), Box<dyn Error>> {
+        let phase = current_phase(&self.session_guard, &self.clock);
+        if !phase.is_trading() {
+            return Ok(());
+        }
+        if self.grid.is_none() {
+            if !phase.allows_entry() {
+                return Ok(());
+            }
+            let price = match self.statistic.get_last_price(&self.instrument.uid).await {
+                Some(price) => price.round_to(&self.min_price_increment()).to_f(),
+                None => return Ok(()),
+            };
+            self.grid = Some(GridState::new(price, self.settings.step_prc, &self.min_price_increment()));
+            for level in 1..=self.settings.levels_count as i64 {
+                self.place_order(-level, OrderDirection::Buy, self.settings.lots_per_level).await;
+            }
+            self.last_orders_check = self.clock.now().seconds;
+            return Ok(());
+        }
+
+        let now = self.clock.now().seconds;
+        if now - self.last_orders_check < self.settings.orders_check_sec {
+            return Ok(());
+        }
+        self.last_orders_check = now;
+
+        // заявки, пропавшие из активных, считаем исполненными
+        let active: HashSet<String> = self.order_service.get_orders().await.into_iter()
+            .filter(|order| order.instrument_uid == self.instrument.uid)
+            .map(|order| order.order_id)
+            .collect();
+        let grid = self.grid.as_mut().unwrap();
+        let filled: Vec<String> = grid.orders.keys().filter(|order_id| !active.contains(*order_id)).cloned().collect();
+        let filled: Vec<GridOrder> = filled.iter().filter_map(|order_id| grid.orders.remove(order_id)).collect();
+        for order in filled {
+            match order.direction {
+                OrderDirection::Buy => self.place_order(order.level + 1, OrderDirection::Sell, order.quantity).await,
+                _ => self.place_order(order.level - 1, OrderDirection::Buy, order.quantity).await,
+            }
+        }
+        Ok(())
+    }
+
+    // заявки выставляются сеткой, а не по сигналам
+    async fn signal_buy(&self, _stat: &Self::Statistic) -> Vec<OpenedPattern> {
+        Vec::new()
+    }
+
+    async fn check_pattern(&self, _instrument: &Share, _stat: &Self::Statistic) -> Option<OpenedPattern> {
+        None
+    }
+
+    async fn signal_sell(&self, _stat: &Self::Statistic) -> Vec<OpenedPattern> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{OrderDirection, Quotation};
+    use crate::strategy::grid_strategy::{GridOrder, GridState};
+
+    #[test]
+    fn test_grid_levels() {
+        let increment = Quotation { units: 0, nano: 50_000_000 };
+        let mut grid = GridState::new(100.0, 0.33, &increment);
+        // шаг 0.33 округляется только в цене уровня
+        assert_eq!(grid.level_price(-1, &increment), Quotation { units: 99, nano: 650_000_000 });
+        assert_eq!(grid.level_price(2, &increment), Quotation { units: 100, nano: 650_000_000 });
+        assert_eq!(grid.level_of(99.65), -1);
+        assert_eq!(grid.level_of(100.70), 2);
+
+        grid.orders.insert("1".to_string(), GridOrder { level: -1, direction: OrderDirection::Buy, quantity: 1 });
+        assert!(grid.has_order(-1, OrderDirection::Buy));
+        assert!(!grid.has_order(-1, OrderDirection::Sell));
+    }
+}
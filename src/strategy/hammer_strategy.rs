@@ -1,131 +1,231 @@
-use std::error::Error;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-#[cfg(not(test))]
-use std::time::SystemTime;
-#[cfg(test)]
-use mock_instant::SystemTime;
-use prost_types::Timestamp;
-use tinkoff_invest_api::tcs::{OrderType, PortfolioResponse, Quotation, Share, SubscriptionInterval};
-use crate::service::order_service::{OrderService, OrderServiceHistBoxImpl};
-use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
-use crate::state::last_price_state::LastPriceState;
-use crate::strategy::strategy::{OpenedPattern, Strategy};
-use crate::trading_cfg::HammerStrategySettings;
+use tinkoff_invest_api::tcs::{Quotation, Share, SubscriptionInterval};
+use crate::analytics::levels::{get_nearest_levels, NearestLevels};
+use crate::analytics::trend::TrendDirection;
+use crate::service::order_service::OrderService;
+use crate::state::candle_state::{CandleState, CandleStateStatistic};
+use crate::state::last_price_state::LastPriceStateStatistic;
+use crate::strategy::ensemble::{combine, EnsembleDecision, Signal};
+use crate::strategy::strategy::{ExitLevels, OpenedPattern, PatternRunner, PatternSignals, PositionDirection};
+use crate::trading_cfg::{ExitCfg, HammerStrategySettings, VolatilityCfg};
 use crate::utils::candle::CandleExtension;
+use crate::utils::clock::Clock;
 use crate::utils::quotation::QuotationExtension;
-use crate::utils::wrapper_mock_system_time::WrapperMockSystemTime;
 
-pub struct HammerStrategy {
-    statistic: Arc<CandleState>,
-    order_service: Arc<RwLock<OrderServiceHistBoxImpl>>, // Arc<RwLock<>> just for hist training
-    instrument: Share,
-    opened_patterns: Vec<OpenedPattern>,
+pub struct HammerSignals {
     settings: HammerStrategySettings,
 }
 
-impl HammerStrategy {
-    pub fn new(
-        statistic: Arc<CandleState>,
-        order_service: Arc<RwLock<OrderServiceHistBoxImpl>>,
-        instrument: Share,
-        settings: HammerStrategySettings,
-    ) -> Self {
-        Self { statistic, order_service, instrument, opened_patterns: Vec::new(), settings }
+impl HammerSignals {
+    pub fn new(settings: HammerStrategySettings) -> Self {
+        Self { settings }
     }
-}
-
-impl Strategy for HammerStrategy {
-    type Statistic = CandleState;
 
-    async fn warm_up(&self, positions: PortfolioResponse) -> Result<(), Box<dyn Error>> {
-        todo!()
+    // последняя цена, если ее еще нет -- по последней свече
+    async fn current_price<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>) -> Option<Quotation> {
+        match runner.last_price_state.get_last_price(&runner.instrument.uid).await {
+            Some(price) => Some(price),
+            None => runner.statistic.get_last_candle(&runner.instrument.uid, SubscriptionInterval::OneMinute).await
+                .map(|candle| if candle.is_bullish() { candle.close.unwrap() } else { candle.open.unwrap() }),
+        }
     }
 
-    async fn update(&mut self) -> Result<(), Box<dyn Error>> {
-        let orders_to_buy = self.signal_buy(&self.statistic).await;
-        let orders_to_sell = self.signal_sell(&self.statistic).await;
-        let mut _order_service = self.order_service.write().unwrap();
-        for order in orders_to_buy {
-            println!("aer order={:#?}", order);
-            let order_response = _order_service.order_buy(
-                order.figi.clone(),
-                order.instrument_id.clone(),
-                order.quantity.clone(),
-                None,
-                OrderType::Market,
-            ).await;
-            match order_response {
-                Ok(_) => {
-                    self.opened_patterns.push(order);
+    // лонг -- бычий молот в нисходящем тренде, шорт -- медвежий молот в восходящем. Уровни шорта зеркальны
+    async fn check_hammer<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState, direction: PositionDirection) -> Option<OpenedPattern> {
+        let range = runner.window_range(SubscriptionInterval::OneMinute, self.settings.window_size_min);
+        let trend_direction = match direction {
+            PositionDirection::Long => TrendDirection::Bearish,
+            PositionDirection::Short => TrendDirection::Bullish,
+        };
+        let trend = stat.get_trend(&self.settings.trend_cfg, &runner.instrument.uid, range).await;
+        let is_trend = trend.as_ref()
+            .map(|trend| trend.confirms(trend_direction, self.settings.trend_cfg.min_strength))
+            .unwrap_or(false);
+        let trend_strength = trend.map(|trend| trend.strength()).unwrap_or(0.0);
+        let last_candle = stat.get_last_candle(&runner.instrument.uid, SubscriptionInterval::OneMinute).await?;
+        let is_hammer = match direction {
+            PositionDirection::Long => stat.is_hammer_bullish(&self.settings.hammer_cfg, last_candle.clone()).await,
+            PositionDirection::Short => stat.is_hammer_bearish(&self.settings.hammer_cfg, last_candle.clone()).await,
+        };
+        let is_volume_confirmed = match &self.settings.volume_cfg {
+            Some(volume_cfg) => {
+                let volume_range = runner.window_range(SubscriptionInterval::OneMinute, volume_cfg.avg_period as u64 + 1);
+                stat.get_relative_volume(volume_cfg, &runner.instrument.uid, volume_range).await
+                    .map(|ratio| ratio >= volume_cfg.confirm_ratio)
+                    .unwrap_or(false) &&
+                    stat.is_trend_volume_confirmed(volume_cfg, &runner.instrument.uid, runner.window_range(SubscriptionInterval::OneMinute, self.settings.window_size_min), trend_direction).await
+            }
+            None => true
+        };
+        let confirmation = match direction {
+            PositionDirection::Long => &self.settings.confirmation,
+            PositionDirection::Short => &self.settings.short_confirmation,
+        };
+        let is_timeframes_confirmed = match confirmation {
+            Some(multi_cfg) => stat.check_timeframes(multi_cfg, &runner.instrument.uid, &runner.clock.now()).await,
+            None => true
+        };
+        let sign = direction.sign() as f64;
+        let signal_strength = match &self.settings.ensemble_cfg {
+            Some(ensemble_cfg) => {
+                let ensemble = combine(ensemble_cfg, vec![
+                    Signal::from_check("hammer", is_hammer, sign),
+                    // ADX 25 -- граница сильного тренда, r2 уже 0-1
+                    Signal::new("trend", if is_trend { sign } else { 0.0 }, trend_strength / 25.0),
+                    Signal::from_check("volume", is_volume_confirmed, sign),
+                    // старшие таймфреймы без подтверждения голосуют против, по ним удобно ставить вето
+                    Signal::new("timeframes", if is_timeframes_confirmed { sign } else { -sign }, 1.0),
+                ]);
+                let expected = match direction {
+                    PositionDirection::Long => EnsembleDecision::Buy,
+                    PositionDirection::Short => EnsembleDecision::Sell,
+                };
+                if let Some(veto) = &ensemble.vetoed_by {
+                    println!("Ensemble ticker={} direction={:?} vetoed by {}: {}", runner.instrument.ticker, direction, veto, ensemble.votes());
                 }
-                Err(e) => eprintln!("Error in orders_to_buy: {}", e.message())
+                if ensemble.decision != expected {
+                    return None;
+                }
+                println!("Ensemble ticker={} direction={:?} score={}: {}", runner.instrument.ticker, direction, ensemble.score, ensemble.votes());
+                ensemble.strength()
+            }
+            None => {
+                if !(is_trend && is_hammer && is_volume_confirmed && is_timeframes_confirmed) {
+                    return None;
+                }
+                // ADX 25 -- граница сильного тренда, r2 уже 0-1
+                trend_strength / 25.0
+            }
+        };
+        let high = last_candle.high.clone().unwrap().to_f();
+        let low = last_candle.low.clone().unwrap().to_f();
+        // цель откладывается от дальнего по направлению конца свечи
+        let extreme = match direction {
+            PositionDirection::Long => high,
+            PositionDirection::Short => low,
+        };
+        let mut close_price = Quotation::from_f(extreme + sign * (high - low) * 2.0);
+        if let Some(volatility_cfg) = &self.settings.volatility_cfg {
+            match stat.get_volatility(volatility_cfg, &runner.instrument.uid, runner.window_range(SubscriptionInterval::OneMinute, volatility_cfg.window_size_min)).await {
+                Some(volatility) if volatility_cfg.allowed_regimes.contains(&volatility.regime) => {
+                    close_price = Quotation::from_f(extreme + sign * volatility.atr * volatility_cfg.target_atr_multiplier);
+                }
+                _ => return None,
             }
         }
-        let mut closed_orders_index = Vec::new();
-        for order in orders_to_sell {
-            let closed_order = _order_service.order_sell(
-                order.figi.clone(),
-                order.instrument_id.clone(),
-                order.quantity.clone(),
-                None,
-                OrderType::Market,
-            ).await;
-            if closed_order.is_ok() {
-                let _closed_order = closed_order.unwrap().into_inner();
-                let index_to_remove = self.opened_patterns.iter().position(|x| x.instrument_id == order.instrument_id).unwrap();
-                closed_orders_index.push(index_to_remove);
+        if let Some(levels_cfg) = &self.settings.levels_cfg {
+            // уровни относительно текущей цены, по ней и будет вход
+            let nearest = get_nearest_levels(stat, &runner.last_price_state, levels_cfg, &runner.instrument.uid, runner.window_range(SubscriptionInterval::OneMinute, levels_cfg.window_size_min)).await?;
+            // молот должен отбиться от поддержки, медвежий -- от сопротивления
+            let (base, target) = match direction {
+                PositionDirection::Long => (nearest.support.filter(|support| support.is_near(low, levels_cfg.cluster_prc)), nearest.resistance),
+                PositionDirection::Short => (nearest.resistance.filter(|resistance| resistance.is_near(high, levels_cfg.cluster_prc)), nearest.support),
+            };
+            let base = base?;
+            println!("Hammer ticker={} direction={:?} from level price={} touches={} volume={} last_touch={}",
+                     runner.instrument.ticker, direction, base.price, base.touches, base.volume, base.last_touch.seconds);
+            if let Some(target) = target {
+                close_price = Quotation::from_f(target.price);
             }
         }
-        for order_index in closed_orders_index {
-            self.opened_patterns.remove(order_index);
+        let quantity = match &runner.position_sizer {
+            Some(_) => {
+                let price = self.current_price(runner).await?.to_f();
+                let atr = runner.current_atr(self.volatility_cfg(), self.interval()).await;
+                runner.lots(direction, price, runner.stop_price(direction, price, atr), signal_strength).await
+            }
+            None => 1
+        };
+        if quantity == 0 {
+            return None;
         }
-        Ok(())
+        Some(OpenedPattern {
+            figi: runner.instrument.figi.clone(),
+            direction,
+            quantity,
+            price_open: None,
+            price_close: Some(close_price),
+            instrument_id: runner.instrument.uid.clone(),
+            exit: ExitLevels::default(),
+        })
     }
+}
 
-    async fn signal_buy(&self, stat: &Self::Statistic) -> Vec<OpenedPattern> {
-        let mut to_buy = Vec::new();
-        let range = if cfg!(test) { // for hist training
-            let window_time_end = WrapperMockSystemTime(mock_instant::SystemTime::now());
-            let window_time_start = WrapperMockSystemTime(window_time_end.0 - Duration::from_secs(self.settings.window_size_min * 60));
-            SizedRange::new_1m(Timestamp::from(window_time_start), Timestamp::from(window_time_end))
-        } else {
-            let window_time_end = std::time::SystemTime::now();
-            let window_time_start = window_time_end - Duration::from_secs(self.settings.window_size_min * 60);
-            SizedRange::new_1m(Timestamp::from(window_time_start), Timestamp::from(window_time_end))
-        };
+impl PatternSignals for HammerSignals {
+    const NAME: &'static str = "HammerStrategy";
+
+    fn exit_cfg(&self) -> Option<&ExitCfg> {
+        self.settings.exit_cfg.as_ref()
+    }
+
+    fn volatility_cfg(&self) -> Option<&VolatilityCfg> {
+        self.settings.volatility_cfg.as_ref()
+    }
+
+    fn allow_short(&self) -> bool {
+        self.settings.allow_short
+    }
 
-        let is_trend_bearish = stat.is_trend_bearish(&self.settings.trend_cfg, &self.instrument.uid, range).await;
-        let last_candle = stat.get_last_candle(&self.instrument.uid, SubscriptionInterval::OneMinute).await.unwrap();
-        let is_hammer_bullish = stat.is_hammer_bullish(&self.settings.hammer_cfg, last_candle.clone()).await;
-        if is_trend_bearish && is_hammer_bullish {
-            let close_price = (last_candle.high.clone().unwrap().wr() + (last_candle.high.clone().unwrap().wr() - last_candle.low.clone().unwrap().wr()) * 2).uwr();
-            to_buy.push(OpenedPattern {
-                figi: self.instrument.figi.clone(),
-                quantity: 1, // fixme more quantity if it's more powerful signal
-                price_open: None,
-                price_close: Some(close_price),
-                instrument_id: self.instrument.uid.clone(),
-            });
+    // цель для позиции, открытой до запуска: ближайшее сопротивление (поддержка для шорта), иначе ATR-цель от цены открытия.
+    // Свеч после старта может еще не быть, тогда остаются только уровни ExitManager в процентах
+    async fn restore_target<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, direction: PositionDirection, price_open: f64) -> Option<Quotation> {
+        if let Some(levels_cfg) = &self.settings.levels_cfg {
+            let levels = runner.statistic.get_levels(levels_cfg, &runner.instrument.uid, runner.window_range(SubscriptionInterval::OneMinute, levels_cfg.window_size_min)).await
+                .unwrap_or_default();
+            let nearest = NearestLevels::new(&levels, price_open);
+            let target = match direction {
+                PositionDirection::Long => nearest.resistance,
+                PositionDirection::Short => nearest.support,
+            };
+            if let Some(target) = target {
+                return Some(Quotation::from_f(target.price));
+            }
+        }
+        let target = match &self.settings.volatility_cfg {
+            Some(volatility_cfg) => runner.current_atr(Some(volatility_cfg), self.interval()).await
+                .filter(|atr| *atr > 0.0)
+                .map(|atr| Quotation::from_f(price_open + direction.sign() as f64 * atr * volatility_cfg.target_atr_multiplier)),
+            None => None,
+        };
+        // без ExitManager позиция закрывается только по цели
+        if target.is_none() && self.settings.exit_cfg.is_none() {
+            eprintln!("Can't restore exit levels for instrument_id={}, position will not be closed", runner.instrument.uid);
         }
+        target
+    }
+
+    // позиций может быть несколько, пока заявка по инструменту не завершена, runner новую не выставит
+    async fn signal_buy<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern> {
+        let mut to_buy = Vec::new();
+        to_buy.extend(self.check_pattern(runner, &runner.instrument, stat).await);
         to_buy
     }
 
-    async fn check_pattern(&self, instrument: &Share, stat: &Self::Statistic) -> Option<OpenedPattern> {
-        None
+    // сначала лонг, шорт -- только если лонга нет и он разрешен
+    async fn check_pattern<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, instrument: &Share, stat: &CandleState) -> Option<OpenedPattern> {
+        if instrument.uid != runner.instrument.uid {
+            return None;
+        }
+        match self.check_hammer(runner, stat, PositionDirection::Long).await {
+            Some(pattern) => Some(pattern),
+            None if self.settings.allow_short => self.check_hammer(runner, stat, PositionDirection::Short).await,
+            None => None,
+        }
     }
 
-    // fixme look at last price for faster sell
-    async fn signal_sell(&self, stat: &Self::Statistic) -> Vec<OpenedPattern> {
+    async fn signal_sell<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, _stat: &CandleState) -> Vec<OpenedPattern> {
         let mut close_request = Vec::new();
-        let last_candle = stat.get_last_candle(&self.instrument.uid, SubscriptionInterval::OneMinute).await.unwrap();
-        let last_price = if last_candle.is_bullish() {
-            last_candle.close.unwrap()
-        } else {
-            last_candle.open.unwrap()
+        let last_price = match self.current_price(runner).await {
+            Some(price) => price,
+            None => return close_request,
         };
-        for order in &self.opened_patterns {
-            if last_price.wr() > order.clone().price_close.unwrap().wr() {
+        for order in &runner.opened_patterns {
+            let is_exit = match &runner.exit_manager {
+                Some(exit_manager) => exit_manager.exit_reason(order, &last_price).is_some(),
+                None => order.price_close.as_ref()
+                    .map(|price_close| (last_price.to_f() - price_close.to_f()) * order.direction.sign() as f64 > 0.0)
+                    .unwrap_or(false),
+            };
+            if is_exit {
                 close_request.push(order.clone());
             }
         }
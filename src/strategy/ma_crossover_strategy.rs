@@ -0,0 +1,98 @@
+use prost_types::Timestamp;
+use tinkoff_invest_api::tcs::{Share, SubscriptionInterval};
+use crate::service::order_service::OrderService;
+use crate::state::candle_state::{CandleState, CandleStateStatistic};
+use crate::strategy::strategy::{ExitLevels, OpenedPattern, PatternRunner, PatternSignals, PositionDirection};
+use crate::trading_cfg::{ExitCfg, MaCrossoverStrategySettings, VolatilityCfg};
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+// покупка при пересечении медленной средней быстрой снизу вверх, продажа при обратном пересечении или по стопу
+pub struct MaCrossoverSignals {
+    settings: MaCrossoverStrategySettings,
+    // свеча, на которой уже входили -- пересечение на ней держится до следующей свечи
+    last_entry_time: Option<Timestamp>,
+}
+
+impl MaCrossoverSignals {
+    pub fn new(settings: MaCrossoverStrategySettings) -> Self {
+        Self { settings, last_entry_time: None }
+    }
+}
+
+impl PatternSignals for MaCrossoverSignals {
+    const NAME: &'static str = "MaCrossoverStrategy";
+
+    fn exit_cfg(&self) -> Option<&ExitCfg> {
+        self.settings.exit_cfg.as_ref()
+    }
+
+    fn volatility_cfg(&self) -> Option<&VolatilityCfg> {
+        self.settings.volatility_cfg.as_ref()
+    }
+
+    // все окна стратегии -- на settings.interval
+    fn interval(&self) -> SubscriptionInterval {
+        self.settings.interval
+    }
+
+    async fn on_entry<O: OrderService, C: Clock>(&mut self, runner: &PatternRunner<O, C>) {
+        self.last_entry_time = runner.statistic.get_last_candle(&runner.instrument.uid, self.settings.interval).await
+            .and_then(|candle| candle.time);
+    }
+
+    async fn check_pattern<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, instrument: &Share, stat: &CandleState) -> Option<OpenedPattern> {
+        let last_candle = stat.get_last_candle(&instrument.uid, self.settings.interval).await?;
+        if last_candle.time.is_some() && last_candle.time == self.last_entry_time {
+            return None;
+        }
+        let crossover = stat.get_ma_crossover(&self.settings.ma_cfg, &instrument.uid, runner.window_range(self.settings.interval, self.settings.window_size_min)).await?;
+        if crossover.cross != 1 {
+            return None;
+        }
+        if let Some(multi_cfg) = &self.settings.confirmation {
+            if !stat.check_timeframes(multi_cfg, &instrument.uid, &runner.clock.now()).await {
+                return None;
+            }
+        }
+        let price = runner.current_price().await?.to_f();
+        let atr = runner.current_atr(self.volatility_cfg(), self.interval()).await;
+        let stop_price = runner.stop_price(PositionDirection::Long, price, atr);
+        // сила сигнала -- насколько разошлись средние, 1% считаем сильным сигналом
+        let spread_prc = (crossover.fast - crossover.slow) / crossover.slow * 100.0;
+        let quantity = runner.lots(PositionDirection::Long, price, stop_price, spread_prc).await;
+        if quantity == 0 {
+            return None;
+        }
+        Some(OpenedPattern {
+            figi: instrument.figi.clone(),
+            direction: PositionDirection::Long,
+            quantity,
+            price_open: None,
+            price_close: None,
+            instrument_id: instrument.uid.clone(),
+            exit: ExitLevels::default(),
+        })
+    }
+
+    async fn signal_sell<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern> {
+        let mut close_request = Vec::new();
+        let last_price = match runner.current_price().await {
+            Some(price) => price,
+            None => return close_request,
+        };
+        let is_cross_down = stat.get_ma_crossover(&self.settings.ma_cfg, &runner.instrument.uid, runner.window_range(self.settings.interval, self.settings.window_size_min)).await
+            .map(|crossover| crossover.fast < crossover.slow)
+            .unwrap_or(false);
+        for order in &runner.opened_patterns {
+            let is_exit = match &runner.exit_manager {
+                Some(exit_manager) => exit_manager.exit_reason(order, &last_price).is_some(),
+                None => false,
+            };
+            if is_cross_down || is_exit {
+                close_request.push(order.clone());
+            }
+        }
+        close_request
+    }
+}
@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::sync::Arc;
+use prost_types::Timestamp;
+use tinkoff_invest_api::tcs::{PortfolioResponse, Quotation, Share, SubscriptionInterval};
+use crate::analytics::correlation::SpreadStats;
+use crate::service::order_service::OrderService;
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
+use crate::strategy::pattern_store::PatternStore;
+use crate::strategy::session_guard::{current_phase, SessionGuard, SessionPhase};
+use crate::strategy::strategy::{map_position_to_pattern, order_close, order_open, ExitLevels, OpenedPattern, OrderIntents, PositionDirection, Strategy, StrategyComponents};
+use crate::trading_cfg::PairStrategySettings;
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+// обе ноги пары -- одна позиция: открываются и закрываются вместе
+#[derive(Debug, Clone)]
+pub struct PairPosition {
+    pub long: OpenedPattern,
+    pub short: OpenedPattern,
+    pub hedge_ratio: f64,
+    // z-score при открытии, по знаку видно, какая нога была переоценена
+    pub zscore_open: f64,
+}
+
+// z-score спреда ln(first) - hedge_ratio * ln(second): выше entry -- первый дорог, продаем первый и покупаем второй, ниже -entry -- наоборот
+pub struct PairStrategy<O: OrderService, C: Clock> {
+    statistic: Arc<CandleState>,
+    order_service: O,
+    clock: C,
+    first: Share,
+    second: Share,
+    settings: PairStrategySettings,
+    position: Option<PairPosition>,
+    // ноги из PatternStore, пара из них складывается в warm_up
+    restored: Vec<OpenedPattern>,
+    pattern_store: Option<PatternStore>,
+    session_guard: Option<SessionGuard>,
+    order_intents: OrderIntents,
+}
+
+impl<O: OrderService, C: Clock> PairStrategy<O, C> {
+    // ноги пары заданы settings.lots и hedge ratio, position_sizer из components не используется
+    pub fn new(statistic: Arc<CandleState>, order_service: O, clock: C, components: StrategyComponents, first: Share, second: Share, settings: PairStrategySettings) -> Self {
+        Self { statistic, order_service, clock, first, second, settings, position: None, restored: Vec::new(), pattern_store: components.pattern_store, session_guard: components.session_guard, order_intents: OrderIntents::default() }
+    }
+
+    // пара сохраняется как две ноги, hedge ratio пересчитывается при восстановлении
+    fn save_state(&mut self) {
+        let legs = match &self.position {
+            Some(position) => vec![position.long.clone(), position.short.clone()],
+            None => Vec::new(),
+        };
+        if let Some(store) = &mut self.pattern_store {
+            store.save(&legs).unwrap_or_else(|e| eprintln!("Error while saving PairStrategy state: {}", e));
+        }
+    }
+
+    fn window_range(&self) -> SizedRange {
+        let window_time_end = self.clock.now();
+        let window_time_start = Timestamp { seconds: window_time_end.seconds - self.settings.window_size_min as i64 * 60, nanos: window_time_end.nanos };
+        SizedRange::new_1m(window_time_start, window_time_end)
+    }
+
+    async fn spread(&self, stat: &CandleState) -> Option<SpreadStats> {
+        stat.get_spread(self.settings.zscore_window, &self.first.uid, &self.second.uid, self.window_range()).await
+    }
+
+    async fn last_close(&self, stat: &CandleState, instrument: &Share) -> Option<f64> {
+        stat.get_last_candle(&instrument.uid, SubscriptionInterval::OneMinute).await
+            .and_then(|candle| candle.close)
+            .map(|close| close.to_f())
+    }
+
+    // ноги примерно равны по деньгам с поправкой на hedge ratio
+    async fn check_pair(&self, stat: &CandleState) -> Option<PairPosition> {
+        let spread = self.spread(stat).await?;
+        // при отрицательном hedge ratio обе ноги были бы в одну сторону, это уже не пара
+        if spread.hedge_ratio <= 0.0 || spread.zscore.abs() < self.settings.entry_zscore || spread.zscore.abs() >= self.settings.stop_zscore {
+            return None;
+        }
+        let first_price = self.last_close(stat, &self.first).await?;
+        let second_price = self.last_close(stat, &self.second).await?;
+        let first_value = first_price * self.first.lot.max(1) as f64 * self.settings.lots as f64;
+        let second_lots = ((spread.hedge_ratio * first_value) / (second_price * self.second.lot.max(1) as f64)).round().max(1.0) as i64;
+
+        let (long, short) = if spread.zscore > 0.0 {
+            (pattern(&self.second, PositionDirection::Long, second_lots, second_price), pattern(&self.first, PositionDirection::Short, self.settings.lots, first_price))
+        } else {
+            (pattern(&self.first, PositionDirection::Long, self.settings.lots, first_price), pattern(&self.second, PositionDirection::Short, second_lots, second_price))
+        };
+        if !self.first.short_enabled_flag && short.instrument_id == self.first.uid || !self.second.short_enabled_flag && short.instrument_id == self.second.uid {
+            return None;
+        }
+        Some(PairPosition { long, short, hedge_ratio: spread.hedge_ratio, zscore_open: spread.zscore })
+    }
+
+    async fn is_exit(&self, stat: &CandleState) -> bool {
+        match self.spread(stat).await {
+            Some(spread) => spread.zscore.abs() <= self.settings.exit_zscore || spread.zscore.abs() >= self.settings.stop_zscore,
+            None => false,
+        }
+    }
+
+    async fn open_pair(&mut self, mut position: PairPosition) {
+        let long_response = order_open(&mut self.order_service, &mut self.order_intents, &position.long).await;
+        match long_response {
+            Ok(response) => {
+                if let Some(price) = response.into_inner().executed_order_price {
+                    position.long.price_open = Some(Quotation::from_money(&price));
+                }
+            }
+            Err(e) => {
+                eprintln!("Error while opening long leg of pair: {}", e.message());
+                return;
+            }
+        }
+        let short_response = order_open(&mut self.order_service, &mut self.order_intents, &position.short).await;
+        match short_response {
+            Ok(response) => {
+                if let Some(price) = response.into_inner().executed_order_price {
+                    position.short.price_open = Some(Quotation::from_money(&price));
+                }
+                println!("Pair opened: {:#?}", position);
+                self.position = Some(position);
+            }
+            Err(e) => {
+                // без второй ноги остается голая позиция, откатываем первую
+                eprintln!("Error while opening short leg of pair, closing long leg: {}", e.message());
+                if let Err(e) = order_close(&mut self.order_service, &mut self.order_intents, &position.long).await {
+                    eprintln!("Error while closing long leg of pair: {}", e.message());
+                }
+            }
+        }
+    }
+
+    // позиция считается закрытой, только когда закрыты обе ноги. Незакрытая нога остается в position и закрывается на следующем update
+    async fn close_pair(&mut self) {
+        let mut position = match self.position.take() {
+            Some(position) => position,
+            None => return,
+        };
+        if position.long.quantity > 0 {
+            match order_close(&mut self.order_service, &mut self.order_intents, &position.long).await {
+                Ok(_) => position.long.quantity = 0,
+                Err(e) => eprintln!("Error while closing long leg of pair: {}", e.message()),
+            }
+        }
+        if position.short.quantity > 0 {
+            match order_close(&mut self.order_service, &mut self.order_intents, &position.short).await {
+                Ok(_) => position.short.quantity = 0,
+                Err(e) => eprintln!("Error while closing short leg of pair: {}", e.message()),
+            }
+        }
+        if position.long.quantity > 0 || position.short.quantity > 0 {
+            self.position = Some(position);
+        }
+    }
+}
+
+fn pattern(instrument: &Share, direction: PositionDirection, quantity: i64, price: f64) -> OpenedPattern {
+    OpenedPattern {
+        figi: instrument.figi.clone(),
+        direction,
+        quantity,
+        price_open: Some(Quotation::from_f(price)),
+        price_close: None,
+        instrument_id: instrument.uid.clone(),
+        exit: ExitLevels::default(),
+    }
+}
+
+impl<O: OrderService, C: Clock> Strategy for PairStrategy<O, C> {
+    type Statistic = CandleState;
+
+    fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse {
+        match &mut self.pattern_store {
+            Some(store) => {
+                let (restored, positions) = store.restore(positions, &[self.first.clone(), self.second.clone()]);
+                self.restored = restored;
+                positions
+            }
+            None => positions,
+        }
+    }
+
+    // пара восстанавливается, только если по одному инструменту лонг, а по другому шорт
+    async fn warm_up(&mut self, mut positions: PortfolioResponse) -> Result<PortfolioResponse, Box<dyn Error>> {
+        let mut long = None;
+        let mut short = None;
+        for pattern in std::mem::take(&mut self.restored) {
+            match pattern.direction {
+                PositionDirection::Long => long = Some(pattern),
+                PositionDirection::Short => short = Some(pattern),
+            }
+        }
+        let mut unclaimed = Vec::new();
+        // позиции брокера, взятые в ноги: если пара не сложится, они вернутся в unclaimed
+        let mut legs = Vec::new();
+        for position in std::mem::take(&mut positions.positions) {
+            let instrument = if position.instrument_uid == self.first.uid {
+                &self.first
+            } else if position.instrument_uid == self.second.uid {
+                &self.second
+            } else {
+                unclaimed.push(position);
+                continue;
+            };
+            let lot = instrument.lot;
+            let quantity = position.quantity.clone().map(|quantity| quantity.units).unwrap_or(0);
+            if quantity == 0 || quantity % lot.max(1) as i64 != 0 || position.blocked {
+                eprintln!("PairStrategy doesn't recognise position, skip it: {:#?}", position);
+                unclaimed.push(position);
+                continue;
+            }
+            let pattern = map_position_to_pattern(position.clone(), lot);
+            // восстановленная нога главнее, по ней известна цена открытия из прошлого запуска
+            let leg = match pattern.direction {
+                PositionDirection::Long => &mut long,
+                PositionDirection::Short => &mut short,
+            };
+            if leg.is_none() {
+                *leg = Some(pattern);
+                legs.push(position);
+            } else {
+                unclaimed.push(position);
+            }
+        }
+        match (long, short) {
+            (Some(long), Some(short)) if long.instrument_id != short.instrument_id => {
+                let spread = self.spread(&self.statistic).await;
+                let position = PairPosition {
+                    long,
+                    short,
+                    hedge_ratio: spread.as_ref().map(|spread| spread.hedge_ratio).unwrap_or(0.0),
+                    zscore_open: spread.map(|spread| spread.zscore).unwrap_or(0.0),
+                };
+                println!("Warm_up={:#?}", position);
+                self.position = Some(position);
+            }
+            (None, None) => {}
+            (long, short) => {
+                eprintln!("PairStrategy found only one leg, skip it: long={:#?}, short={:#?}", long, short);
+                unclaimed.extend(legs);
+            }
+        }
+        self.save_state();
+        positions.positions = unclaimed;
+        Ok(positions)
+    }
+
+    async fn update(&mut self) -> Result<(), Box<dyn Error>> {
+        let phase = current_phase(&self.session_guard, &self.clock);
+        if !phase.is_trading() {
+            return Ok(());
+        }
+        if self.position.is_some() {
+            // недозакрытую пару добиваем без проверки спреда
+            let is_partially_closed = self.position.as_ref()
+                .map(|position| position.long.quantity == 0 || position.short.quantity == 0)
+                .unwrap_or(false);
+            if is_partially_closed || phase == SessionPhase::Flatten || self.is_exit(&self.statistic).await {
+                if let (false, Some(position), Some(spread)) = (is_partially_closed, &self.position, self.spread(&self.statistic).await) {
+                    println!("Pair exit: zscore {} -> {} (spread={} mean={} std={}), hedge_ratio {} -> {} intercept={}",
+                             position.zscore_open, spread.zscore, spread.spread, spread.mean, spread.std, position.hedge_ratio, spread.hedge_ratio, spread.intercept);
+                }
+                self.close_pair().await;
+                self.save_state();
+            }
+            return Ok(());
+        }
+        if !phase.allows_entry() {
+            return Ok(());
+        }
+        if let Some(position) = self.check_pair(&self.statistic).await {
+            self.open_pair(position).await;
+            self.save_state();
+        }
+        Ok(())
+    }
+
+    // обе ноги пары: сначала лонг, потом шорт
+    async fn signal_buy(&self, stat: &Self::Statistic) -> Vec<OpenedPattern> {
+        if self.position.is_some() {
+            return Vec::new();
+        }
+        match self.check_pair(stat).await {
+            Some(position) => vec![position.long, position.short],
+            None => Vec::new(),
+        }
+    }
+
+    // сигнал считается по двум инструментам сразу, см. check_pair
+    async fn check_pattern(&self, _instrument: &Share, _stat: &Self::Statistic) -> Option<OpenedPattern> {
+        None
+    }
+
+    async fn signal_sell(&self, stat: &Self::Statistic) -> Vec<OpenedPattern> {
+        match &self.position {
+            Some(position) if self.is_exit(stat).await => vec![position.long.clone(), position.short.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
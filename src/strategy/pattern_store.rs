@@ -0,0 +1,214 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use tinkoff_invest_api::tcs::{PortfolioResponse, Quotation, Share};
+use crate::strategy::strategy::{ExitLevels, OpenedPattern, PositionDirection};
+use crate::utils::quotation::QuotationExtension;
+
+// одна строка csv -- одна открытая позиция стратегии. Цены в рублях, пустое поле -- None
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PatternRecord {
+    figi: String,
+    instrument_id: String,
+    direction: PositionDirection,
+    quantity: i64,
+    price_open: Option<f64>,
+    price_close: Option<f64>,
+    price_stop: Option<f64>,
+    price_best: Option<f64>,
+    trailing_distance: Option<f64>,
+    stop_order_id: Option<String>,
+}
+
+impl From<&OpenedPattern> for PatternRecord {
+    fn from(pattern: &OpenedPattern) -> Self {
+        PatternRecord {
+            figi: pattern.figi.clone(),
+            instrument_id: pattern.instrument_id.clone(),
+            direction: pattern.direction,
+            quantity: pattern.quantity,
+            price_open: pattern.price_open.as_ref().map(|price| price.to_f()),
+            price_close: pattern.price_close.as_ref().map(|price| price.to_f()),
+            price_stop: pattern.exit.price_stop.as_ref().map(|price| price.to_f()),
+            price_best: pattern.exit.price_best.as_ref().map(|price| price.to_f()),
+            trailing_distance: pattern.exit.trailing_distance.as_ref().map(|price| price.to_f()),
+            stop_order_id: pattern.exit.stop_order_id.clone(),
+        }
+    }
+}
+
+impl From<PatternRecord> for OpenedPattern {
+    fn from(record: PatternRecord) -> Self {
+        OpenedPattern {
+            figi: record.figi,
+            direction: record.direction,
+            quantity: record.quantity,
+            price_open: record.price_open.map(Quotation::from_f),
+            price_close: record.price_close.map(Quotation::from_f),
+            instrument_id: record.instrument_id,
+            exit: ExitLevels {
+                price_stop: record.price_stop.map(Quotation::from_f),
+                price_best: record.price_best.map(Quotation::from_f),
+                trailing_distance: record.trailing_distance.map(Quotation::from_f),
+                stop_order_id: record.stop_order_id,
+            },
+        }
+    }
+}
+
+// состояние стратегии на диске: причина входа, цель и стопы переживают перезапуск
+pub struct PatternStore {
+    path: PathBuf,
+    // последнее записанное, чтобы не писать файл на каждом тике
+    last_saved: Option<Vec<PatternRecord>>,
+}
+
+impl PatternStore {
+    pub fn new(dir: &str, key: &str) -> Self {
+        Self { path: PathBuf::from(dir).join(format!("{}.csv", key)), last_saved: None }
+    }
+
+    // пишет во временный файл и переименовывает, чтобы при падении не остался половинчатый файл
+    pub fn save(&mut self, patterns: &[OpenedPattern]) -> Result<(), Box<dyn Error>> {
+        let records: Vec<PatternRecord> = patterns.iter().map(PatternRecord::from).collect();
+        if self.last_saved.as_ref() == Some(&records) {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("csv.tmp");
+        let mut writer = csv::Writer::from_path(&tmp_path)?;
+        for record in &records {
+            writer.serialize(record)?;
+        }
+        writer.flush()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.last_saved = Some(records);
+        Ok(())
+    }
+
+    // нет файла -- нечего восстанавливать
+    pub fn load(&mut self) -> Result<Vec<OpenedPattern>, Box<dyn Error>> {
+        if !self.path.is_file() {
+            return Ok(Vec::new());
+        }
+        let mut reader = csv::Reader::from_path(&self.path)?;
+        let records = reader.deserialize().collect::<Result<Vec<PatternRecord>, _>>()?;
+        self.last_saved = Some(records.clone());
+        Ok(records.into_iter().map(OpenedPattern::from).collect())
+    }
+
+    // восстановленные позиции, сверенные с брокером, и остаток позиций брокера, которые стратегия разбирает как раньше
+    pub fn restore(&mut self, positions: PortfolioResponse, instruments: &[Share]) -> (Vec<OpenedPattern>, PortfolioResponse) {
+        match self.load() {
+            Ok(saved) => reconcile(saved, positions, instruments),
+            Err(e) => {
+                eprintln!("Error while loading strategy state from {:?}: {}", self.path, e);
+                (Vec::new(), positions)
+            }
+        }
+    }
+}
+
+// брокер главнее: позиции, закрытые пока бот стоял, выбрасываются, уменьшенные -- урезаются.
+// Из positions вычитается то, что досталось сохраненным позициям
+pub fn reconcile(saved: Vec<OpenedPattern>, mut positions: PortfolioResponse, instruments: &[Share]) -> (Vec<OpenedPattern>, PortfolioResponse) {
+    let mut restored = Vec::new();
+    for mut pattern in saved {
+        let lot = match instruments.iter().find(|instrument| instrument.uid == pattern.instrument_id) {
+            Some(instrument) => instrument.lot.max(1) as i64,
+            None => {
+                eprintln!("Instrument of saved position is not traded by strategy anymore, skip it: {:#?}", pattern);
+                continue;
+            }
+        };
+        let position = positions.positions.iter_mut()
+            .find(|position| position.instrument_uid == pattern.instrument_id && !position.blocked);
+        let quantity = match position {
+            Some(position) => position.quantity.get_or_insert_with(Quotation::default),
+            None => {
+                eprintln!("Saved position is closed at broker, skip it: {:#?}", pattern);
+                continue;
+            }
+        };
+        let available = (quantity.units * pattern.direction.sign()).max(0) / lot;
+        if available == 0 {
+            eprintln!("Saved position is closed at broker, skip it: {:#?}", pattern);
+            continue;
+        }
+        if pattern.quantity > available {
+            eprintln!("Saved position is bigger than at broker, reduce it to {} lots: {:#?}", available, pattern);
+            pattern.quantity = available;
+        }
+        quantity.units -= pattern.quantity * lot * pattern.direction.sign();
+        restored.push(pattern);
+    }
+    positions.positions.retain(|position| position.quantity.as_ref().map(|quantity| quantity.units != 0).unwrap_or(false));
+    (restored, positions)
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{PortfolioPosition, PortfolioResponse, Quotation, Share};
+    use crate::strategy::pattern_store::{reconcile, PatternStore};
+    use crate::strategy::strategy::{ExitLevels, OpenedPattern, PositionDirection};
+    use crate::utils::quotation::QuotationExtension;
+
+    fn pattern(instrument_id: &str, direction: PositionDirection, quantity: i64) -> OpenedPattern {
+        OpenedPattern {
+            figi: instrument_id.to_string(),
+            direction,
+            quantity,
+            price_open: Some(Quotation::from_f(100.5)),
+            price_close: Some(Quotation::from_f(110.0)),
+            instrument_id: instrument_id.to_string(),
+            exit: ExitLevels { price_stop: Some(Quotation::from_f(98.0)), price_best: None, trailing_distance: None, stop_order_id: Some("stop".to_string()) },
+        }
+    }
+
+    fn position(instrument_id: &str, units: i64) -> PortfolioPosition {
+        PortfolioPosition { instrument_uid: instrument_id.to_string(), quantity: Some(Quotation { units, nano: 0 }), ..Default::default() }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("pattern_store_{}", std::process::id()));
+        let mut store = PatternStore::new(dir.to_str().unwrap(), "test");
+        store.save(&[pattern("a", PositionDirection::Short, 2)]).unwrap();
+
+        let loaded = PatternStore::new(dir.to_str().unwrap(), "test").load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].direction, PositionDirection::Short);
+        assert_eq!(loaded[0].price_open, Some(Quotation::from_f(100.5)));
+        assert_eq!(loaded[0].exit.price_best, None);
+        assert_eq!(loaded[0].exit.stop_order_id, Some("stop".to_string()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let instruments = vec![
+            Share { uid: "a".to_string(), lot: 10, ..Default::default() },
+            Share { uid: "b".to_string(), lot: 1, ..Default::default() },
+        ];
+        let saved = vec![
+            pattern("a", PositionDirection::Long, 3),
+            // закрыта, пока бот стоял
+            pattern("b", PositionDirection::Long, 1),
+        ];
+        let positions = PortfolioResponse { positions: vec![position("a", 50), position("b", -5)], ..Default::default() };
+
+        let (restored, rest) = reconcile(saved, positions, &instruments);
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].quantity, 3);
+        assert_eq!(restored[0].price_close, Some(Quotation::from_f(110.0)));
+        // остаток лонга и чужой шорт разбирает warm_up
+        assert_eq!(rest.positions.len(), 2);
+        assert_eq!(rest.positions[0].quantity.clone().unwrap().units, 20);
+
+        let (restored, _) = reconcile(vec![pattern("a", PositionDirection::Long, 9)], PortfolioResponse { positions: vec![position("a", 50)], ..Default::default() }, &instruments);
+        assert_eq!(restored[0].quantity, 5);
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+use prost_types::Timestamp;
+use tinkoff_invest_api::tcs::Share;
+use crate::analytics::correlation::CorrelationStats;
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
+use crate::state::portfolio_state::{PortfolioState, PortfolioStateStatistic};
+use crate::strategy::strategy::PositionDirection;
+use crate::trading_cfg::{CorrelationCfg, SizingCfg};
+
+pub struct SizingInput {
+    // капитал стратегии -- ее доля от стоимости портфеля
+    pub capital: f64,
+    pub cash: f64,
+    pub price: f64,
+    pub stop_price: Option<f64>,
+    pub lot: i64,
+    // 0-1
+    pub signal_strength: f64,
+    // уже открыто по инструменту, по модулю
+    pub current_lots: i64,
+    pub max_lots: Option<i64>,
+}
+
+// переводит риск на сделку в целое количество лотов. Стоп может быть с любой стороны цены, шорт обеспечивается деньгами так же, как лонг
+pub fn position_lots(sizing_cfg: &SizingCfg, input: &SizingInput) -> i64 {
+    let lot_price = input.price * input.lot.max(1) as f64;
+    if lot_price <= 0.0 || input.capital <= 0.0 {
+        return 0;
+    }
+    let stop_distance = match input.stop_price {
+        Some(stop) if stop != input.price => (input.price - stop).abs(),
+        _ => input.price * sizing_cfg.default_stop_prc / 100.0,
+    };
+    if stop_distance <= 0.0 {
+        return 0;
+    }
+    let signal_factor = sizing_cfg.min_signal_factor + (1.0 - sizing_cfg.min_signal_factor) * input.signal_strength.clamp(0.0, 1.0);
+    let risk_amount = input.capital * sizing_cfg.risk_prc / 100.0 * signal_factor;
+    let by_risk = (risk_amount / (stop_distance * input.lot.max(1) as f64)).floor() as i64;
+    let by_cash = (input.cash / lot_price).floor() as i64;
+    let by_position = (input.capital * sizing_cfg.max_position_prc / 100.0 / lot_price).floor() as i64 - input.current_lots;
+    let by_max_lots = input.max_lots.map(|max| max - input.current_lots).unwrap_or(i64::MAX);
+    by_risk.min(by_cash).min(by_position).min(by_max_lots).max(0)
+}
+
+// позиция position_units по сильно коррелирующему инструменту -- та же ставка, что и новая позиция в direction.
+// При отрицательной корреляции той же ставкой будет позиция в обратную сторону
+pub fn is_same_bet(correlation_cfg: &CorrelationCfg, stats: &CorrelationStats, direction: PositionDirection, position_units: i64) -> bool {
+    if position_units == 0 || !stats.is_correlated(correlation_cfg) {
+        return false;
+    }
+    position_units.signum() * stats.current().signum() as i64 == direction.sign()
+}
+
+pub struct PositionSizer {
+    sizing_cfg: SizingCfg,
+    // 0-100, доля капитала стратегии
+    capital_share: f64,
+    portfolio_state: Arc<PortfolioState>,
+    candle_state: Arc<CandleState>,
+}
+
+impl PositionSizer {
+    pub fn new(sizing_cfg: SizingCfg, capital_share: f64, portfolio_state: Arc<PortfolioState>, candle_state: Arc<CandleState>) -> Self {
+        Self { sizing_cfg, capital_share, portfolio_state, candle_state }
+    }
+
+    // уже открытая позиция портфеля, с которой новая была бы одной ставкой. Без истории свечей корреляция не считается
+    async fn correlated_position(&self, correlation_cfg: &CorrelationCfg, instrument: &Share, direction: PositionDirection, now: &Timestamp) -> Option<(String, CorrelationStats)> {
+        let range = SizedRange::new_1m(Timestamp { seconds: now.seconds - correlation_cfg.window_size_min as i64 * 60, nanos: now.nanos }, now.clone());
+        for (instrument_uid, units) in self.portfolio_state.get_share_positions().await {
+            if instrument_uid == instrument.uid {
+                continue;
+            }
+            if let Some(stats) = self.candle_state.get_correlation(correlation_cfg, &instrument.uid, &instrument_uid, range.clone()).await {
+                if is_same_bet(correlation_cfg, &stats, direction, units) {
+                    return Some((instrument_uid, stats));
+                }
+            }
+        }
+        None
+    }
+
+    // 0, если портфель еще не загружен, денег не хватает на один лот, брокер не дает инструмент в шорт
+    // или в портфеле уже есть та же ставка по коррелирующему инструменту
+    pub async fn lots(&self, instrument: &Share, direction: PositionDirection, price: f64, stop_price: Option<f64>, signal_strength: f64, now: &Timestamp) -> i64 {
+        if direction == PositionDirection::Short && !instrument.short_enabled_flag {
+            eprintln!("Short is not available for ticker={}", instrument.ticker);
+            return 0;
+        }
+        if let Some(correlation_cfg) = &self.sizing_cfg.correlation {
+            if let Some((instrument_uid, stats)) = self.correlated_position(correlation_cfg, instrument, direction, now).await {
+                eprintln!("Position in ticker={} {:?} correlates with opened instrument_id={}: correlation={}, beta={}, skip it",
+                          instrument.ticker, direction, instrument_uid, stats.current(), stats.beta);
+                return 0;
+            }
+        }
+        let (equity, cash) = match (self.portfolio_state.get_equity().await, self.portfolio_state.get_cash().await) {
+            (Some(equity), Some(cash)) => (equity, cash),
+            _ => {
+                eprintln!("Portfolio is not loaded, can't size position for ticker={}", instrument.ticker);
+                return 0;
+            }
+        };
+        position_lots(&self.sizing_cfg, &SizingInput {
+            capital: equity * self.capital_share / 100.0,
+            cash,
+            price,
+            stop_price,
+            lot: instrument.lot as i64,
+            signal_strength,
+            current_lots: self.portfolio_state.get_position_lots(instrument).await.abs(),
+            max_lots: self.sizing_cfg.max_lots.get(&instrument.ticker).cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::analytics::correlation::CorrelationStats;
+    use crate::strategy::position_sizer::{is_same_bet, position_lots, SizingInput};
+    use crate::strategy::strategy::PositionDirection;
+    use crate::trading_cfg::{CorrelationCfg, SizingCfg};
+
+    fn sizing_cfg() -> SizingCfg {
+        SizingCfg { risk_prc: 1.0, default_stop_prc: 2.0, min_signal_factor: 0.5, max_position_prc: 50.0, max_lots: HashMap::new(), correlation: None }
+    }
+
+    fn input() -> SizingInput {
+        SizingInput { capital: 100_000.0, cash: 100_000.0, price: 100.0, stop_price: Some(98.0), lot: 10, signal_strength: 1.0, current_lots: 0, max_lots: None }
+    }
+
+    #[test]
+    fn test_lots_by_risk() {
+        // риск 1000, на лот 2 * 10 = 20 -> 50 лотов, но позиция ограничена 50% капитала = 50 лотов по 1000
+        assert_eq!(position_lots(&sizing_cfg(), &input()), 50);
+        // слабый сигнал -- половина риска
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { signal_strength: 0.0, ..input() }), 25);
+        // без стопа берется default_stop_prc
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { stop_price: None, ..input() }), 50);
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { stop_price: Some(95.0), ..input() }), 20);
+        // стоп шорта выше цены
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { stop_price: Some(105.0), ..input() }), 20);
+    }
+
+    #[test]
+    fn test_lots_limits() {
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { cash: 9_999.0, ..input() }), 9);
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { current_lots: 45, ..input() }), 5);
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { max_lots: Some(3), current_lots: 1, ..input() }), 2);
+        assert_eq!(position_lots(&sizing_cfg(), &SizingInput { cash: 500.0, ..input() }), 0);
+    }
+
+    #[test]
+    fn test_same_bet() {
+        let cfg = CorrelationCfg { window_size_min: 60, rolling_window: 10, max_correlation: 0.8 };
+        let stats = |correlation: f64| CorrelationStats { correlation, rolling: Vec::new(), beta: 1.0 };
+        // лонг по коррелирующему инструменту уже открыт
+        assert!(is_same_bet(&cfg, &stats(0.9), PositionDirection::Long, 10));
+        assert!(!is_same_bet(&cfg, &stats(0.9), PositionDirection::Short, 10));
+        // при отрицательной корреляции та же ставка -- шорт против лонга
+        assert!(is_same_bet(&cfg, &stats(-0.9), PositionDirection::Short, 10));
+        assert!(is_same_bet(&cfg, &stats(-0.9), PositionDirection::Long, -10));
+        assert!(!is_same_bet(&cfg, &stats(0.5), PositionDirection::Long, 10));
+        assert!(!is_same_bet(&cfg, &stats(0.9), PositionDirection::Long, 0));
+        // решает последнее скользящее окно
+        let decorrelated = CorrelationStats { rolling: vec![0.9, 0.3], ..stats(0.9) };
+        assert!(!is_same_bet(&cfg, &decorrelated, PositionDirection::Long, 10));
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use flume::Receiver;
+use tinkoff_invest_api::tcs::{PortfolioResponse, Share};
+use crate::service::execution_service::ExecutionService;
+use crate::service::order_service::OrderService;
+use crate::service::trading_calendar::TradingCalendar;
+use crate::state::candle_state::CandleState;
+use crate::state::last_price_state::LastPriceState;
+use crate::state::portfolio_state::PortfolioState;
+use crate::strategy::bollinger_strategy::BollingerSignals;
+use crate::strategy::donchian_strategy::DonchianSignals;
+use crate::strategy::first_strategy::FirstStrategy;
+use crate::strategy::grid_strategy::GridStrategy;
+use crate::strategy::hammer_strategy::HammerSignals;
+use crate::strategy::ma_crossover_strategy::MaCrossoverSignals;
+use crate::strategy::pair_strategy::PairStrategy;
+use crate::strategy::pattern_store::PatternStore;
+use crate::strategy::position_sizer::PositionSizer;
+use crate::strategy::script_strategy::ScriptSignals;
+use crate::strategy::session_guard::SessionGuard;
+use crate::strategy::strategy::{PatternExecution, PatternSignals, PatternStrategy, Strategy, StrategyComponents};
+use crate::trading_cfg::{StrategyCfg, StrategySettings};
+use crate::utils::clock::Clock;
+
+pub type StrategyFuture<'a> = Pin<Box<dyn Future<Output=Result<(), Box<dyn Error>>> + 'a>>;
+pub type WarmUpFuture<'a> = Pin<Box<dyn Future<Output=Result<PortfolioResponse, Box<dyn Error>>> + 'a>>;
+
+// Strategy нельзя хранить как dyn из-за async fn и ассоциированного Statistic, поэтому рантайм работает через эту обертку
+pub trait RuntimeStrategy {
+    fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse;
+    fn warm_up_boxed(&mut self, positions: PortfolioResponse) -> WarmUpFuture<'_>;
+    fn update_boxed(&mut self) -> StrategyFuture<'_>;
+}
+
+impl<T: Strategy> RuntimeStrategy for T {
+    fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse {
+        Strategy::restore(self, positions)
+    }
+
+    fn warm_up_boxed(&mut self, positions: PortfolioResponse) -> WarmUpFuture<'_> {
+        Box::pin(self.warm_up(positions))
+    }
+
+    fn update_boxed(&mut self) -> StrategyFuture<'_> {
+        Box::pin(self.update())
+    }
+}
+
+// все что нужно фабрике для создания стратегии. order_service и clock клонируются в каждую стратегию
+pub struct StrategyContext<O, C> {
+    pub candle_state: Arc<CandleState>,
+    pub last_price_state: Arc<LastPriceState>,
+    pub portfolio_state: Arc<PortfolioState>,
+    pub order_service: O,
+    pub clock: C,
+    pub instruments: Vec<Share>,
+    // каталог для состояния стратегий между перезапусками, None -- состояние не сохраняется (бэктест)
+    pub state_dir: Option<String>,
+    // общий для всех стратегий календарь, None -- правила сессий не применяются
+    pub calendar: Option<Arc<RwLock<TradingCalendar>>>,
+}
+
+pub struct RegisteredStrategy {
+    pub name: String,
+    pub strategy: Box<dyn RuntimeStrategy>,
+}
+
+pub type StrategyFactory<O, C> = Box<dyn Fn(&StrategyContext<O, C>, &StrategyCfg, Vec<Share>) -> Result<Vec<Box<dyn RuntimeStrategy>>, Box<dyn Error>>>;
+
+pub struct StrategyRegistry<O, C> {
+    factories: HashMap<String, StrategyFactory<O, C>>,
+}
+
+impl<O, C> StrategyContext<O, C> {
+    pub fn instruments_by_tickers(&self, tickers: &[String]) -> Result<Vec<Share>, Box<dyn Error>> {
+        tickers.iter()
+            .map(|ticker| self.instruments.iter()
+                .find(|share| &share.ticker == ticker)
+                .cloned()
+                .ok_or_else(|| Box::from(format!("Unknown ticker={:?} in strategy config", ticker))))
+            .collect()
+    }
+
+    // capital_share записи конфига делится поровну между созданными по ней стратегиями
+    pub fn position_sizer(&self, cfg: &StrategyCfg, strategies_count: usize) -> Option<PositionSizer> {
+        cfg.sizing.clone().map(|sizing_cfg| PositionSizer::new(
+            sizing_cfg,
+            cfg.capital_share as f64 / strategies_count.max(1) as f64,
+            Arc::clone(&self.portfolio_state),
+            Arc::clone(&self.candle_state),
+        ))
+    }
+
+    // имя конфига уникально, а стратегии по одному инструменту различаются тикером
+    pub fn pattern_store(&self, cfg: &StrategyCfg, instrument: Option<&Share>) -> Option<PatternStore> {
+        let key = match instrument {
+            Some(instrument) => format!("{}_{}", cfg.name, instrument.ticker),
+            None => cfg.name.clone(),
+        };
+        self.state_dir.as_ref().map(|dir| PatternStore::new(dir, &key))
+    }
+
+    pub fn session_guard(&self, cfg: &StrategyCfg) -> Option<SessionGuard> {
+        match (&self.calendar, &cfg.session_rules) {
+            (Some(calendar), Some(rules)) => Some(SessionGuard::new(Arc::clone(calendar), rules.clone())),
+            _ => None,
+        }
+    }
+
+    // instrument -- для стратегии по одному инструменту, strategies_count -- сколько стратегий создается по записи конфига
+    pub fn components(&self, cfg: &StrategyCfg, instrument: Option<&Share>, strategies_count: usize) -> StrategyComponents {
+        StrategyComponents {
+            position_sizer: self.position_sizer(cfg, strategies_count),
+            pattern_store: self.pattern_store(cfg, instrument),
+            session_guard: self.session_guard(cfg),
+        }
+    }
+}
+
+impl<O: OrderService + Clone, C: Clock + Clone> StrategyContext<O, C> {
+    // у каждой стратегии свой ExecutionService поверх общего order_service
+    pub fn execution(&self, cfg: &StrategyCfg) -> Option<PatternExecution<O, C>> {
+        cfg.execution.clone().map(|execution_cfg| PatternExecution::new(
+            ExecutionService::new(self.order_service.clone(), self.clock.clone(), Arc::clone(&self.candle_state)),
+            execution_cfg,
+        ))
+    }
+}
+
+impl<O, C> StrategyRegistry<O, C> {
+    pub fn new() -> Self {
+        Self { factories: HashMap::new() }
+    }
+
+    pub fn register<F>(&mut self, kind: &str, factory: F)
+        where F: Fn(&StrategyContext<O, C>, &StrategyCfg, Vec<Share>) -> Result<Vec<Box<dyn RuntimeStrategy>>, Box<dyn Error>> + 'static {
+        self.factories.insert(kind.to_string(), Box::new(factory));
+    }
+
+    // одна запись конфига может дать несколько стратегий, если стратегия работает с одним инструментом
+    pub fn create(&self, context: &StrategyContext<O, C>, cfg: &StrategyCfg) -> Result<Vec<RegisteredStrategy>, Box<dyn Error>> {
+        let factory = self.factories.get(&cfg.kind)
+            .ok_or_else(|| format!("Strategy kind={:?} is not registered", cfg.kind))?;
+        let instruments = context.instruments_by_tickers(&cfg.tickers)?;
+        let strategies = factory(context, cfg, instruments)?;
+        Ok(strategies.into_iter()
+            .map(|strategy| RegisteredStrategy { name: cfg.name.clone(), strategy })
+            .collect())
+    }
+
+    pub fn create_all(&self, context: &StrategyContext<O, C>, cfgs: &[StrategyCfg]) -> Result<Vec<RegisteredStrategy>, Box<dyn Error>> {
+        let total_share: u32 = cfgs.iter().map(|cfg| cfg.capital_share as u32).sum();
+        if total_share > 100 {
+            return Err(Box::from(format!("Sum of strategies capital_share={} > 100", total_share)));
+        }
+        let mut strategies = Vec::new();
+        for cfg in cfgs {
+            strategies.extend(self.create(context, cfg)?);
+        }
+        Ok(strategies)
+    }
+}
+
+// стратегии не знают, в каком режиме работают: бэктест, песочница или прод определяются order_service и clock
+impl<O: OrderService + Clone + 'static, C: Clock + Clone + 'static> StrategyRegistry<O, C> {
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register("first", |context, cfg, instruments| match &cfg.settings {
+            StrategySettings::First(settings) => Ok(vec![
+                Box::new(FirstStrategy::new(
+                    Arc::clone(&context.last_price_state),
+                    context.order_service.clone(),
+                    context.clock.clone(),
+                    context.components(cfg, None, 1),
+                    instruments,
+                    settings.clone(),
+                )) as Box<dyn RuntimeStrategy>
+            ]),
+            _ => Err(wrong_settings(cfg, "First")),
+        });
+        registry.register_pattern("hammer", |settings| match settings {
+            StrategySettings::Hammer(settings) => Some(HammerSignals::new(settings.clone())),
+            _ => None,
+        });
+        registry.register_pattern("bollinger", |settings| match settings {
+            StrategySettings::Bollinger(settings) => Some(BollingerSignals::new(settings.clone())),
+            _ => None,
+        });
+        registry.register_pattern("ma_crossover", |settings| match settings {
+            StrategySettings::MaCrossover(settings) => Some(MaCrossoverSignals::new(settings.clone())),
+            _ => None,
+        });
+        registry.register_pattern("donchian", |settings| match settings {
+            StrategySettings::Donchian(settings) => Some(DonchianSignals::new(settings.clone())),
+            _ => None,
+        });
+        registry.register_pattern("script", |settings| match settings {
+            StrategySettings::Script(settings) => Some(ScriptSignals::new(settings.clone())),
+            _ => None,
+        });
+        registry.register("grid", |context, cfg, instruments| match &cfg.settings {
+            StrategySettings::Grid(settings) => Ok(instruments.into_iter()
+                .map(|instrument| Box::new(GridStrategy::new(
+                    Arc::clone(&context.last_price_state),
+                    context.order_service.clone(),
+                    context.clock.clone(),
+                    context.session_guard(cfg),
+                    instrument,
+                    settings.clone(),
+                )) as Box<dyn RuntimeStrategy>)
+                .collect()),
+            _ => Err(wrong_settings(cfg, "Grid")),
+        });
+        registry.register("pair", |context, cfg, instruments| match (&cfg.settings, instruments.as_slice()) {
+            (StrategySettings::Pair(settings), [first, second]) => Ok(vec![Box::new(PairStrategy::new(
+                Arc::clone(&context.candle_state),
+                context.order_service.clone(),
+                context.clock.clone(),
+                context.components(cfg, None, 1),
+                first.clone(),
+                second.clone(),
+                settings.clone(),
+            )) as Box<dyn RuntimeStrategy>]),
+            (StrategySettings::Pair(_), _) => Err(Box::from(format!("Strategy {:?} expects exactly two tickers, got {:?}", cfg.name, cfg.tickers))),
+            _ => Err(wrong_settings(cfg, "Pair")),
+        });
+        registry
+    }
+
+    // стратегия по одному инструменту на каждый тикер записи конфига, signals -- None, если настройки от другой стратегии
+    pub fn register_pattern<S, F>(&mut self, kind: &str, signals: F)
+        where S: PatternSignals + 'static, F: Fn(&StrategySettings) -> Option<S> + 'static {
+        self.register(kind, move |context, cfg, instruments| instruments.iter()
+            .map(|instrument| {
+                let signals = signals(&cfg.settings).ok_or_else(|| wrong_settings(cfg, S::NAME))?;
+                Ok(Box::new(PatternStrategy::new(
+                    Arc::clone(&context.candle_state),
+                    Arc::clone(&context.last_price_state),
+                    context.order_service.clone(),
+                    context.clock.clone(),
+                    context.components(cfg, Some(instrument), instruments.len()),
+                    instrument.clone(),
+                    signals,
+                ).with_execution(context.execution(cfg))) as Box<dyn RuntimeStrategy>)
+            })
+            .collect());
+    }
+}
+
+fn wrong_settings(cfg: &StrategyCfg, expected: &str) -> Box<dyn Error> {
+    Box::from(format!("Strategy {:?} expects {} settings, got {:?}", cfg.name, expected, cfg.settings))
+}
+
+// прогрев по позициям брокера, затем обновление всех стратегий на каждый тик.
+// Позиции разбираются в порядке конфига: следующей стратегии достается то, что не взяли предыдущие
+pub async fn run_strategies(mut strategies: Vec<RegisteredStrategy>, mut positions: PortfolioResponse, ticks: Receiver<String>) {
+    // сначала все стратегии забирают свои сохраненные позиции, и только потом разбирается остаток
+    for registered in strategies.iter_mut() {
+        positions = registered.strategy.restore(positions);
+    }
+    for registered in strategies.iter_mut() {
+        match registered.strategy.warm_up_boxed(positions.clone()).await {
+            Ok(unclaimed) => positions = unclaimed,
+            Err(err) => eprintln!("Error while warm up strategy={}: {}", registered.name, err),
+        }
+    }
+    for position in &positions.positions {
+        if position.instrument_type == "share" {
+            eprintln!("No strategy recognised position: {:#?}", position);
+        }
+    }
+    while ticks.recv_async().await.is_ok() {
+        // тики, накопившиеся пока стратегии обновлялись, уже неактуальны
+        let _ = ticks.drain();
+        for registered in strategies.iter_mut() {
+            registered.strategy.update_boxed().await
+                .unwrap_or_else(|err| eprintln!("Error updating strategy={}: {}", registered.name, err));
+        }
+    }
+}
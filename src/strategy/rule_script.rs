@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use tinkoff_invest_api::tcs::Candle;
+use crate::analytics::moving_average::{ema, sma};
+use crate::analytics::oscillators::{bollinger, closes, donchian, rsi};
+use crate::analytics::trend::adx;
+use crate::analytics::volatility::atr;
+use crate::analytics::volume::relative_volume;
+use crate::trading_cfg::ScriptLimitsCfg;
+use crate::utils::quotation::QuotationExtension;
+
+// Язык правил для стратегий без перекомпиляции:
+//
+//     # перепроданность у нижней полосы
+//     lower = bb_lower(20, 2);
+//     buy = close < lower && rsi(14) < 30;
+//     sell = close > bb_middle(20, 2);
+//     target = bb_middle(20, 2);
+//     strength = (30 - rsi(14)) / 30;
+//
+// Скрипт -- последовательность присваиваний, циклов и вызовов наружу нет. Стратегия читает buy и sell (обязательны),
+// target, stop и strength. Данные доступны только на чтение, индикатор без данных дает NaN, и любое сравнение с ним ложно
+
+const BUILTIN_VALUES: [&str; 8] = ["close", "open", "high", "low", "volume", "price", "position", "bars"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+    Assign,
+    Semicolon,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Num(f64),
+    Bool(bool),
+    Var(String),
+    Call(String, Vec<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    statements: Vec<(String, Expr)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Num(f64),
+    Bool(bool),
+}
+
+// то, что скрипт видит: свечи от старых к новым, последняя цена и текущая позиция в лотах (шорт -- со знаком минус)
+#[derive(Debug, Clone, Default)]
+pub struct ScriptData {
+    pub candles: Vec<Candle>,
+    pub price: Option<f64>,
+    pub position: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptOutput {
+    pub buy: bool,
+    pub sell: bool,
+    pub target: Option<f64>,
+    pub stop: Option<f64>,
+    // 0-1 для PositionSizer
+    pub strength: Option<f64>,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(text.parse().map_err(|_| format!("Bad number {:?}", text))?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let two = match (c, next) {
+                ('|', Some('|')) => Some("||"),
+                ('&', Some('&')) => Some("&&"),
+                ('<', Some('=')) => Some("<="),
+                ('>', Some('=')) => Some(">="),
+                ('=', Some('=')) => Some("=="),
+                ('!', Some('=')) => Some("!="),
+                _ => None,
+            };
+            if let Some(op) = two {
+                tokens.push(Token::Op(op));
+                i += 2;
+                continue;
+            }
+            tokens.push(match c {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ',' => Token::Comma,
+                ';' => Token::Semicolon,
+                '=' => Token::Assign,
+                '!' => Token::Op("!"),
+                '<' => Token::Op("<"),
+                '>' => Token::Op(">"),
+                '+' => Token::Op("+"),
+                '-' => Token::Op("-"),
+                '*' => Token::Op("*"),
+                '/' => Token::Op("/"),
+                other => return Err(format!("Unexpected symbol {:?}", other)),
+            });
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+    limits: &'a ScriptLimitsCfg,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(format!("Expected {:?}, got {:?}", expected, other)),
+        }
+    }
+
+    fn is_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    // левоассоциативный уровень бинарных операторов
+    fn binary(&mut self, ops: &[&str], operand: fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        let mut left = operand(self)?;
+        while let Some(op) = self.is_op(ops) {
+            self.pos += 1;
+            let right = operand(self)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.depth += 1;
+        if self.depth > self.limits.max_depth {
+            return Err(format!("Expression is nested deeper than max_depth={}", self.limits.max_depth));
+        }
+        let expr = self.binary(&["||"], |parser| parser.binary(&["&&"], Self::comparison));
+        self.depth -= 1;
+        expr
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        self.binary(&["<", "<=", ">", ">=", "==", "!="], |parser| parser.binary(&["+", "-"], |parser| parser.binary(&["*", "/"], Self::unary)))
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.is_op(&["!", "-"]) {
+            Some(op) => {
+                self.pos += 1;
+                self.depth += 1;
+                if self.depth > self.limits.max_depth {
+                    return Err(format!("Expression is nested deeper than max_depth={}", self.limits.max_depth));
+                }
+                let operand = self.unary();
+                self.depth -= 1;
+                Ok(Expr::Unary(op, Box::new(operand?)))
+            }
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(value)) => Ok(Expr::Num(value)),
+            Some(Token::LParen) => {
+                let expr = self.expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) if name == "true" => Ok(Expr::Bool(true)),
+            Some(Token::Ident(name)) if name == "false" => Ok(Expr::Bool(false)),
+            Some(Token::Ident(name)) => {
+                if self.peek() != Some(&Token::LParen) {
+                    return Ok(Expr::Var(name));
+                }
+                self.pos += 1;
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::RParen) {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.peek() != Some(&Token::Comma) {
+                            break;
+                        }
+                        self.pos += 1;
+                    }
+                }
+                self.expect(Token::RParen)?;
+                Ok(Expr::Call(name, args))
+            }
+            other => Err(format!("Unexpected token {:?}", other)),
+        }
+    }
+}
+
+pub fn parse_script(source: &str, limits: &ScriptLimitsCfg) -> Result<Script, String> {
+    if source.len() > limits.max_source_len {
+        return Err(format!("Script is longer than max_source_len={}", limits.max_source_len));
+    }
+    let mut parser = Parser { tokens: tokenize(source)?, pos: 0, depth: 0, limits };
+    let mut statements = Vec::new();
+    while parser.peek().is_some() {
+        let name = match parser.next() {
+            Some(Token::Ident(name)) => name,
+            other => return Err(format!("Expected variable name, got {:?}", other)),
+        };
+        if BUILTIN_VALUES.contains(&name.as_str()) {
+            return Err(format!("Can't assign to read-only {:?}", name));
+        }
+        parser.expect(Token::Assign)?;
+        statements.push((name, parser.expr()?));
+        parser.expect(Token::Semicolon)?;
+        if statements.len() > limits.max_statements {
+            return Err(format!("Script has more than max_statements={}", limits.max_statements));
+        }
+    }
+    if !statements.iter().any(|(name, _)| name == "buy") || !statements.iter().any(|(name, _)| name == "sell") {
+        return Err("Script must assign both buy and sell".to_string());
+    }
+    Ok(Script { statements })
+}
+
+struct Evaluator<'a> {
+    data: &'a ScriptData,
+    closes: Vec<f64>,
+    vars: HashMap<String, Value>,
+    ops: usize,
+    limits: &'a ScriptLimitsCfg,
+}
+
+impl<'a> Evaluator<'a> {
+    fn spend(&mut self, ops: usize) -> Result<(), String> {
+        self.ops += ops;
+        if self.ops > self.limits.max_ops {
+            return Err(format!("Script exceeded max_ops={}", self.limits.max_ops));
+        }
+        Ok(())
+    }
+
+    fn num(&mut self, expr: &Expr) -> Result<f64, String> {
+        match self.eval(expr)? {
+            Value::Num(value) => Ok(value),
+            Value::Bool(_) => Err(format!("Expected number in {:?}", expr)),
+        }
+    }
+
+    fn bool(&mut self, expr: &Expr) -> Result<bool, String> {
+        match self.eval(expr)? {
+            Value::Bool(value) => Ok(value),
+            Value::Num(_) => Err(format!("Expected true/false in {:?}", expr)),
+        }
+    }
+
+    fn last_candle_value(&self, get: fn(&Candle) -> f64) -> f64 {
+        self.data.candles.last().map(get).unwrap_or(f64::NAN)
+    }
+
+    fn builtin_value(&self, name: &str) -> Option<f64> {
+        let value = match name {
+            "close" => self.last_candle_value(|candle| candle.close.clone().unwrap().to_f()),
+            "open" => self.last_candle_value(|candle| candle.open.clone().unwrap().to_f()),
+            "high" => self.last_candle_value(|candle| candle.high.clone().unwrap().to_f()),
+            "low" => self.last_candle_value(|candle| candle.low.clone().unwrap().to_f()),
+            "volume" => self.last_candle_value(|candle| candle.volume as f64),
+            "price" => self.data.price.unwrap_or_else(|| self.closes.last().copied().unwrap_or(f64::NAN)),
+            "position" => self.data.position as f64,
+            "bars" => self.data.candles.len() as f64,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // период индикатора проверяется и оплачивается шагами
+    fn period(&mut self, expr: &Expr) -> Result<usize, String> {
+        let period = self.num(expr)?;
+        if !(period >= 1.0 && period <= self.limits.max_period as f64) {
+            return Err(format!("Period {} is out of 1..={}", period, self.limits.max_period));
+        }
+        self.spend(period as usize)?;
+        Ok(period as usize)
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<f64, String> {
+        let arity = match name {
+            "abs" | "sma" | "ema" | "rsi" | "atr" | "adx" | "donchian_upper" | "donchian_lower" | "rel_volume" | "close_ago" => 1,
+            "min" | "max" | "bb_upper" | "bb_middle" | "bb_lower" => 2,
+            _ => return Err(format!("Unknown function {:?}", name)),
+        };
+        if args.len() != arity {
+            return Err(format!("Function {:?} expects {} arguments, got {}", name, arity, args.len()));
+        }
+        let value = match name {
+            "abs" => self.num(&args[0])?.abs(),
+            "min" => self.num(&args[0])?.min(self.num(&args[1])?),
+            "max" => self.num(&args[0])?.max(self.num(&args[1])?),
+            "sma" => {
+                let period = self.period(&args[0])?;
+                sma(&self.closes, period).unwrap_or(f64::NAN)
+            }
+            "ema" => {
+                let period = self.period(&args[0])?;
+                ema(&self.closes, period).unwrap_or(f64::NAN)
+            }
+            "rsi" => {
+                let period = self.period(&args[0])?;
+                rsi(&self.closes, period).unwrap_or(f64::NAN)
+            }
+            "atr" | "adx" if self.data.candles.len() < 2 => {
+                self.period(&args[0])?;
+                f64::NAN
+            }
+            "atr" => {
+                let period = self.period(&args[0])?;
+                atr(&self.data.candles, period)
+            }
+            "adx" => {
+                let period = self.period(&args[0])?;
+                adx(&self.data.candles, period).0
+            }
+            "donchian_upper" | "donchian_lower" => {
+                let period = self.period(&args[0])?;
+                donchian(&self.data.candles, period)
+                    .map(|channel| if name == "donchian_upper" { channel.upper } else { channel.lower })
+                    .unwrap_or(f64::NAN)
+            }
+            "rel_volume" => {
+                let period = self.period(&args[0])?;
+                relative_volume(&self.data.candles, period).unwrap_or(f64::NAN)
+            }
+            "close_ago" => {
+                let bars = self.period(&args[0])?;
+                self.closes.len().checked_sub(bars + 1).map(|index| self.closes[index]).unwrap_or(f64::NAN)
+            }
+            _ => {
+                let period = self.period(&args[0])?;
+                let std_multiplier = self.num(&args[1])?;
+                bollinger(&self.closes, period, std_multiplier)
+                    .map(|bands| match name {
+                        "bb_upper" => bands.upper,
+                        "bb_middle" => bands.middle,
+                        _ => bands.lower,
+                    })
+                    .unwrap_or(f64::NAN)
+            }
+        };
+        Ok(value)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        self.spend(1)?;
+        let value = match expr {
+            Expr::Num(value) => Value::Num(*value),
+            Expr::Bool(value) => Value::Bool(*value),
+            Expr::Var(name) => match self.vars.get(name) {
+                Some(value) => *value,
+                None => Value::Num(self.builtin_value(name).ok_or_else(|| format!("Unknown variable {:?}", name))?),
+            },
+            Expr::Call(name, args) => Value::Num(self.call(name, args)?),
+            Expr::Unary("!", operand) => Value::Bool(!self.bool(operand)?),
+            Expr::Unary(_, operand) => Value::Num(-self.num(operand)?),
+            // && и || не вычисляют правую часть без надобности
+            Expr::Binary("&&", left, right) => Value::Bool(self.bool(left)? && self.bool(right)?),
+            Expr::Binary("||", left, right) => Value::Bool(self.bool(left)? || self.bool(right)?),
+            Expr::Binary(op @ ("==" | "!="), left, right) => {
+                let is_equal = self.eval(left)? == self.eval(right)?;
+                Value::Bool(if *op == "==" { is_equal } else { !is_equal })
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.num(left)?;
+                let right = self.num(right)?;
+                match *op {
+                    "+" => Value::Num(left + right),
+                    "-" => Value::Num(left - right),
+                    "*" => Value::Num(left * right),
+                    "/" => Value::Num(left / right),
+                    "<" => Value::Bool(left < right),
+                    "<=" => Value::Bool(left <= right),
+                    ">" => Value::Bool(left > right),
+                    _ => Value::Bool(left >= right),
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+pub fn run_script(script: &Script, data: &ScriptData, limits: &ScriptLimitsCfg) -> Result<ScriptOutput, String> {
+    let mut evaluator = Evaluator { data, closes: closes(&data.candles), vars: HashMap::new(), ops: 0, limits };
+    for (name, expr) in &script.statements {
+        let value = evaluator.eval(expr)?;
+        evaluator.vars.insert(name.clone(), value);
+    }
+    let flag = |name: &str| match evaluator.vars.get(name) {
+        Some(Value::Bool(value)) => Ok(*value),
+        other => Err(format!("{} must be true/false, got {:?}", name, other)),
+    };
+    // NaN и бесконечность -- уровня нет
+    let level = |name: &str| match evaluator.vars.get(name) {
+        Some(Value::Num(value)) if value.is_finite() => Some(*value),
+        _ => None,
+    };
+    Ok(ScriptOutput {
+        buy: flag("buy")?,
+        sell: flag("sell")?,
+        target: level("target"),
+        stop: level("stop"),
+        strength: level("strength").map(|strength| strength.clamp(0.0, 1.0)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{Candle, Quotation};
+    use crate::strategy::rule_script::{parse_script, run_script, ScriptData, ScriptOutput};
+    use crate::trading_cfg::ScriptLimitsCfg;
+    use crate::utils::quotation::QuotationExtension;
+
+    fn limits() -> ScriptLimitsCfg {
+        ScriptLimitsCfg { max_source_len: 1000, max_statements: 10, max_depth: 8, max_ops: 200, max_period: 50 }
+    }
+
+    fn data(closes: &[f64]) -> ScriptData {
+        let candles = closes.iter().map(|close| Candle {
+            open: Some(Quotation::from_f(*close)),
+            high: Some(Quotation::from_f(close + 1.0)),
+            low: Some(Quotation::from_f(close - 1.0)),
+            close: Some(Quotation::from_f(*close)),
+            volume: 100,
+            ..Default::default()
+        }).collect();
+        ScriptData { candles, price: None, position: 0 }
+    }
+
+    #[test]
+    fn test_run_script() {
+        let script = parse_script("
+            # покупка под средней
+            avg = sma(3);
+            buy = close < avg && position == 0;
+            sell = !buy || close_ago(1) > 100;
+            target = avg + 2 * (high - low) / 2;
+            stop = rsi(40);
+        ", &limits()).unwrap();
+
+        let output = run_script(&script, &data(&[10.0, 12.0, 11.0, 7.0]), &limits()).unwrap();
+        assert_eq!(output, ScriptOutput { buy: true, sell: false, target: Some(12.0), stop: None, strength: None });
+
+        let output = run_script(&script, &ScriptData { position: 1, ..data(&[10.0, 12.0, 11.0, 7.0]) }, &limits()).unwrap();
+        assert!(!output.buy && output.sell);
+
+        // данных нет -- сравнения ложны, а не ошибка
+        let output = run_script(&script, &ScriptData::default(), &limits()).unwrap();
+        assert!(!output.buy && output.sell);
+
+        // пример из репозитория должен оставаться рабочим
+        let example = parse_script(include_str!("../../scripts/bollinger_rsi.rules"), &limits()).unwrap();
+        assert!(run_script(&example, &data(&[10.0, 12.0, 11.0, 7.0]), &limits()).is_ok());
+    }
+
+    #[test]
+    fn test_limits() {
+        assert!(parse_script("buy = true; sell = false", &limits()).is_err());
+        assert!(parse_script("buy = true;", &limits()).is_err());
+        assert!(parse_script("close = 1; buy = true; sell = false;", &limits()).is_err());
+        assert!(parse_script("buy = ((((((((((true)))))))))); sell = false;", &limits()).is_err());
+        assert!(parse_script("buy = system(\"rm\"); sell = false;", &limits()).is_err());
+
+        let script = parse_script("buy = sma(60) > 0; sell = false;", &limits()).unwrap();
+        assert!(run_script(&script, &data(&[1.0]), &limits()).is_err());
+        let script = parse_script("a = sma(50) + sma(50) + sma(50) + sma(50); buy = a > 0; sell = false;", &limits()).unwrap();
+        assert!(run_script(&script, &data(&[1.0]), &limits()).is_err());
+        let script = parse_script("buy = 1; sell = false;", &limits()).unwrap();
+        assert!(run_script(&script, &data(&[1.0]), &limits()).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::time::SystemTime;
+use tinkoff_invest_api::tcs::{Quotation, Share, SubscriptionInterval};
+use crate::service::order_service::OrderService;
+use crate::state::candle_state::{CandleState, CandleStateStatistic};
+use crate::strategy::exit_manager::ExitManager;
+use crate::strategy::rule_script::{parse_script, run_script, Script, ScriptData, ScriptOutput};
+use crate::strategy::strategy::{ExitLevels, OpenedPattern, PatternRunner, PatternSignals, PositionDirection};
+use crate::trading_cfg::{ExitCfg, ScriptStrategySettings};
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
+
+// правила входа и выхода из файла скрипта, см. rule_script. Только лонг
+pub struct ScriptSignals {
+    settings: ScriptStrategySettings,
+    script: Option<Script>,
+    script_modified: Option<SystemTime>,
+    last_reload_check: i64,
+}
+
+impl ScriptSignals {
+    pub fn new(settings: ScriptStrategySettings) -> Self {
+        Self { settings, script: None, script_modified: None, last_reload_check: 0 }
+    }
+
+    // ошибка выполнения (лимиты, типы) -- скрипт в этот раз молчит
+    async fn evaluate<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Option<ScriptOutput> {
+        let script = self.script.as_ref()?;
+        let range = runner.window_range(SubscriptionInterval::OneMinute, self.settings.window_size_min);
+        let mut candles = stat.get_candles(&runner.instrument.uid, range).await.unwrap_or_default();
+        candles.reverse();
+        let data = ScriptData {
+            candles,
+            price: runner.current_price().await.map(|price| price.to_f()),
+            position: runner.opened_patterns.iter().map(|pattern| pattern.quantity * pattern.direction.sign()).sum(),
+        };
+        match run_script(script, &data, &self.settings.limits) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                eprintln!("Error while running script {} for ticker={}: {}", self.settings.path, runner.instrument.ticker, e);
+                None
+            }
+        }
+    }
+}
+
+impl PatternSignals for ScriptSignals {
+    const NAME: &'static str = "ScriptStrategy";
+
+    fn exit_cfg(&self) -> Option<&ExitCfg> {
+        self.settings.exit_cfg.as_ref()
+    }
+
+    // файл перечитывается, только если изменился. Скрипт с ошибкой не заменяет рабочий
+    fn prepare<O: OrderService, C: Clock>(&mut self, runner: &PatternRunner<O, C>) {
+        let now = runner.clock.now().seconds;
+        if self.script.is_some() && now - self.last_reload_check < self.settings.reload_sec {
+            return;
+        }
+        self.last_reload_check = now;
+        let modified = match fs::metadata(&self.settings.path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                eprintln!("Error while reading script {}: {}", self.settings.path, e);
+                return;
+            }
+        };
+        if self.script_modified == Some(modified) {
+            return;
+        }
+        self.script_modified = Some(modified);
+        let parsed = fs::read_to_string(&self.settings.path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| parse_script(&source, &self.settings.limits));
+        match parsed {
+            Ok(script) => {
+                println!("Script {} loaded for ticker={}", self.settings.path, runner.instrument.ticker);
+                self.script = Some(script);
+            }
+            Err(e) => eprintln!("Error in script {}, keep previous version: {}", self.settings.path, e),
+        }
+    }
+
+    // стоп из скрипта главнее стопа из настроек
+    fn attach(&self, exit_manager: &ExitManager, pattern: &mut OpenedPattern, atr: Option<f64>) {
+        let price_stop = pattern.exit.price_stop.take();
+        exit_manager.attach(pattern, atr);
+        if price_stop.is_some() {
+            pattern.exit.price_stop = price_stop;
+        }
+    }
+
+    async fn check_pattern<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, instrument: &Share, stat: &CandleState) -> Option<OpenedPattern> {
+        let output = self.evaluate(runner, stat).await?;
+        if !output.buy {
+            return None;
+        }
+        let price = runner.current_price().await?.to_f();
+        let stop_price = output.stop.or_else(|| runner.stop_price(PositionDirection::Long, price, None));
+        let quantity = runner.lots(PositionDirection::Long, price, stop_price, output.strength.unwrap_or(1.0)).await;
+        if quantity == 0 {
+            return None;
+        }
+        println!("Script buy ticker={}: {:?}", instrument.ticker, output);
+        Some(OpenedPattern {
+            figi: instrument.figi.clone(),
+            direction: PositionDirection::Long,
+            quantity,
+            price_open: None,
+            price_close: output.target.map(Quotation::from_f),
+            instrument_id: instrument.uid.clone(),
+            exit: ExitLevels { price_stop: output.stop.map(Quotation::from_f), ..ExitLevels::default() },
+        })
+    }
+
+    async fn signal_sell<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern> {
+        let mut close_request = Vec::new();
+        if runner.opened_patterns.is_empty() {
+            return close_request;
+        }
+        let last_price = runner.current_price().await;
+        let is_script_sell = self.evaluate(runner, stat).await.map(|output| output.sell).unwrap_or(false);
+        for order in &runner.opened_patterns {
+            let is_target_reached = match (&order.price_close, &last_price) {
+                (Some(target), Some(price)) => price.to_f() >= target.to_f(),
+                _ => false,
+            };
+            // стоп из скрипта работает и без ExitManager
+            let is_stopped = match (&order.exit.price_stop, &last_price) {
+                (Some(stop), Some(price)) => price.to_f() <= stop.to_f(),
+                _ => false,
+            };
+            let is_exit = match (&runner.exit_manager, &last_price) {
+                (Some(exit_manager), Some(price)) => exit_manager.exit_reason(order, price).is_some(),
+                _ => false,
+            };
+            if is_script_sell || is_target_reached || is_stopped || is_exit {
+                close_request.push(order.clone());
+            }
+        }
+        close_request
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicI64, Ordering};
+use prost_types::Timestamp;
+use crate::service::trading_calendar::TradingCalendar;
+use crate::trading_cfg::SessionRulesCfg;
+use crate::utils::clock::Clock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+    // неторговый день или время между сессиями
+    Closed,
+    Auction,
+    // позиции ведутся, новые не открываются
+    NoEntry,
+    Open,
+    // скоро конец торгового дня, все позиции закрываются
+    Flatten,
+}
+
+impl SessionPhase {
+    pub fn is_trading(&self) -> bool {
+        !matches!(self, SessionPhase::Closed | SessionPhase::Auction)
+    }
+
+    pub fn allows_entry(&self) -> bool {
+        *self == SessionPhase::Open
+    }
+}
+
+const MISSING_LOG_PERIOD_SEC: i64 = 3600;
+
+// правила стратегии поверх общего для всех торгового календаря
+pub struct SessionGuard {
+    // календарь периодически перезагружается в main
+    calendar: Arc<RwLock<TradingCalendar>>,
+    rules: SessionRulesCfg,
+    // когда последний раз писали в лог, что календарь закончился
+    missing_logged_at: AtomicI64,
+}
+
+impl SessionGuard {
+    pub fn new(calendar: Arc<RwLock<TradingCalendar>>, rules: SessionRulesCfg) -> Self {
+        Self { calendar, rules, missing_logged_at: AtomicI64::new(i64::MIN) }
+    }
+
+    // запрет входа считается от границ каждой сессии, закрытие позиций -- от конца торгового дня
+    pub fn phase(&self, now: &Timestamp) -> SessionPhase {
+        let calendar = self.calendar.read().unwrap();
+        if calendar.is_auction(now) {
+            return SessionPhase::Auction;
+        }
+        let session = match calendar.session_at(now) {
+            Some(session) => session,
+            None => {
+                if !calendar.covers(now) {
+                    self.log_missing(now);
+                }
+                return SessionPhase::Closed;
+            }
+        };
+        if let Some(flatten_min) = self.rules.flatten_before_close_min {
+            if calendar.day_close(session) - now.seconds <= flatten_min as i64 * 60 {
+                return SessionPhase::Flatten;
+            }
+        }
+        if now.seconds - session.start < self.rules.no_entry_first_min as i64 * 60
+            || session.end - now.seconds <= self.rules.no_entry_last_min as i64 * 60 {
+            return SessionPhase::NoEntry;
+        }
+        SessionPhase::Open
+    }
+
+    // не чаще раза в час, phase вызывается на каждом тике
+    fn log_missing(&self, now: &Timestamp) {
+        let logged_at = self.missing_logged_at.load(Ordering::Relaxed);
+        if logged_at == i64::MIN || now.seconds - logged_at >= MISSING_LOG_PERIOD_SEC {
+            self.missing_logged_at.store(now.seconds, Ordering::Relaxed);
+            eprintln!("Trading calendar has no data for {}, session is treated as closed", now.seconds);
+        }
+    }
+}
+
+// без правил стратегия торгует всегда, как до появления календаря
+pub fn current_phase<C: Clock>(guard: &Option<SessionGuard>, clock: &C) -> SessionPhase {
+    match guard {
+        Some(guard) => guard.phase(&clock.now()),
+        None => SessionPhase::Open,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+    use prost_types::Timestamp;
+    use crate::service::trading_calendar::{SessionInterval, SessionKind, TradingCalendar};
+    use crate::strategy::session_guard::{SessionGuard, SessionPhase};
+    use crate::trading_cfg::SessionRulesCfg;
+
+    #[test]
+    fn test_phase() {
+        let min = 60;
+        let calendar = TradingCalendar::new(vec![
+            SessionInterval { kind: SessionKind::Auction, start: 0, end: 10 * min },
+            SessionInterval { kind: SessionKind::Main, start: 10 * min, end: 100 * min },
+            SessionInterval { kind: SessionKind::Evening, start: 110 * min, end: 200 * min },
+        ]);
+        let guard = SessionGuard::new(Arc::new(RwLock::new(calendar)), SessionRulesCfg {
+            no_entry_first_min: 5,
+            no_entry_last_min: 10,
+            flatten_before_close_min: Some(15),
+        });
+        let phase = |minute: i64| guard.phase(&Timestamp { seconds: minute * min, nanos: 0 });
+
+        assert_eq!(phase(5), SessionPhase::Auction);
+        assert_eq!(phase(12), SessionPhase::NoEntry);
+        assert_eq!(phase(50), SessionPhase::Open);
+        // конец основной сессии -- не конец дня, позиции не закрываются
+        assert_eq!(phase(95), SessionPhase::NoEntry);
+        assert_eq!(phase(105), SessionPhase::Closed);
+        assert_eq!(phase(150), SessionPhase::Open);
+        assert_eq!(phase(190), SessionPhase::Flatten);
+        assert_eq!(phase(200), SessionPhase::Closed);
+    }
+}
@@ -1,33 +1,762 @@
-use tinkoff_invest_api::tcs::{PortfolioPosition, PortfolioResponse, Quotation, Share};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use prost_types::Timestamp;
+use serde::{Deserialize, Serialize};
+use tinkoff_invest_api::tcs::{OrderDirection, OrderType, PortfolioPosition, PortfolioResponse, PostOrderResponse, Quotation, Share, SubscriptionInterval};
+use tonic::{Response, Status};
+use uuid::Uuid;
+use crate::service::execution_service::{ExecutionService, ParentOrder};
+use crate::service::order_retry::is_retryable;
+use crate::service::order_service::OrderService;
+use crate::service::order_tracker::{OrderFill, OrderTracker};
+use crate::state::candle_state::{CandleState, CandleStateStatistic, SizedRange};
+use crate::state::last_price_state::{LastPriceState, LastPriceStateStatistic};
+use crate::strategy::exit_manager::ExitManager;
+use crate::strategy::pattern_store::PatternStore;
+use crate::strategy::position_sizer::PositionSizer;
+use crate::strategy::session_guard::{current_phase, SessionGuard, SessionPhase};
+use crate::trading_cfg::{ExecutionCfg, ExitCfg, VolatilityCfg};
+use crate::utils::clock::Clock;
+use crate::utils::quotation::QuotationExtension;
 
 pub trait Strategy {
     type Statistic;
-    async fn warm_up(&self, positions: PortfolioResponse) -> Result<(), Box<dyn std::error::Error>>;
+    // восстанавливает сохраненные позиции, сверив с брокером, и возвращает остаток. Вызывается у всех стратегий до первого warm_up,
+    // иначе позиции, сохраненные одной стратегией, разобрала бы стоящая перед ней
+    fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse {
+        positions
+    }
+    // разбирает позиции брокера и возвращает те, что не взяла: их разбирает следующая стратегия
+    async fn warm_up(&mut self, positions: PortfolioResponse) -> Result<PortfolioResponse, Box<dyn Error>>;
     // main logic here. Updating buy/sell signal and making buy/sell orders.
-    async fn update(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+    async fn update(&mut self) -> Result<(), Box<dyn Error>>;
     async fn signal_buy(&self, stat: &Self::Statistic) -> Vec<OpenedPattern>;
     async fn check_pattern(&self, instrument: &Share, stat: &Self::Statistic) -> Option<OpenedPattern>;
     async fn signal_sell(&self, stat: &Self::Statistic) -> Vec<OpenedPattern>;
 }
 
+// необязательные части стратегии, фабрика собирает их из конфига. Без них стратегия торгует 1 лотом, не сохраняет состояние и не смотрит на сессии
+#[derive(Default)]
+pub struct StrategyComponents {
+    pub position_sizer: Option<PositionSizer>,
+    pub pattern_store: Option<PatternStore>,
+    pub session_guard: Option<SessionGuard>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PositionDirection {
+    // открывается покупкой, закрывается продажей
+    #[default]
+    Long,
+    // открывается продажей, закрывается покупкой
+    Short,
+}
+
+impl PositionDirection {
+    // прибыль позиции = sign * (цена - цена открытия)
+    pub fn sign(&self) -> i64 {
+        match self {
+            PositionDirection::Long => 1,
+            PositionDirection::Short => -1,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct OpenedPattern {
     pub figi: String,
+    pub direction: PositionDirection,
+    // всегда положительное, в лотах
     pub quantity: i64,
     pub price_open: Option<Quotation>,
+    // take-profit
     pub price_close: Option<Quotation>,
     pub instrument_id: String,
+    pub exit: ExitLevels,
+}
+
+// состояние выхода из позиции, ведется ExitManager
+#[derive(Debug, Clone, Default)]
+pub struct ExitLevels {
+    // stop-loss, двигается трейлингом и переносом в безубыток
+    pub price_stop: Option<Quotation>,
+    // лучшая цена с момента открытия: максимум для лонга, минимум для шорта
+    pub price_best: Option<Quotation>,
+    // расстояние трейлинг-стопа от price_best
+    pub trailing_distance: Option<Quotation>,
+    // стоп-заявка на стороне брокера
+    pub stop_order_id: Option<String>,
+}
+
+impl OpenedPattern {
+    // без комиссий и платы за заем, lot -- штук в лоте
+    pub fn pnl(&self, price: &Quotation, lot: i32) -> Option<f64> {
+        let price_open = self.price_open.as_ref()?;
+        Some(self.direction.sign() as f64 * (price.to_f() - price_open.to_f()) * (self.quantity * lot.max(1) as i64) as f64)
+    }
+}
+
+// order_id запросов, на которые брокер еще не ответил окончательно. Повтор после временной ошибки идет с тем же id,
+// и если заявка все же была выставлена, брокер вернет ее, а не выставит вторую
+#[derive(Debug, Default)]
+pub struct OrderIntents {
+    ids: HashMap<String, String>,
+}
+
+impl OrderIntents {
+    // ключ -- инструмент и сторона заявки
+    pub fn request_id(&mut self, key: &str) -> String {
+        self.ids.entry(key.to_string()).or_insert_with(|| Uuid::new_v4().to_string()).clone()
+    }
+
+    // id больше не нужен после ответа брокера или ошибки, которую повтор не исправит
+    pub fn resolve<T>(&mut self, key: &str, result: &Result<T, Status>) {
+        match result {
+            Err(e) if is_retryable(e.code()) => {}
+            _ => {
+                self.ids.remove(key);
+            }
+        }
+    }
+}
+
+// лонг -- покупка, шорт -- продажа. Рыночной заявкой
+pub async fn order_open<O: OrderService>(order_service: &mut O, intents: &mut OrderIntents, pattern: &OpenedPattern) -> Result<Response<PostOrderResponse>, Status> {
+    let key = format!("open_{}", pattern.instrument_id);
+    let order_request_id = intents.request_id(&key);
+    let result = match pattern.direction {
+        PositionDirection::Long => order_service.order_buy(order_request_id, pattern.figi.clone(), pattern.instrument_id.clone(), pattern.quantity, None, OrderType::Market).await,
+        PositionDirection::Short => order_service.order_sell(order_request_id, pattern.figi.clone(), pattern.instrument_id.clone(), pattern.quantity, None, OrderType::Market).await,
+    };
+    intents.resolve(&key, &result);
+    result
+}
+
+// лонг -- продажа, шорт -- покупка. Рыночной заявкой
+pub async fn order_close<O: OrderService>(order_service: &mut O, intents: &mut OrderIntents, pattern: &OpenedPattern) -> Result<Response<PostOrderResponse>, Status> {
+    let key = format!("close_{}", pattern.instrument_id);
+    let order_request_id = intents.request_id(&key);
+    let result = match pattern.direction {
+        PositionDirection::Long => order_service.order_sell(order_request_id, pattern.figi.clone(), pattern.instrument_id.clone(), pattern.quantity, None, OrderType::Market).await,
+        PositionDirection::Short => order_service.order_buy(order_request_id, pattern.figi.clone(), pattern.instrument_id.clone(), pattern.quantity, None, OrderType::Market).await,
+    };
+    intents.resolve(&key, &result);
+    result
+}
+
+// заявка стратегии по позиции pattern, позиция меняется только по ее исполнениям
+#[derive(Debug, Clone)]
+pub enum PatternOrder {
+    Open(OpenedPattern),
+    Close(OpenedPattern),
+}
+
+impl PatternOrder {
+    pub fn pattern(&self) -> &OpenedPattern {
+        match self {
+            PatternOrder::Open(pattern) | PatternOrder::Close(pattern) => pattern,
+        }
+    }
+
+    // вход в лонг и выход из шорта -- покупка
+    pub fn direction(&self) -> OrderDirection {
+        match self {
+            PatternOrder::Open(pattern) if pattern.direction == PositionDirection::Long => OrderDirection::Buy,
+            PatternOrder::Close(pattern) if pattern.direction == PositionDirection::Short => OrderDirection::Buy,
+            _ => OrderDirection::Sell,
+        }
+    }
+}
+
+pub type PatternOrderTracker = OrderTracker<PatternOrder>;
+
+// крупные заявки стратегии исполняются алгоритмом ExecutionService. Исполнения дочерних заявок отдаются как OrderFill,
+// родительская заявка в ожидании, пока не исполнена целиком
+pub struct PatternExecution<O: OrderService, C: Clock> {
+    service: ExecutionService<O, C>,
+    cfg: ExecutionCfg,
+    // parent_id -> заявка стратегии и сколько исполнений по ней уже отдано
+    parents: HashMap<String, (PatternOrder, usize)>,
 }
 
-pub fn map_position_to_pattern(position: PortfolioPosition) -> OpenedPattern {
+impl<O: OrderService, C: Clock> PatternExecution<O, C> {
+    pub fn new(service: ExecutionService<O, C>, cfg: ExecutionCfg) -> Self {
+        Self { service, cfg, parents: HashMap::new() }
+    }
+
+    pub fn accepts(&self, quantity: i64) -> bool {
+        quantity >= self.cfg.min_lots
+    }
+
+    pub fn is_pending(&self, instrument_id: &str) -> bool {
+        self.parents.values().any(|(order, _)| order.pattern().instrument_id == instrument_id)
+    }
+
+    pub async fn submit(&mut self, order: PatternOrder, arrival_price: f64) {
+        let pattern = order.pattern();
+        let parent = ParentOrder {
+            figi: pattern.figi.clone(),
+            instrument_id: pattern.instrument_id.clone(),
+            direction: order.direction(),
+            quantity: pattern.quantity,
+            limit_price: None,
+            algo: self.cfg.algo.clone(),
+        };
+        let parent_id = self.service.submit(parent, arrival_price).await;
+        self.parents.insert(parent_id, (order, 0));
+    }
+
+    // новые исполнения дочерних заявок, завершенные родительские перестают отслеживаться
+    pub async fn poll(&mut self) -> Vec<OrderFill<PatternOrder>> {
+        self.service.update().await;
+        let mut fills = Vec::new();
+        let mut completed = Vec::new();
+        for (parent_id, (order, taken)) in self.parents.iter_mut() {
+            let report = match self.service.report(parent_id) {
+                Some(report) => report,
+                None => continue,
+            };
+            for child in &report.fills[*taken..] {
+                fills.push(OrderFill {
+                    order_id: child.order_id.clone(),
+                    instrument_id: report.parent.instrument_id.clone(),
+                    direction: report.parent.direction,
+                    lots: child.quantity,
+                    price: Some(Quotation::from_f(child.price)),
+                    commission: child.commission,
+                    tag: order.clone(),
+                });
+            }
+            *taken = report.fills.len();
+            if report.is_completed {
+                completed.push(parent_id.clone());
+            }
+        }
+        for parent_id in completed {
+            self.parents.remove(&parent_id);
+            self.service.remove(&parent_id);
+        }
+        fills
+    }
+
+    // вход, не добранный к моменту, когда входить уже нельзя, дальше не исполняется
+    pub async fn cancel_entries(&mut self) {
+        let parent_ids: Vec<String> = self.parents.iter()
+            .filter(|(_, (order, _))| matches!(order, PatternOrder::Open(_)))
+            .map(|(parent_id, _)| parent_id.clone())
+            .collect();
+        for parent_id in parent_ids {
+            self.service.cancel(&parent_id).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FillEffect {
+    // индекс новой позиции в opened_patterns
+    Opened(usize),
+    // позиция набрана еще частью заявки на открытие
+    Increased(usize),
+    // позиция закрыта частично
+    Reduced(usize),
+    Closed(OpenedPattern),
+}
+
+// позиция по инструменту и направлению у стратегии одна: открытие добирает лоты по средней цене, закрытие убавляет
+pub fn apply_fill(opened_patterns: &mut Vec<OpenedPattern>, fill: &OrderFill<PatternOrder>) -> Option<FillEffect> {
+    let (pattern, is_open) = match &fill.tag {
+        PatternOrder::Open(pattern) => (pattern, true),
+        PatternOrder::Close(pattern) => (pattern, false),
+    };
+    let index = opened_patterns.iter().position(|opened| opened.instrument_id == pattern.instrument_id && opened.direction == pattern.direction);
+    match (index, is_open) {
+        (None, true) => {
+            opened_patterns.push(OpenedPattern { quantity: fill.lots, price_open: fill.price.clone(), ..pattern.clone() });
+            Some(FillEffect::Opened(opened_patterns.len() - 1))
+        }
+        (Some(index), true) => {
+            let opened = &mut opened_patterns[index];
+            opened.price_open = match (&opened.price_open, &fill.price) {
+                (Some(price_open), Some(price)) => Some(Quotation::from_f(
+                    (price_open.to_f() * opened.quantity as f64 + price.to_f() * fill.lots as f64) / (opened.quantity + fill.lots) as f64
+                )),
+                (price_open, price) => price_open.clone().or(price.clone()),
+            };
+            opened.quantity += fill.lots;
+            Some(FillEffect::Increased(index))
+        }
+        (Some(index), false) => {
+            opened_patterns[index].quantity -= fill.lots;
+            if opened_patterns[index].quantity > 0 {
+                Some(FillEffect::Reduced(index))
+            } else {
+                Some(FillEffect::Closed(opened_patterns.remove(index)))
+            }
+        }
+        (None, false) => None,
+    }
+}
+
+// quantity в портфеле в штуках, а заявки выставляются в лотах. Шорт в портфеле -- отрицательное количество
+pub fn map_position_to_pattern(position: PortfolioPosition, lot: i32) -> OpenedPattern {
+    let units = position.quantity.unwrap().units;
     OpenedPattern {
         figi: position.figi,
-        quantity: position.quantity.unwrap().units,
+        direction: if units < 0 { PositionDirection::Short } else { PositionDirection::Long },
+        quantity: units.abs() / lot.max(1) as i64,
         price_open: Option::from(Quotation {
             units: position.average_position_price.clone().unwrap().units,
             nano: position.average_position_price.unwrap().nano,
         }),
         price_close: None,
         instrument_id: position.instrument_uid,
+        exit: ExitLevels::default(),
+    }
+}
+
+// сигналы стратегии по одному инструменту. Заявки и их исполнения, уровни выхода, сессии и сохранение состояния ведет PatternRunner
+pub trait PatternSignals {
+    // для сообщений в лог
+    const NAME: &'static str;
+
+    fn exit_cfg(&self) -> Option<&ExitCfg>;
+
+    // источник ATR для стопов в exit_cfg
+    fn volatility_cfg(&self) -> Option<&VolatilityCfg> {
+        None
+    }
+
+    // таймфрейм окон стратегии, в том числе окна ATR
+    fn interval(&self) -> SubscriptionInterval {
+        SubscriptionInterval::OneMinute
+    }
+
+    // шорт из позиций брокера принимает только стратегия, которая сама открывает шорты
+    fn allow_short(&self) -> bool {
+        false
+    }
+
+    // перед warm_up и каждым update
+    fn prepare<O: OrderService, C: Clock>(&mut self, _runner: &PatternRunner<O, C>) {}
+
+    // цель для позиции, открытой до запуска, None -- цель из exit_cfg или по сигналу выхода
+    async fn restore_target<O: OrderService, C: Clock>(&self, _runner: &PatternRunner<O, C>, _direction: PositionDirection, _price_open: f64) -> Option<Quotation> {
+        None
+    }
+
+    // уровни выхода по цене открытия новой позиции
+    fn attach(&self, exit_manager: &ExitManager, pattern: &mut OpenedPattern, atr: Option<f64>) {
+        exit_manager.attach(pattern, atr);
+    }
+
+    // брокер принял заявку на вход
+    async fn on_entry<O: OrderService, C: Clock>(&mut self, _runner: &PatternRunner<O, C>) {}
+
+    // по умолчанию одна позиция на инструмент
+    async fn signal_buy<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern> {
+        let mut to_buy = Vec::new();
+        if runner.opened_patterns.is_empty() {
+            to_buy.extend(self.check_pattern(runner, &runner.instrument, stat).await);
+        }
+        to_buy
+    }
+
+    async fn check_pattern<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, instrument: &Share, stat: &CandleState) -> Option<OpenedPattern>;
+
+    async fn signal_sell<O: OrderService, C: Clock>(&self, runner: &PatternRunner<O, C>, stat: &CandleState) -> Vec<OpenedPattern>;
+}
+
+// общее для стратегий по одному инструменту: позиции меняются только по исполнениям заявок, стопы ведет ExitManager, состояние пишется в PatternStore
+pub struct PatternRunner<O: OrderService, C: Clock> {
+    pub statistic: Arc<CandleState>,
+    pub last_price_state: Arc<LastPriceState>,
+    pub clock: C,
+    pub instrument: Share,
+    pub opened_patterns: Vec<OpenedPattern>,
+    pub exit_manager: Option<ExitManager>,
+    pub position_sizer: Option<PositionSizer>,
+    order_service: O,
+    pattern_store: Option<PatternStore>,
+    session_guard: Option<SessionGuard>,
+    order_tracker: PatternOrderTracker,
+    order_intents: OrderIntents,
+    execution: Option<PatternExecution<O, C>>,
+}
+
+impl<O: OrderService, C: Clock> PatternRunner<O, C> {
+    // последняя цена, если ее еще нет -- закрытие последней минутной свечи
+    pub async fn current_price(&self) -> Option<Quotation> {
+        match self.last_price_state.get_last_price(&self.instrument.uid).await {
+            Some(price) => Some(price),
+            None => self.statistic.get_last_candle(&self.instrument.uid, SubscriptionInterval::OneMinute).await
+                .and_then(|candle| candle.close),
+        }
+    }
+
+    // окно window_size_min минут, заканчивающееся сейчас
+    pub fn window_range(&self, interval: SubscriptionInterval, window_size_min: u64) -> SizedRange {
+        let window_time_end = self.clock.now();
+        let window_time_start = Timestamp { seconds: window_time_end.seconds - window_size_min as i64 * 60, nanos: window_time_end.nanos };
+        SizedRange::new(interval, window_time_start, window_time_end)
+    }
+
+    pub async fn current_atr(&self, volatility_cfg: Option<&VolatilityCfg>, interval: SubscriptionInterval) -> Option<f64> {
+        let volatility_cfg = volatility_cfg?;
+        self.statistic.get_volatility(volatility_cfg, &self.instrument.uid, self.window_range(interval, volatility_cfg.window_size_min)).await
+            .map(|volatility| volatility.atr)
+    }
+
+    // начальный стоп из exit_cfg, нужен для размера позиции
+    pub fn stop_price(&self, direction: PositionDirection, price: f64, atr: Option<f64>) -> Option<f64> {
+        self.exit_manager.as_ref().and_then(|exit_manager| exit_manager.stop_price(direction, price, atr))
+    }
+
+    // без PositionSizer стратегия торгует одним лотом
+    pub async fn lots(&self, direction: PositionDirection, price: f64, stop_price: Option<f64>, signal_strength: f64) -> i64 {
+        match &self.position_sizer {
+            Some(position_sizer) => position_sizer.lots(&self.instrument, direction, price, stop_price, signal_strength, &self.clock.now()).await,
+            None => 1,
+        }
+    }
+
+    // пока по инструменту есть незавершенная заявка, новая по нему не выставляется
+    fn is_pending(&self, instrument_id: &str) -> bool {
+        self.order_tracker.is_pending(instrument_id) ||
+            self.execution.as_ref().map(|execution| execution.is_pending(instrument_id)).unwrap_or(false)
+    }
+
+    // заявка от min_lots лотов отдается алгоритму исполнения, меньшая или без текущей цены -- одной рыночной
+    async fn post_order<S: PatternSignals>(&mut self, signals: &S, order: PatternOrder) -> Result<(), Status> {
+        let arrival_price = match &self.execution {
+            Some(execution) if execution.accepts(order.pattern().quantity) => self.current_price().await.map(|price| price.to_f()),
+            _ => None,
+        };
+        if let (Some(execution), Some(arrival_price)) = (&mut self.execution, arrival_price) {
+            execution.submit(order, arrival_price).await;
+            return Ok(());
+        }
+        let result = match &order {
+            PatternOrder::Open(pattern) => order_open(&mut self.order_service, &mut self.order_intents, pattern).await,
+            PatternOrder::Close(pattern) => order_close(&mut self.order_service, &mut self.order_intents, pattern).await,
+        };
+        let fill = self.order_tracker.track(&result?.into_inner(), order);
+        self.apply_fills(signals, fill.into_iter().collect()).await;
+        Ok(())
+    }
+
+    fn save_state(&mut self) {
+        if let Some(store) = &mut self.pattern_store {
+            store.save(&self.opened_patterns)
+                .unwrap_or_else(|e| eprintln!("Error while saving state of instrument_id={}: {}", self.instrument.uid, e));
+        }
+    }
+
+    // позиции меняются только по исполнениям, стоп у брокера выставляется на набранное количество
+    async fn apply_fills<S: PatternSignals>(&mut self, signals: &S, fills: Vec<OrderFill<PatternOrder>>) {
+        if fills.is_empty() {
+            return;
+        }
+        let current_price = self.current_price().await;
+        let atr = self.current_atr(signals.volatility_cfg(), signals.interval()).await;
+        for mut fill in fills {
+            fill.price = fill.price.or(current_price.clone());
+            println!("Fill order={} direction={:?} lots={} price={:?} commission={}", fill.order_id, fill.direction, fill.lots, fill.price, fill.commission);
+            let index = match apply_fill(&mut self.opened_patterns, &fill) {
+                Some(FillEffect::Opened(index)) => {
+                    if let Some(exit_manager) = &self.exit_manager {
+                        signals.attach(exit_manager, &mut self.opened_patterns[index], atr);
+                    }
+                    index
+                }
+                Some(FillEffect::Increased(index)) => index,
+                Some(FillEffect::Reduced(index)) => {
+                    println!("Partially closed={:#?}", self.opened_patterns[index]);
+                    continue;
+                }
+                Some(FillEffect::Closed(pattern)) => {
+                    println!("Closed={:#?} at price={:?}", pattern, fill.price);
+                    continue;
+                }
+                None => continue,
+            };
+            if let Some(exit_manager) = &self.exit_manager {
+                exit_manager.sync_broker_stop(&mut self.order_service, &mut self.opened_patterns[index]).await;
+            }
+        }
+    }
+
+    // сохраненные позиции восстанавливаются с прежними целью и стопами
+    pub fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse {
+        match &mut self.pattern_store {
+            Some(store) => {
+                let (restored, positions) = store.restore(positions, std::slice::from_ref(&self.instrument));
+                for pattern in restored {
+                    println!("Restored={:#?}", pattern);
+                    self.opened_patterns.push(pattern);
+                }
+                positions
+            }
+            None => positions,
+        }
+    }
+
+    // остаток после restore разбирается по позиции брокера
+    pub async fn warm_up<S: PatternSignals>(&mut self, signals: &mut S, mut positions: PortfolioResponse) -> Result<PortfolioResponse, Box<dyn Error>> {
+        signals.prepare(self);
+        let atr = self.current_atr(signals.volatility_cfg(), signals.interval()).await;
+        let current_price = self.current_price().await;
+        let mut unclaimed = Vec::new();
+        for position in std::mem::take(&mut positions.positions) {
+            if position.instrument_uid != self.instrument.uid {
+                unclaimed.push(position);
+                continue;
+            }
+            let quantity = position.quantity.clone().map(|quantity| quantity.units).unwrap_or(0);
+            // стратегия торгует только целыми лотами, шорты -- только с allow_short, остальные позиции открыты не ей
+            let is_direction_allowed = quantity > 0 || quantity < 0 && signals.allow_short();
+            if !is_direction_allowed || quantity % self.instrument.lot.max(1) as i64 != 0 || position.blocked {
+                eprintln!("{} doesn't recognise position, skip it: {:#?}", S::NAME, position);
+                unclaimed.push(position);
+                continue;
+            }
+            let mut pattern = map_position_to_pattern(position, self.instrument.lot);
+            let price_open = pattern.price_open.clone().unwrap().to_f();
+            pattern.price_close = signals.restore_target(self, pattern.direction, price_open).await;
+            // стопы восстанавливаются по текущему ATR и сразу подтягиваются к текущей цене
+            if let Some(exit_manager) = &self.exit_manager {
+                signals.attach(exit_manager, &mut pattern, atr);
+                if let Some(price) = &current_price {
+                    exit_manager.update_levels(&mut pattern, price);
+                }
+            }
+            println!("Warm_up={:#?}", pattern);
+            self.opened_patterns.push(pattern);
+        }
+        self.save_state();
+        positions.positions = unclaimed;
+        Ok(positions)
+    }
+
+    pub async fn update<S: PatternSignals>(&mut self, signals: &mut S) -> Result<(), Box<dyn Error>> {
+        signals.prepare(self);
+        let mut fills = self.order_tracker.poll(&mut self.order_service, self.clock.now().seconds).await;
+        if let Some(execution) = &mut self.execution {
+            fills.extend(execution.poll().await);
+        }
+        self.apply_fills(signals, fills).await;
+        if let Some(exit_manager) = &self.exit_manager {
+            for pattern in exit_manager.take_executed_stops(&mut self.order_service, &mut self.opened_patterns).await {
+                println!("Closed by broker stop={:#?}", pattern);
+            }
+        }
+        let phase = current_phase(&self.session_guard, &self.clock);
+        if !phase.allows_entry() {
+            if let Some(execution) = &mut self.execution {
+                execution.cancel_entries().await;
+            }
+        }
+        if !phase.is_trading() {
+            self.save_state();
+            return Ok(());
+        }
+        let orders_to_buy = if phase.allows_entry() { signals.signal_buy(self, &self.statistic).await } else { Vec::new() };
+        let current_price = self.current_price().await;
+        for order in orders_to_buy {
+            // пока заявка по инструменту не завершена, новая не выставляется
+            if self.is_pending(&order.instrument_id) {
+                continue;
+            }
+            match self.post_order(signals, PatternOrder::Open(order)).await {
+                Ok(()) => signals.on_entry(self).await,
+                Err(e) => eprintln!("Error in orders_to_buy: {}", e.message())
+            }
+        }
+
+        // все позиции runner по одному инструменту
+        let is_pending = self.is_pending(&self.instrument.uid);
+        if let (Some(exit_manager), Some(price), false) = (&self.exit_manager, &current_price, is_pending) {
+            for order in self.opened_patterns.iter_mut() {
+                if exit_manager.update_levels(order, price) {
+                    exit_manager.sync_broker_stop(&mut self.order_service, order).await;
+                }
+            }
+        }
+
+        let orders_to_sell = if phase == SessionPhase::Flatten { self.opened_patterns.clone() } else { signals.signal_sell(self, &self.statistic).await };
+        for order in orders_to_sell {
+            if self.is_pending(&order.instrument_id) {
+                continue;
+            }
+            let index = match self.opened_patterns.iter().position(|x| x.instrument_id == order.instrument_id) {
+                Some(index) => index,
+                None => continue,
+            };
+            // стоп снимается до закрытия, иначе он может сработать вместе с ним
+            if let Some(exit_manager) = &self.exit_manager {
+                exit_manager.cancel_broker_stop(&mut self.order_service, &mut self.opened_patterns[index]).await;
+            }
+            let instrument_id = order.instrument_id.clone();
+            if let Err(e) = self.post_order(signals, PatternOrder::Close(order)).await {
+                eprintln!("Error in orders_to_sell: {}", e.message());
+                // закрытие не принято -- позиция снова под стопом у брокера
+                if let (Some(exit_manager), Some(opened)) = (&self.exit_manager, self.opened_patterns.iter_mut().find(|x| x.instrument_id == instrument_id)) {
+                    exit_manager.sync_broker_stop(&mut self.order_service, opened).await;
+                }
+            }
+        }
+        self.save_state();
+        Ok(())
+    }
+}
+
+// стратегия по одному инструменту -- сигналы S поверх PatternRunner
+pub struct PatternStrategy<S: PatternSignals, O: OrderService, C: Clock> {
+    runner: PatternRunner<O, C>,
+    signals: S,
+}
+
+impl<S: PatternSignals, O: OrderService, C: Clock> PatternStrategy<S, O, C> {
+    pub fn new(
+        statistic: Arc<CandleState>,
+        last_price_state: Arc<LastPriceState>,
+        order_service: O,
+        clock: C,
+        components: StrategyComponents,
+        instrument: Share,
+        signals: S,
+    ) -> Self {
+        let runner = PatternRunner {
+            statistic,
+            last_price_state,
+            clock,
+            instrument,
+            opened_patterns: Vec::new(),
+            exit_manager: signals.exit_cfg().cloned().map(ExitManager::new),
+            position_sizer: components.position_sizer,
+            order_service,
+            pattern_store: components.pattern_store,
+            session_guard: components.session_guard,
+            order_tracker: PatternOrderTracker::default(),
+            order_intents: OrderIntents::default(),
+            execution: None,
+        };
+        Self { runner, signals }
+    }
+
+    // без исполнения алгоритмом любая заявка выставляется одной рыночной
+    pub fn with_execution(mut self, execution: Option<PatternExecution<O, C>>) -> Self {
+        self.runner.execution = execution;
+        self
+    }
+}
+
+impl<S: PatternSignals, O: OrderService, C: Clock> Strategy for PatternStrategy<S, O, C> {
+    type Statistic = CandleState;
+
+    fn restore(&mut self, positions: PortfolioResponse) -> PortfolioResponse {
+        self.runner.restore(positions)
+    }
+
+    async fn warm_up(&mut self, positions: PortfolioResponse) -> Result<PortfolioResponse, Box<dyn Error>> {
+        self.runner.warm_up(&mut self.signals, positions).await
+    }
+
+    async fn update(&mut self) -> Result<(), Box<dyn Error>> {
+        self.runner.update(&mut self.signals).await
+    }
+
+    async fn signal_buy(&self, stat: &Self::Statistic) -> Vec<OpenedPattern> {
+        self.signals.signal_buy(&self.runner, stat).await
+    }
+
+    async fn check_pattern(&self, instrument: &Share, stat: &Self::Statistic) -> Option<OpenedPattern> {
+        self.signals.check_pattern(&self.runner, instrument, stat).await
+    }
+
+    async fn signal_sell(&self, stat: &Self::Statistic) -> Vec<OpenedPattern> {
+        self.signals.signal_sell(&self.runner, stat).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tinkoff_invest_api::tcs::{OrderDirection, Quotation};
+    use tonic::Status;
+    use crate::service::order_tracker::OrderFill;
+    use crate::strategy::strategy::{apply_fill, ExitLevels, FillEffect, OpenedPattern, OrderIntents, PatternOrder, PositionDirection};
+
+    fn fill(tag: PatternOrder, lots: i64, price: i64) -> OrderFill<PatternOrder> {
+        OrderFill {
+            order_id: "1".to_string(),
+            instrument_id: "uid".to_string(),
+            direction: OrderDirection::Buy,
+            lots,
+            price: Some(Quotation { units: price, nano: 0 }),
+            commission: 0.0,
+            tag,
+        }
+    }
+
+    #[test]
+    fn test_apply_fill() {
+        let pattern = OpenedPattern {
+            figi: "".to_string(),
+            direction: PositionDirection::Long,
+            quantity: 3,
+            price_open: None,
+            price_close: None,
+            instrument_id: "uid".to_string(),
+            exit: ExitLevels::default(),
+        };
+        let mut opened_patterns = Vec::new();
+
+        assert!(matches!(apply_fill(&mut opened_patterns, &fill(PatternOrder::Open(pattern.clone()), 1, 100)), Some(FillEffect::Opened(0))));
+        assert!(matches!(apply_fill(&mut opened_patterns, &fill(PatternOrder::Open(pattern.clone()), 2, 103)), Some(FillEffect::Increased(0))));
+        assert_eq!(opened_patterns[0].quantity, 3);
+        assert_eq!(opened_patterns[0].price_open, Some(Quotation { units: 102, nano: 0 }));
+
+        // закрытие чужого направления позицию не трогает
+        let short = OpenedPattern { direction: PositionDirection::Short, ..pattern.clone() };
+        assert!(apply_fill(&mut opened_patterns, &fill(PatternOrder::Close(short), 1, 100)).is_none());
+
+        assert!(matches!(apply_fill(&mut opened_patterns, &fill(PatternOrder::Close(pattern.clone()), 2, 110)), Some(FillEffect::Reduced(0))));
+        assert_eq!(opened_patterns[0].quantity, 1);
+        assert!(matches!(apply_fill(&mut opened_patterns, &fill(PatternOrder::Close(pattern), 1, 110)), Some(FillEffect::Closed(_))));
+        assert!(opened_patterns.is_empty());
+    }
+
+    #[test]
+    fn test_pattern_order_direction() {
+        let long = OpenedPattern {
+            figi: "".to_string(),
+            direction: PositionDirection::Long,
+            quantity: 1,
+            price_open: None,
+            price_close: None,
+            instrument_id: "uid".to_string(),
+            exit: ExitLevels::default(),
+        };
+        let short = OpenedPattern { direction: PositionDirection::Short, ..long.clone() };
+        assert_eq!(PatternOrder::Open(long.clone()).direction(), OrderDirection::Buy);
+        assert_eq!(PatternOrder::Close(long).direction(), OrderDirection::Sell);
+        assert_eq!(PatternOrder::Open(short.clone()).direction(), OrderDirection::Sell);
+        assert_eq!(PatternOrder::Close(short).direction(), OrderDirection::Buy);
+    }
+
+    #[test]
+    fn test_order_intents() {
+        let mut intents = OrderIntents::default();
+        let id = intents.request_id("open_uid");
+        assert_ne!(id, intents.request_id("close_uid"));
+
+        // временная ошибка -- повтор с тем же id
+        intents.resolve::<()>("open_uid", &Err(Status::unavailable("")));
+        assert_eq!(intents.request_id("open_uid"), id);
+
+        intents.resolve::<()>("open_uid", &Err(Status::invalid_argument("")));
+        let id = intents.request_id("open_uid");
+        intents.resolve("open_uid", &Ok(()));
+        assert_ne!(intents.request_id("open_uid"), id);
     }
 }
@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+use tinkoff_invest_api::tcs::SubscriptionInterval;
+use crate::analytics::moving_average::MovingAverageKind;
+use crate::analytics::trend::TrendDirection;
+use crate::analytics::volatility::VolatilityRegime;
+
 #[derive(Debug, Clone)]
 pub struct HammerCfg {
     pub bottom_start: u8,
@@ -10,7 +16,202 @@ pub struct HammerCfg {
 }
 #[derive(Debug, Clone)]
 pub struct TrendCfg {
-    pub max_candle_skip: i8
+    pub max_candle_skip: i8,
+    // период сглаживания ADX в свечах
+    pub adx_period: usize,
+    // сколько свеч с каждой стороны должно быть ниже(выше) экстремума
+    pub swing_depth: usize,
+    // наклон регрессии в % за свечу, ниже которого тренд считается боковым
+    pub flat_slope_prc: f64,
+    // минимальная сила тренда 0-100 для входа в сделку
+    pub min_strength: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LevelsCfg {
+    // сколько истории берем для поиска уровней
+    pub window_size_min: u64, // in minutes
+    pub swing_depth: usize,
+    // допуск в % от цены, в пределах которого экстремумы склеиваются в один уровень
+    pub cluster_prc: f64,
+    pub min_touches: usize,
+    // через сколько минут касание весит в два раза меньше
+    pub recency_half_life_min: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeCfg {
+    // сколько свеч берем для среднего объема
+    pub avg_period: usize,
+    // во сколько раз объем выше среднего, чтобы считаться всплеском
+    pub spike_ratio: f64,
+    // во сколько раз объем выше среднего, чтобы подтвердить паттерн или пробой
+    pub confirm_ratio: f64,
+    // во сколько раз объем по направлению тренда должен превышать объем против него
+    pub trend_volume_ratio: f64,
+    // на сколько ценовых корзин делится профиль объема
+    pub profile_buckets: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct VolatilityCfg {
+    // сколько истории берем для оценки волатильности и режима
+    pub window_size_min: u64, // in minutes
+    pub atr_period: usize,
+    // 0-100, перцентили текущей волатильности, ниже/выше которых режим низкий/высокий
+    pub low_percentile: f64,
+    pub high_percentile: f64,
+    // цель = high + atr * target_atr_multiplier
+    pub target_atr_multiplier: f64,
+    // в каких режимах разрешено открывать сделки
+    pub allowed_regimes: Vec<VolatilityRegime>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TrailingCfg {
+    // расстояние стопа от максимума в % от цены открытия
+    Percent(f64),
+    // расстояние стопа от максимума в ATR на момент открытия
+    Atr(f64),
+}
+
+// все уровни считаются от цены открытия позиции
+#[derive(Debug, Clone)]
+pub struct ExitCfg {
+    pub stop_loss_prc: Option<f64>,
+    // стоп в ATR, если задан вместе со stop_loss_prc -- берется ближний к цене
+    pub stop_loss_atr: Option<f64>,
+    // используется, если стратегия сама не задала цель
+    pub take_profit_prc: Option<f64>,
+    pub trailing: Option<TrailingCfg>,
+    // после роста на break_even_prc стоп переносится в цену открытия
+    pub break_even_prc: Option<f64>,
+    // выставлять stop-loss заявкой у брокера, а не только проверять по тикам
+    pub use_broker_stops: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct BollingerCfg {
+    // период средней в свечах
+    pub period: usize,
+    // ширина канала в стандартных отклонениях
+    pub std_multiplier: f64,
+    // в более узком канале (в % от средней) цель у средней не покрывает комиссию
+    pub min_width_prc: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RsiCfg {
+    pub period: usize,
+    // 0-100, ниже -- перепроданность
+    pub oversold: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MovingAverageCfg {
+    pub kind: MovingAverageKind,
+    // периоды в свечах
+    pub fast_period: usize,
+    pub slow_period: usize,
+}
+
+// размер позиции от риска: сколько капитала стратегии теряем, если сработает стоп
+#[derive(Debug, Clone)]
+pub struct SizingCfg {
+    // риск на сделку в % от капитала стратегии
+    pub risk_prc: f64,
+    // расстояние до стопа в % от цены, если стоп не задан
+    pub default_stop_prc: f64,
+    // 0-1, доля риска при самом слабом сигнале. При сильнейшем берется весь risk_prc
+    pub min_signal_factor: f64,
+    // максимальная позиция по одному инструменту в % от капитала стратегии
+    pub max_position_prc: f64,
+    // лимит позиции в лотах по тикеру
+    pub max_lots: HashMap<String, i64>,
+    // если не задано -- корреляция с уже открытыми позициями не проверяется
+    pub correlation: Option<CorrelationCfg>,
+}
+
+// правила по времени торговой сессии, сессии берутся из торгового календаря
+#[derive(Debug, Clone)]
+pub struct SessionRulesCfg {
+    // не открывать позиции первые N минут после начала сессии
+    pub no_entry_first_min: u32,
+    // не открывать позиции последние N минут до конца сессии
+    pub no_entry_last_min: u32,
+    // закрыть все позиции за N минут до конца сессии, None -- позиции переносятся
+    pub flatten_before_close_min: Option<u32>,
+}
+
+// повтор выставления заявки при временных ошибках, все попытки идут с одним order_id
+#[derive(Debug, Clone)]
+pub struct OrderRetryCfg {
+    // вместе с первой
+    pub max_attempts: u32,
+    // пауза перед второй попыткой, дальше удваивается
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+// как крупная (родительская) заявка режется на дочерние
+#[derive(Debug, Clone)]
+pub enum ExecutionAlgo {
+    // равные части рыночными заявками через равные промежутки
+    Twap { duration_sec: i64, slices: usize },
+    // части пропорционально объему в те же минуты предыдущих lookback_days дней
+    Vwap { duration_sec: i64, slices: usize, lookback_days: i64 },
+    // лимитная заявка на visible_lots, следующая -- после исполнения предыдущей
+    Iceberg { visible_lots: i64, orders_check_sec: i64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutionCfg {
+    pub algo: ExecutionAlgo,
+    // заявки меньше min_lots выставляются одной рыночной
+    pub min_lots: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorrelationCfg {
+    // окно минутных свечей, по которому считается корреляция
+    pub window_size_min: u64,
+    // окно скользящей корреляции в свечах
+    pub rolling_window: usize,
+    // при |корреляции| выше этого значения позиции считаются одной ставкой
+    pub max_correlation: f64,
+}
+
+#[derive(Debug, Clone)]
+pub enum TimeframeCondition {
+    TrendBullish(TrendCfg),
+    TrendBearish(TrendCfg),
+    TrendFlat(TrendCfg),
+    // направление по регрессии с силой не ниже trend_cfg.min_strength
+    TrendDirection(TrendCfg, TrendDirection),
+    HammerBullish(HammerCfg),
+    HammerBearish(HammerCfg),
+    VolumeSpike(VolumeCfg),
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeframeRule {
+    pub interval: SubscriptionInterval,
+    // окно, на котором проверяется условие
+    pub window_size_min: u64, // in minutes
+    pub condition: TimeframeCondition,
+}
+
+#[derive(Debug, Clone)]
+pub enum TimeframeCombine {
+    All,
+    Any,
+}
+
+// например: молот на 1m берем только если на 5m нисходящий тренд
+#[derive(Debug, Clone)]
+pub struct MultiTimeframeCfg {
+    pub rules: Vec<TimeframeRule>,
+    pub combine: TimeframeCombine,
 }
 
 #[derive(Debug, Clone)]
@@ -19,6 +220,121 @@ pub struct HammerStrategySettings {
     pub trend_cfg: TrendCfg,
     // как далеко мы смотрим назад при поиске паттерна при покупке
     pub window_size_min: u64, // in minutes
+    // если задано -- молот должен быть у поддержки, цель -- ближайшее сопротивление
+    pub levels_cfg: Option<LevelsCfg>,
+    // если задано -- молот и тренд должны подтверждаться объемом
+    pub volume_cfg: Option<VolumeCfg>,
+    // если задано -- сигнал должен подтверждаться на старших таймфреймах
+    pub confirmation: Option<MultiTimeframeCfg>,
+    // если задано -- цель считается от ATR, сделки открываются только в разрешенных режимах
+    pub volatility_cfg: Option<VolatilityCfg>,
+    pub exit_cfg: Option<ExitCfg>,
+    // шорт на медвежьем молоте в восходящем тренде
+    pub allow_short: bool,
+    // подтверждение шорта на старших таймфреймах, confirmation действует только на лонг
+    pub short_confirmation: Option<MultiTimeframeCfg>,
+    // если задано -- молот, тренд, объем и таймфреймы голосуют, а не должны выполниться все сразу
+    pub ensemble_cfg: Option<EnsembleCfg>,
+}
+
+// детектор, способный отменить решение ансамбля, если голосует против
+#[derive(Debug, Clone)]
+pub struct VetoCfg {
+    pub signal: String,
+    // 0-1, вето при score * confidence против решения не меньше этого
+    pub min_against: f64,
+}
+
+// взвешенное голосование детекторов вместо жесткого И всех условий
+#[derive(Debug, Clone)]
+pub struct EnsembleCfg {
+    // вес по имени детектора
+    pub weights: HashMap<String, f64>,
+    // вес детектора, которого нет в weights. 0 -- такие детекторы не голосуют
+    pub default_weight: f64,
+    // 0-1, итоговый score не ниже этого -- покупка
+    pub buy_threshold: f64,
+    // 0-1, итоговый score не выше минус этого -- продажа
+    pub sell_threshold: f64,
+    pub vetoes: Vec<VetoCfg>,
+}
+
+// покупка при закрытии ниже нижней полосы Боллинджера и перепроданности по RSI, выход на средней полосе или по стопу
+#[derive(Debug, Clone)]
+pub struct BollingerStrategySettings {
+    pub bollinger_cfg: BollingerCfg,
+    pub rsi_cfg: RsiCfg,
+    // сколько истории берем для полос и RSI, должно покрывать оба периода
+    pub window_size_min: u64, // in minutes
+    // если задано -- сделки открываются только в разрешенных режимах волатильности
+    pub volatility_cfg: Option<VolatilityCfg>,
+    pub exit_cfg: Option<ExitCfg>,
+}
+
+// пересечение быстрой и медленной средних, базовая трендовая стратегия для сравнения с паттернами
+#[derive(Debug, Clone)]
+pub struct MaCrossoverStrategySettings {
+    pub ma_cfg: MovingAverageCfg,
+    // на каком интервале считаются средние и ATR
+    pub interval: SubscriptionInterval,
+    // сколько истории берем, должно покрывать slow_period свеч интервала
+    pub window_size_min: u64, // in minutes
+    // фильтр по тренду старшего таймфрейма
+    pub confirmation: Option<MultiTimeframeCfg>,
+    // источник ATR для стопов и трейлинга в exit_cfg
+    pub volatility_cfg: Option<VolatilityCfg>,
+    pub exit_cfg: Option<ExitCfg>,
+}
+
+// вход при закрытии выше максимума entry_period свечей, выход при закрытии ниже минимума exit_period свечей или по трейлингу
+#[derive(Debug, Clone)]
+pub struct DonchianStrategySettings {
+    // периоды каналов в свечах interval
+    pub entry_period: usize,
+    pub exit_period: usize,
+    pub interval: SubscriptionInterval,
+    // сколько истории берем, должно покрывать entry_period и exit_period свеч интервала
+    pub window_size_min: u64, // in minutes
+    // если задано -- пробой всего окна должен подтверждаться объемом выше volume_cfg.confirm_ratio,
+    // а закрытие -- уйти за point of control профиля объема из volume_cfg.profile_buckets корзин
+    pub volume_cfg: Option<VolumeCfg>,
+    // если задано -- сделки открываются только в разрешенных режимах волатильности, ATR для стопов
+    pub volatility_cfg: Option<VolatilityCfg>,
+    pub exit_cfg: Option<ExitCfg>,
+    // открывать шорт на пробое вниз
+    pub allow_short: bool,
+}
+
+// лестница лимитных заявок с шагом step_prc вокруг опорной цены
+#[derive(Debug, Clone)]
+pub struct GridStrategySettings {
+    // шаг сетки в % от опорной цены
+    pub step_prc: f64,
+    // сколько заявок на покупку ниже опорной цены
+    pub levels_count: usize,
+    pub lots_per_level: i64,
+    // как часто сверяться с активными заявками брокера
+    pub orders_check_sec: i64,
+}
+
+// парная торговля на спреде лог-цен двух инструментов: первый из tickers -- основной, второй -- хедж
+#[derive(Debug, Clone)]
+pub struct PairStrategySettings {
+    // окно для hedge ratio
+    pub window_size_min: u64,
+    // окно среднего и отклонения спреда в свечах
+    pub zscore_window: usize,
+    // открытие при |z| >= entry_zscore, закрытие при |z| <= exit_zscore или стоп при |z| >= stop_zscore
+    pub entry_zscore: f64,
+    pub exit_zscore: f64,
+    pub stop_zscore: f64,
+    // лоты основного инструмента, лоты хеджа считаются по hedge ratio
+    pub lots: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FirstStrategySettings {
+    pub exit_cfg: Option<ExitCfg>,
 }
 
 impl HammerCfg {
@@ -39,4 +355,57 @@ impl HammerCfg {
             panic!("Incorrect HammerCfg")
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone)]
+pub enum StrategySettings {
+    First(FirstStrategySettings),
+    Hammer(HammerStrategySettings),
+    Bollinger(BollingerStrategySettings),
+    MaCrossover(MaCrossoverStrategySettings),
+    Donchian(DonchianStrategySettings),
+    Grid(GridStrategySettings),
+    Pair(PairStrategySettings),
+    Script(ScriptStrategySettings),
+}
+
+// ограничения песочницы скрипта: скрипт не может ни зациклиться, ни запросить индикатор на всю историю
+#[derive(Debug, Clone)]
+pub struct ScriptLimitsCfg {
+    pub max_source_len: usize,
+    pub max_statements: usize,
+    // вложенность выражений
+    pub max_depth: usize,
+    // шагов вычисления за один запуск, индикатор стоит столько шагов, сколько у него период
+    pub max_ops: usize,
+    pub max_period: usize,
+}
+
+// правила покупки и продажи в файле скрипта, файл перечитывается при изменении без перезапуска бота
+#[derive(Debug, Clone)]
+pub struct ScriptStrategySettings {
+    pub path: String,
+    // сколько минутных свеч видит скрипт
+    pub window_size_min: u64,
+    // как часто проверять, не изменился ли файл
+    pub reload_sec: i64,
+    pub limits: ScriptLimitsCfg,
+    pub exit_cfg: Option<ExitCfg>,
+}
+
+// одна запись -- одна стратегия в registry, kind -- имя, под которым зарегистрирована фабрика
+#[derive(Debug, Clone)]
+pub struct StrategyCfg {
+    pub name: String,
+    pub kind: String,
+    pub tickers: Vec<String>,
+    // доля капитала 0-100
+    pub capital_share: u8,
+    // если не задано -- стратегия торгует одним лотом
+    pub sizing: Option<SizingCfg>,
+    // если не задано -- стратегия торгует на любом тике, без оглядки на календарь
+    pub session_rules: Option<SessionRulesCfg>,
+    // если не задано -- заявка выставляется одной рыночной при любом размере
+    pub execution: Option<ExecutionCfg>,
+    pub settings: StrategySettings,
+}
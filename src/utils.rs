@@ -2,4 +2,5 @@ pub mod cmp;
 pub mod wrapper_mock_system_time;
 pub mod local_tokens;
 pub mod quotation;
-pub mod candle;
\ No newline at end of file
+pub mod candle;
+pub mod clock;
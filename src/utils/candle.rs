@@ -50,4 +50,21 @@ impl CandleExtension for Candle {
         }
         close_prc
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub fn new_test_candle(minute: i64, open: f64, high: f64, low: f64, close: f64, volume: i64) -> Candle {
+    use prost_types::Timestamp;
+    use tinkoff_invest_api::tcs::{Quotation, SubscriptionInterval};
+    Candle {
+        figi: "test".to_string(),
+        interval: SubscriptionInterval::OneMinute as i32,
+        open: Some(<Quotation as QuotationExtension>::from_f(open)),
+        high: Some(<Quotation as QuotationExtension>::from_f(high)),
+        low: Some(<Quotation as QuotationExtension>::from_f(low)),
+        close: Some(<Quotation as QuotationExtension>::from_f(close)),
+        volume,
+        time: Some(Timestamp { seconds: minute * 60, nanos: 0 }),
+        last_trade_ts: None,
+        instrument_uid: "test".to_string(),
+    }
+}
@@ -0,0 +1,29 @@
+use prost_types::Timestamp;
+#[cfg(test)]
+use crate::utils::wrapper_mock_system_time::WrapperMockSystemTime;
+
+// источник текущего времени для стратегий: в песочнице и проде -- системное, в исторических данных -- mock_instant
+pub trait Clock {
+    fn now(&self) -> Timestamp;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+
+// время двигает исторический прогон через mock_instant::MockClock, прогоны есть только в тестах
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct HistClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::from(std::time::SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+impl Clock for HistClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::from(WrapperMockSystemTime(mock_instant::SystemTime::now()))
+    }
+}
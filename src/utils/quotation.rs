@@ -1,7 +1,7 @@
 use std::cmp::Ordering;
 use std::ops::{Add, Mul, Sub};
 use std::str::FromStr;
-use tinkoff_invest_api::tcs::Quotation;
+use tinkoff_invest_api::tcs::{MoneyValue, Quotation};
 
 // https://russianinvestments.github.io/investAPI/faq_custom_types/
 #[derive(Debug, Clone)]
@@ -17,6 +17,10 @@ pub trait QuotationExtension {
     fn wr(&self) -> QuotationWrapper;
     fn to_f(&self) -> f64;
     fn from_str(str: &str) -> Quotation;
+    fn from_f(f: f64) -> Quotation;
+    fn from_money(money: &MoneyValue) -> Quotation;
+    // ближайшая цена, кратная шагу цены инструмента
+    fn round_to(&self, increment: &Quotation) -> Quotation;
 }
 
 impl QuotationExtension for Quotation {
@@ -34,6 +38,24 @@ impl QuotationExtension for Quotation {
             nano: (f.fract() * 1_000_000_000.0) as i32,
         }
     }
+    fn from_f(f: f64) -> Quotation {
+        Quotation {
+            units: f.trunc() as i64,
+            nano: (f.fract() * 1_000_000_000.0).round() as i32,
+        }
+    }
+    fn from_money(money: &MoneyValue) -> Quotation {
+        Quotation { units: money.units, nano: money.nano }
+    }
+    fn round_to(&self, increment: &Quotation) -> Quotation {
+        let step = increment.units as i128 * 1_000_000_000 + increment.nano as i128;
+        if step <= 0 {
+            return self.clone();
+        }
+        let value = self.units as i128 * 1_000_000_000 + self.nano as i128;
+        let rounded = (value + value.signum() * step / 2) / step * step;
+        Quotation { units: (rounded / 1_000_000_000) as i64, nano: (rounded % 1_000_000_000) as i32 }
+    }
 }
 
 
@@ -169,6 +191,14 @@ mod test {
         assert_eq!(<Quotation as QuotationExtension>::from_str(&f3.to_string()), q3);
     }
 
+    #[test]
+    fn test_round_to() {
+        let increment = Quotation { units: 0, nano: 10_000_000 };
+        assert_eq!(Quotation { units: 100, nano: 123_000_000 }.round_to(&increment), Quotation { units: 100, nano: 120_000_000 });
+        assert_eq!(Quotation { units: 100, nano: 995_000_000 }.round_to(&increment), Quotation { units: 101, nano: 0 });
+        assert_eq!(Quotation { units: 257, nano: 0 }.round_to(&Quotation { units: 5, nano: 0 }), Quotation { units: 255, nano: 0 });
+    }
+
     #[test]
     fn test_arith() {
         let x_1 = Quotation { units: 114, nano: i32::MAX };